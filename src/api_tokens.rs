@@ -0,0 +1,339 @@
+use axum::{
+    Extension,
+    extract::Request,
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use tracing::{debug, error, warn};
+
+use crate::{
+    auth::{effective_permissions, generate_token, permissions},
+    middleware::GuardResponse,
+    models::User,
+};
+
+/// Name of the Mongo collection holding API tokens
+pub const API_TOKENS_COLLECTION: &str = "api_tokens";
+
+/// Prefix on every token, so leaked ones are easy to spot (and grep for)
+const TOKEN_PREFIX: &str = "ffa_";
+
+/// Expiry choices offered when creating a token, in days
+pub const EXPIRY_DAYS: &[i64] = &[30, 90, 365];
+
+/// Skip the last-used write if the token was used this recently
+const LAST_USED_RESOLUTION_MS: i64 = 60 * 1000;
+
+/// A personal API token as stored in the "api_tokens" collection. Only the
+/// hash of the secret is kept; the token itself is shown once at creation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub token_hash: String,
+    /// Start of the token, so people can tell their tokens apart
+    pub display_prefix: String,
+    /// Permissions the token may use (never more than its owner has)
+    pub scopes: Vec<String>,
+    pub created_at: bson::DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<bson::DateTime>,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self, now: bson::DateTime) -> bool {
+        self.expires_at.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Who a Bearer request is acting as; put in the request extensions by
+/// `bearer_auth` and honoured by the guards and the `UserState` extractor
+#[derive(Debug, Clone)]
+pub struct ApiPrincipal {
+    pub user: User,
+    pub token_id: ObjectId,
+    /// The owner's permissions narrowed to the token's scopes
+    pub permissions: HashSet<String>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Permissions a token can carry. Managing staff accounts stays browser-only.
+pub fn token_scopes() -> impl Iterator<Item = &'static str> {
+    permissions::ALL
+        .iter()
+        .copied()
+        .filter(|permission| *permission != permissions::USERS_MANAGE)
+}
+
+/// Scopes `user` may put on a token, in display order
+pub fn available_scopes(user: &User) -> Vec<&'static str> {
+    let allowed = effective_permissions(user);
+    token_scopes().filter(|scope| allowed.contains(*scope)).collect()
+}
+
+/// Scopes the token actually gets: what was asked for, limited to what the user can do
+pub fn granted_permissions(user: &User, scopes: &[String]) -> HashSet<String> {
+    let allowed = available_scopes(user);
+    scopes
+        .iter()
+        .filter(|scope| allowed.contains(&scope.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// API token storage and lookup
+#[derive(Debug, Clone)]
+pub struct ApiTokens {
+    collection: Collection<ApiToken>,
+    users: Collection<User>,
+}
+
+impl ApiTokens {
+    pub fn new(db: &Database, users: Collection<User>) -> Self {
+        Self {
+            collection: db.collection(API_TOKENS_COLLECTION),
+            users,
+        }
+    }
+
+    /// Unique index on the hash, which is what every request looks up
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let hash_index = IndexModel::builder()
+            .keys(doc! { "token_hash": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("api_tokens_hash".to_string())
+                    .unique(true)
+                    .build(),
+            )
+            .build();
+        self.collection.create_index(hash_index).await?;
+        Ok(())
+    }
+
+    /// Create a token, returning the stored record and the secret to show once
+    pub async fn create(
+        &self,
+        user_id: ObjectId,
+        name: String,
+        scopes: Vec<String>,
+        expires_at: Option<bson::DateTime>,
+    ) -> mongodb::error::Result<(ApiToken, String)> {
+        let secret = format!("{}{}", TOKEN_PREFIX, generate_token());
+        let token = ApiToken {
+            id: ObjectId::new(),
+            user_id,
+            name,
+            token_hash: hash_token(&secret),
+            display_prefix: secret[..TOKEN_PREFIX.len() + 6].to_string(),
+            scopes,
+            created_at: bson::DateTime::now(),
+            expires_at,
+            last_used_at: None,
+        };
+        self.collection.insert_one(&token).await?;
+        Ok((token, secret))
+    }
+
+    pub async fn list_for_user(&self, user_id: ObjectId) -> mongodb::error::Result<Vec<ApiToken>> {
+        self.collection
+            .find(doc! { "user_id": user_id })
+            .sort(doc! { "created_at": -1 })
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Delete one of the user's tokens; false if it wasn't theirs or didn't exist
+    pub async fn revoke(&self, user_id: ObjectId, token_id: ObjectId) -> mongodb::error::Result<bool> {
+        let result = self
+            .collection
+            .delete_one(doc! { "_id": token_id, "user_id": user_id })
+            .await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Resolve a presented secret to the acting user, if it's valid
+    pub async fn authenticate(&self, secret: &str) -> mongodb::error::Result<Option<ApiPrincipal>> {
+        if !secret.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let Some(token) = self
+            .collection
+            .find_one(doc! { "token_hash": hash_token(secret) })
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let now = bson::DateTime::now();
+        if token.is_expired(now) {
+            return Ok(None);
+        }
+
+        // Disabled or removed staff lose their tokens along with their logins
+        let user = match self.users.find_one(doc! { "_id": token.user_id }).await? {
+            Some(user) if !user.disabled && user.has_staff_access() => user,
+            _ => return Ok(None),
+        };
+
+        let stale = token
+            .last_used_at
+            .is_none_or(|used| now.timestamp_millis() - used.timestamp_millis() > LAST_USED_RESOLUTION_MS);
+        if stale {
+            self.collection
+                .update_one(doc! { "_id": token.id }, doc! { "$set": { "last_used_at": now } })
+                .await?;
+        }
+
+        Ok(Some(ApiPrincipal {
+            permissions: granted_permissions(&user, &token.scopes),
+            user,
+            token_id: token.id,
+        }))
+    }
+}
+
+/// The token from an `Authorization: Bearer ...` header, if there is one
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn invalid_token() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        Json(GuardResponse {
+            success: false,
+            message: "Invalid or expired API token".to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Outermost admin layer: requests carrying a Bearer token act as the token's
+/// owner (with its scopes) instead of going through the session cookie
+pub async fn bearer_auth(
+    Extension(tokens): Extension<ApiTokens>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(secret) = bearer_token(&request) else {
+        return next.run(request).await;
+    };
+
+    match tokens.authenticate(secret).await {
+        Ok(Some(principal)) => {
+            debug!("API request as {} (token {})", principal.user.username, principal.token_id);
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Ok(None) => {
+            warn!("Rejected request with invalid API token");
+            invalid_token()
+        }
+        Err(e) => {
+            error!("API token lookup failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Token check failed").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+    use axum::body::Body;
+
+    fn user_with(roles: Vec<Role>) -> User {
+        User::new("printer@example.com".to_string(), String::new(), false, roles)
+    }
+
+    #[test]
+    fn test_granted_permissions_never_exceed_the_owner() {
+        let user = user_with(vec![Role::Fulfilment]);
+        let scopes = vec!["orders:view".to_string(), "products:edit".to_string()];
+
+        let granted = granted_permissions(&user, &scopes);
+        assert!(granted.contains("orders:view"));
+        assert!(!granted.contains("products:edit"));
+        assert!(!granted.contains("orders:update")); // Owner has it but the token didn't ask
+    }
+
+    #[test]
+    fn test_tokens_cannot_manage_users() {
+        let admin = User {
+            is_admin: true,
+            ..user_with(vec![])
+        };
+        assert!(!available_scopes(&admin).contains(&"users:manage"));
+        assert!(available_scopes(&admin).contains(&"products:edit"));
+
+        let granted = granted_permissions(&admin, &["users:manage".to_string()]);
+        assert!(granted.is_empty());
+    }
+
+    #[test]
+    fn test_bearer_token_parsing() {
+        let request = Request::builder()
+            .header(header::AUTHORIZATION, "Bearer ffa_abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(bearer_token(&request), Some("ffa_abc123"));
+
+        let basic = Request::builder()
+            .header(header::AUTHORIZATION, "Basic dXNlcjpwYXNz")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(bearer_token(&basic), None);
+
+        let none = Request::builder().body(Body::empty()).unwrap();
+        assert_eq!(bearer_token(&none), None);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let now = bson::DateTime::now();
+        let mut token = ApiToken {
+            id: ObjectId::new(),
+            user_id: ObjectId::new(),
+            name: "Label printer".to_string(),
+            token_hash: hash_token("ffa_x"),
+            display_prefix: "ffa_xxxxxx".to_string(),
+            scopes: vec![],
+            created_at: now,
+            expires_at: None,
+            last_used_at: None,
+        };
+        assert!(!token.is_expired(now));
+
+        token.expires_at = Some(now);
+        assert!(token.is_expired(now));
+
+        token.expires_at = Some(bson::DateTime::from_millis(now.timestamp_millis() + 1000));
+        assert!(!token.is_expired(now));
+    }
+}
@@ -0,0 +1,375 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use axum_login::AuthSession;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::error;
+
+use crate::{api_tokens::ApiPrincipal, auth::MongoAuth};
+
+/// Name of the Mongo collection holding the audit trail
+pub const AUDIT_LOG_COLLECTION: &str = "audit_log";
+
+/// Action names stored in the log
+pub mod actions {
+    pub const LOGIN_SUCCEEDED: &str = "login.succeeded";
+    pub const LOGIN_FAILED: &str = "login.failed";
+    pub const LOGOUT: &str = "logout";
+    pub const PRODUCT_CREATED: &str = "product.created";
+    pub const PRODUCT_UPDATED: &str = "product.updated";
+    pub const PRODUCT_DELETED: &str = "product.deleted";
+    pub const CATEGORY_CREATED: &str = "category.created";
+    pub const CATEGORY_UPDATED: &str = "category.updated";
+    pub const CATEGORY_DELETED: &str = "category.deleted";
+    pub const ORDER_STATUS_CHANGED: &str = "order.status_changed";
+    pub const QUOTE_STATUS_CHANGED: &str = "quote.status_changed";
+
+    /// Every action, for the filter on the audit page
+    pub const ALL: &[&str] = &[
+        LOGIN_SUCCEEDED,
+        LOGIN_FAILED,
+        LOGOUT,
+        PRODUCT_CREATED,
+        PRODUCT_UPDATED,
+        PRODUCT_DELETED,
+        CATEGORY_CREATED,
+        CATEGORY_UPDATED,
+        CATEGORY_DELETED,
+        ORDER_STATUS_CHANGED,
+        QUOTE_STATUS_CHANGED,
+    ];
+}
+
+/// One field's value before and after an action (None where it didn't exist)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// A record in the "audit_log" collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub timestamp: bson::DateTime,
+    /// Username of whoever did it (or tried to log in as)
+    pub actor: String,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<String>,
+    #[serde(default)]
+    pub changes: Vec<FieldChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Set when the action came in through an API token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<ObjectId>,
+}
+
+/// Field-by-field differences between two snapshots. Pass an empty `before`
+/// for something new and an empty `after` for something deleted.
+pub fn diff(before: &[(&str, String)], after: &[(&str, String)]) -> Vec<FieldChange> {
+    let lookup = |fields: &[(&str, String)], name: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut names: Vec<&str> = before.iter().map(|(field, _)| *field).collect();
+    for (field, _) in after {
+        if !names.contains(field) {
+            names.push(field);
+        }
+    }
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = lookup(before, name);
+            let new = lookup(after, name);
+            (old != new).then(|| FieldChange {
+                field: name.to_string(),
+                before: old,
+                after: new,
+            })
+        })
+        .collect()
+}
+
+/// Who is acting and from where; handlers take it as an extractor
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    pub username: String,
+    pub ip: Option<String>,
+    pub token_id: Option<ObjectId>,
+}
+
+impl Actor {
+    /// Someone who isn't logged in yet, e.g. a login attempt
+    pub fn anonymous(username: &str, ip: std::net::IpAddr) -> Self {
+        Self {
+            username: username.to_string(),
+            ip: Some(ip.to_string()),
+            token_id: None,
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Actor
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+        if let Some(principal) = parts.extensions.get::<ApiPrincipal>() {
+            return Ok(Actor {
+                username: principal.user.username.clone(),
+                ip,
+                token_id: Some(principal.token_id),
+            });
+        }
+
+        let username = AuthSession::<MongoAuth>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|auth| auth.user)
+            .map(|user| user.username)
+            .unwrap_or_default();
+        Ok(Actor {
+            username,
+            ip,
+            token_id: None,
+        })
+    }
+}
+
+/// Filters from the audit page's query string; blank fields are ignored
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default)]
+    pub actor: String,
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub target: String,
+    /// Inclusive dates, as YYYY-MM-DD
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub to: String,
+}
+
+fn day_start(date: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+impl AuditFilter {
+    /// The Mongo query for these filters
+    pub fn to_document(&self) -> Document {
+        let mut filter = Document::new();
+        if !self.actor.trim().is_empty() {
+            filter.insert("actor", self.actor.trim());
+        }
+        if !self.action.trim().is_empty() {
+            filter.insert("action", self.action.trim());
+        }
+        if !self.target.trim().is_empty() {
+            filter.insert("target_id", self.target.trim());
+        }
+
+        let mut range = Document::new();
+        if let Some(start) = day_start(&self.from) {
+            range.insert("$gte", bson::DateTime::from_millis(start));
+        }
+        if let Some(end) = day_start(&self.to) {
+            range.insert("$lt", bson::DateTime::from_millis(end + 24 * 60 * 60 * 1000));
+        }
+        if !range.is_empty() {
+            filter.insert("timestamp", range);
+        }
+        filter
+    }
+
+    /// The filters as a query string, for pagination and export links
+    pub fn query_string(&self) -> String {
+        [
+            ("actor", &self.actor),
+            ("action", &self.action),
+            ("target", &self.target),
+            ("from", &self.from),
+            ("to", &self.to),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value.trim())))
+        .collect::<Vec<_>>()
+        .join("&")
+    }
+}
+
+/// Format an entry's timestamp for the page (RFC 3339 is used for export)
+pub fn format_timestamp(timestamp: bson::DateTime, format: &str) -> String {
+    DateTime::<Utc>::from_timestamp_millis(timestamp.timestamp_millis())
+        .map(|t| t.format(format).to_string())
+        .unwrap_or_default()
+}
+
+/// Append-only record of logins and admin changes
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    collection: Collection<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(AUDIT_LOG_COLLECTION),
+        }
+    }
+
+    /// Indexes for the newest-first listing and the usual filters
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let indexes = [
+            ("audit_log_timestamp", doc! { "timestamp": -1 }),
+            ("audit_log_actor", doc! { "actor": 1, "timestamp": -1 }),
+            ("audit_log_target", doc! { "target_id": 1, "timestamp": -1 }),
+        ]
+        .into_iter()
+        .map(|(name, keys)| {
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().name(name.to_string()).build())
+                .build()
+        });
+        self.collection.create_indexes(indexes).await?;
+        Ok(())
+    }
+
+    /// Write an entry. Failures are logged rather than failing the action
+    /// that's already happened.
+    pub async fn record(&self, actor: &Actor, action: &str, target_id: Option<String>, changes: Vec<FieldChange>) {
+        let entry = AuditEntry {
+            id: ObjectId::new(),
+            timestamp: bson::DateTime::now(),
+            actor: actor.username.clone(),
+            action: action.to_string(),
+            target_id,
+            changes,
+            ip: actor.ip.clone(),
+            token_id: actor.token_id,
+        };
+        if let Err(e) = self.collection.insert_one(&entry).await {
+            error!("Failed to write audit entry {} by {}: {}", action, actor.username, e);
+        }
+    }
+
+    /// Matching entries, newest first, along with the total match count
+    pub async fn find(
+        &self,
+        filter: &AuditFilter,
+        skip: u64,
+        limit: i64,
+    ) -> mongodb::error::Result<(Vec<AuditEntry>, u64)> {
+        let query = filter.to_document();
+        let total = self.collection.count_documents(query.clone()).await?;
+        let entries = self
+            .collection
+            .find(query)
+            .sort(doc! { "timestamp": -1 })
+            .skip(skip)
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?;
+        Ok((entries, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_diff_only_lists_changed_fields() {
+        let before = fields(&[("name", "Fox Badge"), ("price", "4.50"), ("quantity", "10")]);
+        let after = fields(&[("name", "Fox Badge"), ("price", "5.00"), ("quantity", "10")]);
+
+        assert_eq!(
+            diff(&before, &after),
+            vec![FieldChange {
+                field: "price".to_string(),
+                before: Some("4.50".to_string()),
+                after: Some("5.00".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_for_created_and_deleted() {
+        let product = fields(&[("name", "Fox Badge"), ("price", "4.50")]);
+
+        let created = diff(&[], &product);
+        assert_eq!(created.len(), 2);
+        assert!(created.iter().all(|c| c.before.is_none() && c.after.is_some()));
+
+        let deleted = diff(&product, &[]);
+        assert_eq!(deleted.len(), 2);
+        assert!(deleted.iter().all(|c| c.before.is_some() && c.after.is_none()));
+    }
+
+    #[test]
+    fn test_filter_document() {
+        let filter = AuditFilter {
+            actor: " owner@example.com ".to_string(),
+            action: "product.deleted".to_string(),
+            from: "2025-03-01".to_string(),
+            to: "2025-03-01".to_string(),
+            ..AuditFilter::default()
+        };
+        let query = filter.to_document();
+
+        assert_eq!(query.get_str("actor").unwrap(), "owner@example.com");
+        assert_eq!(query.get_str("action").unwrap(), "product.deleted");
+        assert!(query.get("target_id").is_none());
+
+        // "to" is inclusive, so the range covers the whole day
+        let range = query.get_document("timestamp").unwrap();
+        let start = range.get_datetime("$gte").unwrap().timestamp_millis();
+        let end = range.get_datetime("$lt").unwrap().timestamp_millis();
+        assert_eq!(end - start, 24 * 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn test_filter_ignores_blank_and_bad_dates() {
+        let filter = AuditFilter {
+            from: "yesterday".to_string(),
+            ..AuditFilter::default()
+        };
+        assert!(filter.to_document().is_empty());
+        assert_eq!(filter.query_string(), "from=yesterday");
+        assert_eq!(AuditFilter::default().query_string(), "");
+    }
+}
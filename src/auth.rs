@@ -1,6 +1,9 @@
 use argon2::{
     Argon2,
-    password_hash::{PasswordHash, PasswordVerifier},
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+        rand_core::{OsRng, RngCore},
+    },
 };
 use async_trait::async_trait;
 use std::{collections::HashSet, future::Future, pin::Pin};
@@ -10,7 +13,72 @@ use axum_login::{AuthUser, AuthnBackend, AuthzBackend, UserId};
 use mongodb::bson::doc;
 use mongodb::{Collection, bson::oid::ObjectId};
 
-use crate::models::{Credentials, User};
+use crate::{
+    login_throttle::locked_until,
+    models::{Credentials, Role, User},
+};
+
+/// Permission names checked per route and in templates
+pub mod permissions {
+    pub const PRODUCTS_VIEW: &str = "products:view";
+    pub const PRODUCTS_EDIT: &str = "products:edit";
+    pub const ORDERS_VIEW: &str = "orders:view";
+    pub const ORDERS_UPDATE: &str = "orders:update";
+    pub const QUOTES_VIEW: &str = "quotes:view";
+    pub const QUOTES_UPDATE: &str = "quotes:update";
+    pub const USERS_MANAGE: &str = "users:manage";
+    pub const AUDIT_VIEW: &str = "audit:view";
+
+    /// Every permission, granted to owners and legacy `is_admin` users
+    pub const ALL: &[&str] = &[
+        PRODUCTS_VIEW,
+        PRODUCTS_EDIT,
+        ORDERS_VIEW,
+        ORDERS_UPDATE,
+        QUOTES_VIEW,
+        QUOTES_UPDATE,
+        USERS_MANAGE,
+        AUDIT_VIEW,
+    ];
+}
+
+impl Role {
+    /// The permission group granted by this role
+    pub fn permissions(&self) -> &'static [&'static str] {
+        use permissions::*;
+        match self {
+            Role::Owner => ALL,
+            Role::Fulfilment => &[PRODUCTS_VIEW, ORDERS_VIEW, ORDERS_UPDATE],
+            Role::Quotes => &[QUOTES_VIEW, QUOTES_UPDATE],
+            Role::Viewer => &[PRODUCTS_VIEW, ORDERS_VIEW, QUOTES_VIEW],
+        }
+    }
+}
+
+/// Permissions granted directly to the user (legacy `is_admin` flag)
+pub fn user_permissions(user: &User) -> HashSet<String> {
+    if user.is_admin {
+        permissions::ALL.iter().map(|p| p.to_string()).collect()
+    } else {
+        HashSet::new()
+    }
+}
+
+/// Permissions granted through the user's roles
+pub fn group_permissions(user: &User) -> HashSet<String> {
+    user.roles
+        .iter()
+        .flat_map(|role| role.permissions())
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Everything the user may do: direct plus role permissions
+pub fn effective_permissions(user: &User) -> HashSet<String> {
+    let mut perms = user_permissions(user);
+    perms.extend(group_permissions(user));
+    perms
+}
 
 /// A sized, concrete error type you can `#[from]`.
 #[derive(Debug, Error)]
@@ -22,7 +90,7 @@ pub enum AuthError {
 impl AuthUser for User {
     type Id = ObjectId;
     fn id(&self) -> Self::Id {
-        self.id.clone()
+        self.id
     }
     fn session_auth_hash(&self) -> &[u8] {
         self.password_hash.as_bytes()
@@ -39,6 +107,37 @@ pub async fn verify_password(submitted: &str, stored_hash: &str) -> bool {
         .is_ok()
 }
 
+/// Shortest password accepted when one is set or reset
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+
+/// Check a new password before hashing it
+pub fn validate_new_password(password: &str, confirm: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    if password != confirm {
+        return Err("Passwords do not match".to_string());
+    }
+    Ok(())
+}
+
+/// Argon2 hash in PHC string form, as stored in `User::password_hash`
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Random 256-bit token, hex encoded (CSRF tokens and the like)
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
 
 /// Your Mongo‐backed auth manager
 #[derive(Clone)]
@@ -57,17 +156,21 @@ impl AuthnBackend for MongoAuth {
             .users
             .find_one(doc! { "username": &creds.username })
             .await?;
-        if let Some(u) = maybe {
-            if verify_password(&creds.password, &u.password_hash).await {
-                return Ok(Some(u));
-            }
+        // Locked and disabled accounts are refused even with the right password
+        if let Some(u) = maybe
+            && !u.disabled
+            && locked_until(&u).is_none()
+            && verify_password(&creds.password, &u.password_hash).await
+        {
+            return Ok(Some(u));
         }
         Ok(None)
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        let user = self.users.find_one(doc! { "_id": user_id.clone() }).await?;
-        Ok(user)
+        let user = self.users.find_one(doc! { "_id": *user_id }).await?;
+        // Disabling an account ends its existing sessions too
+        Ok(user.filter(|u| !u.disabled))
     }
 }
 
@@ -84,25 +187,105 @@ impl AuthzBackend for MongoAuth {
         'a: 'c,
         'b: 'c,
     {
-        let is_admin = user.is_admin;
-        Box::pin(async move {
-            let mut perms = HashSet::new();
-            if is_admin {
-                perms.insert("admin".into());
-            }
-            Ok(perms)
-        })
+        let perms = user_permissions(user);
+        Box::pin(async move { Ok(perms) })
     }
 
     fn get_group_permissions<'a, 'b, 'c>(
         &'a self,
-        _user: &'b User,
+        user: &'b User,
     ) -> Pin<Box<dyn Future<Output = Result<HashSet<String>, AuthError>> + Send + 'c>>
     where
         Self: Sync + 'c,
         'a: 'c,
         'b: 'c,
     {
-        Box::pin(async { Ok(HashSet::new()) })
+        let perms = group_permissions(user);
+        Box::pin(async move { Ok(perms) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_with(is_admin: bool, roles: Vec<Role>) -> User {
+        User::new("staff@example.com".to_string(), String::new(), is_admin, roles)
+    }
+
+    #[test]
+    fn test_generate_token_is_random_hex() {
+        let a = generate_token();
+        let b = generate_token();
+        assert_eq!(a.len(), 64);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn test_hash_password_verifies() {
+        let hash = hash_password("correct horse battery").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse battery", &hash).await);
+        assert!(!verify_password("wrong horse battery", &hash).await);
+    }
+
+    #[test]
+    fn test_validate_new_password() {
+        assert!(validate_new_password("long enough pw", "long enough pw").is_ok());
+        assert!(validate_new_password("short", "short").is_err());
+        assert!(validate_new_password("long enough pw", "long enough px").is_err());
+    }
+
+    #[test]
+    fn test_legacy_admin_gets_every_permission() {
+        let perms = effective_permissions(&user_with(true, vec![]));
+        for perm in permissions::ALL {
+            assert!(perms.contains(*perm), "missing {}", perm);
+        }
+    }
+
+    #[test]
+    fn test_fulfilment_can_update_orders_but_not_edit_products() {
+        let perms = effective_permissions(&user_with(false, vec![Role::Fulfilment]));
+        assert!(perms.contains(permissions::ORDERS_UPDATE));
+        assert!(perms.contains(permissions::PRODUCTS_VIEW));
+        assert!(!perms.contains(permissions::PRODUCTS_EDIT));
+        assert!(!perms.contains(permissions::QUOTES_VIEW));
+    }
+
+    #[test]
+    fn test_quotes_role_is_limited_to_quotes() {
+        let perms = effective_permissions(&user_with(false, vec![Role::Quotes]));
+        assert_eq!(perms.len(), 2);
+        assert!(perms.contains(permissions::QUOTES_VIEW));
+        assert!(perms.contains(permissions::QUOTES_UPDATE));
+    }
+
+    #[test]
+    fn test_viewer_is_read_only() {
+        let perms = effective_permissions(&user_with(false, vec![Role::Viewer]));
+        assert!(perms.iter().all(|p| p.ends_with(":view")));
+    }
+
+    #[test]
+    fn test_roles_combine() {
+        let perms = effective_permissions(&user_with(false, vec![Role::Quotes, Role::Viewer]));
+        assert!(perms.contains(permissions::QUOTES_UPDATE));
+        assert!(perms.contains(permissions::ORDERS_VIEW));
+        assert!(!perms.contains(permissions::ORDERS_UPDATE));
+    }
+
+    #[test]
+    fn test_user_without_roles_has_no_staff_access() {
+        let user = user_with(false, vec![]);
+        assert!(!user.has_staff_access());
+        assert!(effective_permissions(&user).is_empty());
+    }
+
+    #[test]
+    fn test_role_serialises_lowercase() {
+        let value = mongodb::bson::to_bson(&Role::Fulfilment).unwrap();
+        assert_eq!(value.as_str(), Some("fulfilment"));
     }
 }
@@ -0,0 +1,520 @@
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database,
+    bson::{self, Bson, Document, doc},
+};
+use serde::de::DeserializeOwned;
+use std::{
+    io::BufRead,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    auth::{hash_password, validate_new_password},
+    handlers::users::{convert_to_display, validate_user_form},
+    image_cleanup,
+    models::{CustomBadgeQuote, Order, Product, Role, User, UserForm},
+    money::Currency,
+    session_store::MongoSessionStore,
+    storage::ImageStores,
+};
+
+/// Foxy Fabrications admin server, account and maintenance tools. With no command, runs the server.
+#[derive(Parser, Debug)]
+#[command(name = "foxy-fabrications-admin", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the admin web server (the default)
+    Serve,
+    /// Create a staff account, e.g. the first admin of a new deployment
+    CreateUser {
+        /// Login name (the user's email address)
+        username: String,
+        /// Role to grant; repeat for several (owner, fulfilment, quotes, viewer)
+        #[arg(long = "role", value_name = "ROLE", value_parser = parse_role)]
+        roles: Vec<Role>,
+        /// Grant every permission regardless of roles
+        #[arg(long)]
+        admin: bool,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Set a user's password, clearing any lockout
+    SetPassword {
+        username: String,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// List user accounts and their access
+    ListUsers,
+    /// Report product images no product uses, and duplicate uploads
+    CleanImages {
+        /// Delete the unused images instead of just listing them
+        #[arg(long)]
+        delete: bool,
+        /// Leave images younger than this alone, as they may be mid-upload
+        #[arg(long, default_value_t = 24)]
+        min_age_hours: u64,
+    },
+    /// Convert prices stored as strings or decimals to exact amounts with a
+    /// currency. Safe to run again, e.g. after older storefront orders arrive.
+    MigrateMoney {
+        /// Report what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+fn parse_role(value: &str) -> Result<Role, String> {
+    Role::parse(&value.to_ascii_lowercase()).ok_or_else(|| {
+        let names: Vec<&str> = Role::ALL.iter().map(|role| role.as_str()).collect();
+        format!("unknown role '{}' (expected one of: {})", value, names.join(", "))
+    })
+}
+
+/// Get a new password either from stdin (for scripts) or an interactive prompt
+fn read_new_password(from_stdin: bool) -> Result<String> {
+    let (password, confirm) = if from_stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .context("failed to read password from stdin")?;
+        let password = line.trim_end_matches(['\r', '\n']).to_string();
+        (password.clone(), password)
+    } else {
+        let password = rpassword::prompt_password("New password: ").context("failed to read password")?;
+        let confirm = rpassword::prompt_password("Confirm password: ").context("failed to read password")?;
+        (password, confirm)
+    };
+
+    validate_new_password(&password, &confirm).map_err(anyhow::Error::msg)?;
+    Ok(password)
+}
+
+/// Run one of the account or maintenance commands against the database
+pub async fn run(command: Command, db: &Database) -> Result<()> {
+    let users: Collection<User> = db.collection("users");
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::CreateUser {
+            username,
+            roles,
+            admin,
+            password_stdin,
+        } => create_user(&users, username, roles, admin, password_stdin).await,
+        Command::SetPassword {
+            username,
+            password_stdin,
+        } => set_password(&users, &MongoSessionStore::new(db), &username, password_stdin).await,
+        Command::ListUsers => list_users(&users).await,
+        Command::CleanImages { delete, min_age_hours } => {
+            clean_images(&db.collection("products"), delete, min_age_hours).await
+        }
+        Command::MigrateMoney { dry_run } => migrate_money(db, dry_run).await,
+    }
+}
+
+async fn create_user(
+    users: &Collection<User>,
+    username: String,
+    roles: Vec<Role>,
+    admin: bool,
+    password_stdin: bool,
+) -> Result<()> {
+    let form = UserForm {
+        username: username.trim().to_string(),
+        is_admin: admin,
+        roles,
+        ..UserForm::default()
+    };
+    validate_user_form(&form).map_err(|e| anyhow::anyhow!("{} (use --role or --admin)", e))?;
+
+    if users.find_one(doc! { "username": &form.username }).await?.is_some() {
+        bail!("a user called {} already exists", form.username);
+    }
+
+    let password = read_new_password(password_stdin)?;
+    let password_hash = hash_password(&password).map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+    let user = User::new(form.username, password_hash, form.is_admin, form.roles);
+    users.insert_one(&user).await?;
+
+    println!("Created user {}", user.username);
+    Ok(())
+}
+
+async fn set_password(
+    users: &Collection<User>,
+    sessions: &MongoSessionStore,
+    username: &str,
+    password_stdin: bool,
+) -> Result<()> {
+    let Some(user) = users.find_one(doc! { "username": username }).await? else {
+        bail!("no user called {}", username);
+    };
+
+    let password = read_new_password(password_stdin)?;
+    let password_hash =
+        hash_password(&password).map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+    users
+        .update_one(
+            doc! { "username": username },
+            doc! {
+                "$set": { "password_hash": password_hash, "failed_login_attempts": 0 },
+                "$unset": { "locked_until": "" },
+            },
+        )
+        .await?;
+    sessions.revoke_all_for_user(user.id, None).await?;
+
+    println!("Password updated for {} (existing sessions have been logged out)", username);
+    Ok(())
+}
+
+async fn list_users(users: &Collection<User>) -> Result<()> {
+    let all: Vec<User> = users
+        .find(doc! {})
+        .sort(doc! { "username": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    if all.is_empty() {
+        println!("No users yet - create one with: foxy-fabrications-admin create-user <email> --admin");
+        return Ok(());
+    }
+
+    println!("{:<40} {:<30} {:<4} STATUS", "USERNAME", "ACCESS", "2FA");
+    for user in &all {
+        let display = convert_to_display(user);
+        println!(
+            "{:<40} {:<30} {:<4} {}",
+            display.username,
+            describe_access(display.is_admin, &display.roles),
+            if display.two_factor_enabled { "on" } else { "off" },
+            if display.disabled {
+                "disabled".to_string()
+            } else if display.is_locked {
+                format!("locked until {}", display.formatted_locked_until)
+            } else {
+                "active".to_string()
+            }
+        );
+    }
+    Ok(())
+}
+
+async fn clean_images(products: &Collection<Product>, delete: bool, min_age_hours: u64) -> Result<()> {
+    let stores = ImageStores::from_env().await.map_err(anyhow::Error::msg)?;
+    let storage = stores.product_images.as_ref();
+
+    let all: Vec<Product> = products.find(doc! {}).await?.try_collect().await?;
+    let referenced = image_cleanup::referenced_files(&all);
+    let files = storage.list().await.context("failed to list product images")?;
+    println!(
+        "{} image files in {}, {} referenced by {} products",
+        files.len(),
+        storage.describe(),
+        referenced.len(),
+        all.len()
+    );
+
+    let cutoff = SystemTime::now() - Duration::from_secs(min_age_hours * 60 * 60);
+    let orphans = image_cleanup::find_orphans(&files, &referenced, cutoff);
+    let orphan_bytes: u64 = orphans.iter().map(|file| file.size).sum();
+    if orphans.is_empty() {
+        println!("\nNo unused images");
+    } else {
+        println!("\nUnused images ({}, {}):", orphans.len(), format_size(orphan_bytes));
+        for file in &orphans {
+            println!("  {:<50} {:>10}", file.key, format_size(file.size));
+        }
+    }
+
+    let duplicates = image_cleanup::find_duplicates(storage, &files)
+        .await
+        .context("failed to read product images")?;
+    if !duplicates.is_empty() {
+        println!("\nDuplicate uploads ({} sets):", duplicates.len());
+        for group in &duplicates {
+            let keys: Vec<String> = group
+                .keys
+                .iter()
+                .map(|key| {
+                    let used = if referenced.contains(key) { "in use" } else { "unused" };
+                    format!("{} ({})", key, used)
+                })
+                .collect();
+            println!("  {}  {}", &group.hash[..12], keys.join(", "));
+        }
+    }
+
+    if orphans.is_empty() {
+        return Ok(());
+    }
+    if !delete {
+        println!("\nRun again with --delete to remove the unused images");
+        return Ok(());
+    }
+    for file in &orphans {
+        storage
+            .delete(&file.key)
+            .await
+            .with_context(|| format!("failed to delete {}", file.key))?;
+    }
+    println!("\nDeleted {} unused images ({})", orphans.len(), format_size(orphan_bytes));
+    Ok(())
+}
+
+/// Matches documents where `field` isn't yet stored as a money sub-document
+fn not_money(field: &str) -> Document {
+    doc! { field: { "$exists": true, "$not": { "$type": "object" } } }
+}
+
+/// `$set` fields rewriting a product's price and variant price changes
+fn product_money_update(product: &Product) -> Document {
+    let mut update = doc! { "price": product.price };
+    for (i, variant) in product.variants.iter().enumerate() {
+        update.insert(format!("variants.{}.price_delta", i), variant.price_delta);
+    }
+    update
+}
+
+/// `$set` fields rewriting an order's amounts in the order's currency. Items
+/// are updated field by field so nothing else the storefront stored is lost.
+fn order_money_update(order: &Order) -> Result<Document> {
+    let currency = Currency::parse(&order.currency).with_context(|| format!("unknown currency '{}'", order.currency))?;
+    let mut update = doc! {
+        "subtotal": order.subtotal.with_currency(currency),
+        "shipping_cost": order.shipping_cost.with_currency(currency),
+        "total": order.total.with_currency(currency),
+    };
+    for (i, item) in order.items.iter().enumerate() {
+        update.insert(format!("items.{}.price", i), item.price.with_currency(currency));
+        update.insert(format!("items.{}.line_total", i), item.line_total.with_currency(currency));
+    }
+    Ok(update)
+}
+
+/// Rewrite the matching documents of one collection, returning how many were
+/// converted and how many couldn't be
+async fn migrate_collection<T: DeserializeOwned>(
+    collection: Collection<Document>,
+    filter: Document,
+    dry_run: bool,
+    update: impl Fn(&T) -> Result<Document>,
+) -> Result<(u64, u64)> {
+    let legacy: Vec<Document> = collection.find(filter).await?.try_collect().await?;
+    let (mut converted, mut failed) = (0, 0);
+    for document in legacy {
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        let changes = bson::from_document::<T>(document)
+            .map_err(anyhow::Error::from)
+            .and_then(|parsed| update(&parsed));
+        match changes {
+            Ok(changes) => {
+                if !dry_run {
+                    collection
+                        .update_one(doc! { "_id": id.clone() }, doc! { "$set": changes })
+                        .await
+                        .with_context(|| format!("failed to update {} in {}", id, collection.name()))?;
+                }
+                converted += 1;
+            }
+            Err(e) => {
+                println!("  skipped {} in {}: {}", id, collection.name(), e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((converted, failed))
+}
+
+async fn migrate_money(db: &Database, dry_run: bool) -> Result<()> {
+    let products = migrate_collection(
+        db.collection("products"),
+        doc! { "$or": [
+            not_money("price"),
+            { "variants": { "$elemMatch": not_money("price_delta") } },
+        ] },
+        dry_run,
+        |product: &Product| Ok(product_money_update(product)),
+    )
+    .await?;
+
+    let orders = migrate_collection(
+        db.collection("orders"),
+        doc! { "$or": [
+            not_money("subtotal"),
+            not_money("shipping_cost"),
+            not_money("total"),
+            { "items": { "$elemMatch": { "$or": [not_money("price"), not_money("line_total")] } } },
+        ] },
+        dry_run,
+        order_money_update,
+    )
+    .await?;
+
+    let quotes = migrate_collection(
+        db.collection("badge_quotes"),
+        not_money("estimated_price"),
+        dry_run,
+        |quote: &CustomBadgeQuote| Ok(doc! { "estimated_price": quote.estimated_price }),
+    )
+    .await?;
+
+    let verb = if dry_run { "Would convert" } else { "Converted" };
+    for (name, (converted, failed)) in [("products", products), ("orders", orders), ("quotes", quotes)] {
+        println!("{} {} {} ({} skipped)", verb, converted, name, failed);
+    }
+    if products.1 + orders.1 + quotes.1 > 0 {
+        bail!("some documents couldn't be converted; fix them and run again");
+    }
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{} B", b),
+    }
+}
+
+fn describe_access(is_admin: bool, roles: &str) -> String {
+    match (is_admin, roles.is_empty()) {
+        (true, true) => "Admin".to_string(),
+        (true, false) => format!("Admin, {}", roles),
+        (false, true) => "-".to_string(),
+        (false, false) => roles.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::oid::ObjectId;
+
+    #[test]
+    fn test_parse_create_user_command() {
+        let cli = Cli::try_parse_from([
+            "foxy-fabrications-admin",
+            "create-user",
+            "owner@example.com",
+            "--role",
+            "Owner",
+            "--role",
+            "quotes",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Some(Command::CreateUser {
+                username,
+                roles,
+                admin,
+                password_stdin,
+            }) => {
+                assert_eq!(username, "owner@example.com");
+                assert_eq!(roles, vec![Role::Owner, Role::Quotes]);
+                assert!(!admin);
+                assert!(!password_stdin);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_role() {
+        let result = Cli::try_parse_from([
+            "foxy-fabrications-admin",
+            "create-user",
+            "owner@example.com",
+            "--role",
+            "superuser",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_no_command_means_serve() {
+        let cli = Cli::try_parse_from(["foxy-fabrications-admin"]).unwrap();
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_parse_clean_images_command() {
+        let cli = Cli::try_parse_from(["foxy-fabrications-admin", "clean-images", "--delete"]).unwrap();
+        match cli.command {
+            Some(Command::CleanImages { delete, min_age_hours }) => {
+                assert!(delete);
+                assert_eq!(min_age_hours, 24);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_order_money_update_uses_the_order_currency() {
+        let order: Order = bson::from_document(doc! {
+            "_id": ObjectId::new(),
+            "order_reference": "ORD-1",
+            "customer_name": "Jo",
+            "customer_email": "jo@example.com",
+            "shipping_address": { "line1": "1 Street", "city": "Town", "postcode": "AB1", "country": "IE" },
+            "items": [{ "product_id": "abc", "name": "Fox Badge", "quantity": 2, "price": 4.5, "line_total": 9.0 }],
+            "subtotal": 9.0,
+            "shipping_cost": 3,
+            "total": 12.0,
+            "currency": "eur",
+            "status": "paid",
+            "created_at": "2025-01-01T12:00:00Z",
+            "updated_at": "2025-01-01T12:00:00Z",
+        })
+        .unwrap();
+
+        let update = order_money_update(&order).unwrap();
+        assert_eq!(update.get_document("total").unwrap(), &doc! { "minor_units": 1200_i64, "currency": "EUR" });
+        assert_eq!(
+            update.get_document("items.0.price").unwrap(),
+            &doc! { "minor_units": 450_i64, "currency": "EUR" }
+        );
+        assert_eq!(update.get_document("shipping_cost").unwrap().get_i64("minor_units").unwrap(), 300);
+
+        let unknown = Order {
+            currency: "XYZ".to_string(),
+            ..order
+        };
+        assert!(order_money_update(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_parse_migrate_money_command() {
+        let cli = Cli::try_parse_from(["foxy-fabrications-admin", "migrate-money", "--dry-run"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::MigrateMoney { dry_run: true })));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2048), "2.0 KB");
+        assert_eq!(format_size(3 * 1024 * 1024 + 512 * 1024), "3.5 MB");
+    }
+
+    #[test]
+    fn test_describe_access() {
+        assert_eq!(describe_access(true, ""), "Admin");
+        assert_eq!(describe_access(true, "Quotes"), "Admin, Quotes");
+        assert_eq!(describe_access(false, "Viewer"), "Viewer");
+        assert_eq!(describe_access(false, ""), "-");
+    }
+}
@@ -0,0 +1,211 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{FromRequest, Multipart, Request},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use axum_login::tower_sessions::Session;
+use tracing::{error, warn};
+
+use crate::{
+    api_tokens::ApiPrincipal,
+    auth::generate_token,
+    middleware::{GuardResponse, wants_html},
+};
+
+/// Session key holding the per-session CSRF token
+const CSRF_SESSION_KEY: &str = "csrf_token";
+
+/// Header the admin pages' fetch calls send the token in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Hidden form field carrying the token on regular form posts
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Largest body buffered while looking for the form field (product image uploads included)
+const MAX_BUFFERED_BODY: usize = 25 * 1024 * 1024;
+
+/// Get the session's CSRF token, creating one on first use
+pub async fn session_token(session: &Session) -> String {
+    match session.get::<String>(CSRF_SESSION_KEY).await {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let token = generate_token();
+            if let Err(e) = session.insert(CSRF_SESSION_KEY, &token).await {
+                error!("Failed to store CSRF token in session: {}", e);
+            }
+            token
+        }
+        Err(e) => {
+            error!("Failed to read CSRF token from session: {}", e);
+            String::new()
+        }
+    }
+}
+
+/// Compare tokens without leaking how many leading bytes matched
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn is_state_changing(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn content_type(request: &Request) -> String {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Pull the csrf_token field out of a buffered form body
+async fn token_from_form(content_type: &str, body: &[u8]) -> Option<String> {
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        return serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+            .ok()?
+            .into_iter()
+            .find(|(key, _)| key == CSRF_FIELD)
+            .map(|(_, value)| value);
+    }
+
+    if content_type.starts_with("multipart/form-data") {
+        // Parse a copy of the body; the handler gets the original bytes back
+        let copy = Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_vec()))
+            .ok()?;
+        let mut multipart = Multipart::from_request(copy, &()).await.ok()?;
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some(CSRF_FIELD) {
+                return field.text().await.ok();
+            }
+        }
+    }
+
+    None
+}
+
+fn rejected(request_wants_html: bool) -> Response {
+    warn!("Rejected request with missing or invalid CSRF token");
+    if request_wants_html {
+        (
+            StatusCode::FORBIDDEN,
+            "Invalid or missing form token - please go back, reload the page and try again",
+        )
+            .into_response()
+    } else {
+        (
+            StatusCode::FORBIDDEN,
+            Json(GuardResponse {
+                success: false,
+                message: "Invalid or missing CSRF token - reload the page and try again".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Layer for the admin routes: every POST/DELETE must carry the session's
+/// token, either in the X-CSRF-Token header or a csrf_token form field.
+/// Bearer-token requests are exempt: browsers never attach those on their own.
+pub async fn csrf_protect(session: Session, request: Request, next: Next) -> Response {
+    if !is_state_changing(request.method()) || request.extensions().get::<ApiPrincipal>().is_some() {
+        return next.run(request).await;
+    }
+
+    let html = wants_html(&request);
+    let expected = match session.get::<String>(CSRF_SESSION_KEY).await {
+        Ok(Some(token)) => token,
+        _ => return rejected(html),
+    };
+
+    // Header first: that's what fetch() calls use and needs no body buffering
+    if let Some(provided) = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        return if tokens_match(&expected, provided) {
+            next.run(request).await
+        } else {
+            rejected(html)
+        };
+    }
+
+    let content_type = content_type(&request);
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, MAX_BUFFERED_BODY).await {
+        Ok(bytes) => bytes,
+        Err(_) => return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response(),
+    };
+
+    match token_from_form(&content_type, &bytes).await {
+        Some(provided) if tokens_match(&expected, &provided) => {
+            next.run(Request::from_parts(parts, Body::from(bytes))).await
+        }
+        _ => rejected(html),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+        assert!(!tokens_match("abc123", ""));
+    }
+
+    #[test]
+    fn test_safe_methods_are_not_checked() {
+        assert!(!is_state_changing(&Method::GET));
+        assert!(!is_state_changing(&Method::HEAD));
+        assert!(is_state_changing(&Method::POST));
+        assert!(is_state_changing(&Method::DELETE));
+    }
+
+    #[tokio::test]
+    async fn test_token_from_urlencoded_form() {
+        let token = token_from_form(
+            "application/x-www-form-urlencoded",
+            b"order_id=abc&csrf_token=secret-token&status=paid",
+        )
+        .await;
+        assert_eq!(token.as_deref(), Some("secret-token"));
+    }
+
+    #[tokio::test]
+    async fn test_token_from_multipart_form() {
+        let body = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"name\"\r\n\r\n\
+            Fox Badge\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+            secret-token\r\n\
+            --XYZ--\r\n";
+        let token = token_from_form("multipart/form-data; boundary=XYZ", body.as_bytes()).await;
+        assert_eq!(token.as_deref(), Some("secret-token"));
+    }
+
+    #[tokio::test]
+    async fn test_token_missing_from_form() {
+        let token = token_from_form("application/x-www-form-urlencoded", b"order_id=abc").await;
+        assert!(token.is_none());
+
+        let token = token_from_form("application/json", b"{\"csrf_token\":\"x\"}").await;
+        assert!(token.is_none());
+    }
+}
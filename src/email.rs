@@ -0,0 +1,176 @@
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials as SmtpCredentials,
+};
+use std::env;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Sender used when SMTP_FROM isn't set
+const DEFAULT_FROM: &str = "Foxy Fabrications Admin <noreply@foxyfabrications.co.uk>";
+
+#[derive(Debug, Error)]
+pub enum EmailError {
+    #[error("email is not configured (set SMTP_HOST)")]
+    NotConfigured,
+    #[error("invalid address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("could not build message: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+/// How to talk to the SMTP server (SMTP_TLS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Implicit TLS, usually port 465
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587
+    StartTls,
+    /// No encryption; only for local sinks such as MailHog or Mailpit
+    None,
+}
+
+impl SmtpSecurity {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "tls" | "ssl" => Some(SmtpSecurity::Tls),
+            "starttls" => Some(SmtpSecurity::StartTls),
+            "none" | "off" | "false" => Some(SmtpSecurity::None),
+            _ => None,
+        }
+    }
+
+    fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::None => 25,
+        }
+    }
+}
+
+/// Outgoing email over SMTP. Without SMTP_HOST nothing is sent, and
+/// callers get `EmailError::NotConfigured`.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    /// Configure from SMTP_HOST, SMTP_PORT, SMTP_TLS, SMTP_USERNAME,
+    /// SMTP_PASSWORD and SMTP_FROM
+    pub fn from_env() -> Result<Self, String> {
+        let from = env::var("SMTP_FROM")
+            .unwrap_or_else(|_| DEFAULT_FROM.to_string())
+            .parse::<Mailbox>()
+            .map_err(|e| format!("SMTP_FROM is not a valid address: {}", e))?;
+
+        let Ok(host) = env::var("SMTP_HOST") else {
+            return Ok(Self {
+                transport: None,
+                from,
+            });
+        };
+
+        let security = match env::var("SMTP_TLS") {
+            Ok(value) => SmtpSecurity::parse(&value)
+                .ok_or_else(|| format!("SMTP_TLS must be tls, starttls or none, not {}", value))?,
+            Err(_) => SmtpSecurity::StartTls,
+        };
+        let port = match env::var("SMTP_PORT") {
+            Ok(value) => value
+                .parse::<u16>()
+                .map_err(|_| format!("SMTP_PORT is not a valid port: {}", value))?,
+            Err(_) => security.default_port(),
+        };
+
+        let mut builder = match security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+                .map_err(|e| format!("Invalid SMTP_HOST: {}", e))?,
+            SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+                .map_err(|e| format!("Invalid SMTP_HOST: {}", e))?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+        }
+        .port(port);
+
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(SmtpCredentials::new(username, password));
+        }
+
+        info!("📧 Email via {}:{} ({:?})", host, port, security);
+        Ok(Self {
+            transport: Some(builder.build()),
+            from,
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// Build a plain-text message from the configured sender
+    pub fn build_message(&self, to: &str, subject: &str, body: String) -> Result<Message, EmailError> {
+        Ok(Message::builder()
+            .from(self.from.clone())
+            .to(to.parse::<Mailbox>()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?)
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), EmailError> {
+        let Some(ref transport) = self.transport else {
+            warn!("Not sending '{}' to {}: email is not configured", subject, to);
+            return Err(EmailError::NotConfigured);
+        };
+        let message = self.build_message(to, subject, body)?;
+        transport.send(message).await?;
+        info!("📧 Sent '{}' to {}", subject, to);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unconfigured() -> Mailer {
+        Mailer {
+            transport: None,
+            from: DEFAULT_FROM.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_smtp_security_parse() {
+        assert_eq!(SmtpSecurity::parse("STARTTLS"), Some(SmtpSecurity::StartTls));
+        assert_eq!(SmtpSecurity::parse("ssl"), Some(SmtpSecurity::Tls));
+        assert_eq!(SmtpSecurity::parse("none"), Some(SmtpSecurity::None));
+        assert_eq!(SmtpSecurity::parse("maybe"), None);
+        assert_eq!(SmtpSecurity::None.default_port(), 25);
+    }
+
+    #[test]
+    fn test_build_message_rejects_bad_recipient() {
+        let mailer = unconfigured();
+        assert!(mailer.build_message("staff@example.com", "Hi", "Body".into()).is_ok());
+        assert!(matches!(
+            mailer.build_message("not an address", "Hi", "Body".into()),
+            Err(EmailError::Address(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_without_smtp_host_fails() {
+        let mailer = unconfigured();
+        assert!(!mailer.is_configured());
+        assert!(matches!(
+            mailer.send("staff@example.com", "Hi", "Body".into()).await,
+            Err(EmailError::NotConfigured)
+        ));
+    }
+}
@@ -0,0 +1,647 @@
+use askama::Template;
+use axum::{
+    Extension, Form,
+    extract::Path,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_login::tower_sessions::Session;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, doc, oid::ObjectId};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    api_tokens::{self, ApiToken, ApiTokens},
+    auth::{hash_password, validate_new_password, verify_password},
+    handlers::auth::AppAuthSession,
+    models::{
+        ApiTokenDisplay, ApiTokenForm, ApiTokensTemplate, ChangePasswordForm, ChangePasswordTemplate,
+        SessionDisplay, SessionsTemplate, TwoFactorSettingsTemplate, TwoFactorSetup, User, UserState,
+    },
+    session_store::{MongoSessionStore, SessionDocument},
+    two_factor::{self, TwoFactorCipher, TwoFactorError},
+};
+
+fn change_password_page(user_state: UserState, success_message: &str, error_message: String) -> Response {
+    Html(
+        ChangePasswordTemplate {
+            user_state,
+            success_message: success_message.to_string(),
+            error_message,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+pub async fn show_change_password_form(user_state: UserState) -> impl IntoResponse {
+    change_password_page(user_state, "", String::new())
+}
+
+/// Change the logged-in user's password after checking the current one
+pub async fn change_password(
+    mut auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(sessions): Extension<MongoSessionStore>,
+    Form(form): Form<ChangePasswordForm>,
+) -> impl IntoResponse {
+    let user = auth.user.clone().expect("require_staff lets only logged-in users through");
+
+    if !verify_password(&form.current_password, &user.password_hash).await {
+        return change_password_page(user_state, "", "Current password is incorrect".into());
+    }
+    if let Err(error) = validate_new_password(&form.password, &form.confirm_password) {
+        return change_password_page(user_state, "", error);
+    }
+
+    let password_hash = match hash_password(&form.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return change_password_page(user_state, "", "Server error".into());
+        }
+    };
+
+    if let Err(e) = auth
+        .backend
+        .users
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "password_hash": &password_hash } })
+        .await
+    {
+        return change_password_page(user_state, "", format!("Database error: {}", e));
+    }
+    info!("{} changed their password", user.username);
+
+    // Other sessions are tied to the old hash and would be refused anyway;
+    // remove them so they drop off the sessions page too
+    let current = session.id().map(|id| id.to_string());
+    if let Err(e) = sessions.revoke_all_for_user(user.id, current.as_deref()).await {
+        error!("Failed to remove other sessions for {}: {}", user.username, e);
+    }
+
+    // Log in again with the new hash so this session survives
+    let updated = User { password_hash, ..user };
+    if let Err(e) = auth.login(&updated).await {
+        error!("Failed to refresh session after password change: {}", e);
+    }
+    change_password_page(
+        user_state,
+        "Password changed - you've been logged out on other devices",
+        String::new(),
+    )
+}
+
+/// Session key holding the not-yet-confirmed secret during enrolment
+const SETUP_SECRET_KEY: &str = "totp_setup_secret";
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+/// Settings page state for `user`, with nothing pending and no messages
+fn settings_page(user: &User, user_state: UserState, cipher: &TwoFactorCipher) -> TwoFactorSettingsTemplate {
+    TwoFactorSettingsTemplate {
+        user_state,
+        enabled: user.has_two_factor(),
+        available: cipher.is_configured(),
+        recovery_codes_left: user.recovery_codes.len(),
+        setup: None,
+        new_recovery_codes: vec![],
+        success_message: String::new(),
+        error_message: String::new(),
+    }
+}
+
+fn render(template: TwoFactorSettingsTemplate) -> Response {
+    Html(template.render().unwrap()).into_response()
+}
+
+fn build_setup(secret: &[u8], username: &str) -> Result<TwoFactorSetup, TwoFactorError> {
+    let otpauth_uri = two_factor::otpauth_uri(secret, username)?;
+    Ok(TwoFactorSetup {
+        qr_svg: two_factor::qr_svg(&otpauth_uri)?,
+        otpauth_uri,
+        secret: two_factor::secret_base32(secret),
+    })
+}
+
+/// Show whether two-factor is on for the logged-in user
+pub async fn show_two_factor_settings(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(cipher): Extension<TwoFactorCipher>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    render(settings_page(&user, user_state, &cipher))
+}
+
+/// Generate a secret and show the QR code to scan (the only time it's shown)
+pub async fn start_two_factor_setup(
+    auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(cipher): Extension<TwoFactorCipher>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    let mut page = settings_page(&user, user_state, &cipher);
+
+    if page.enabled {
+        page.error_message = "Two-factor authentication is already enabled".to_string();
+        return render(page);
+    }
+    if !page.available {
+        page.error_message = TwoFactorError::NotConfigured.to_string();
+        return render(page);
+    }
+
+    let secret = two_factor::generate_secret();
+    match build_setup(&secret, &user.username) {
+        Ok(setup) => {
+            if let Err(e) = session.insert(SETUP_SECRET_KEY, &setup.secret).await {
+                error!("Failed to store 2FA setup secret: {}", e);
+                page.error_message = "Server error".to_string();
+            } else {
+                page.setup = Some(setup);
+            }
+        }
+        Err(e) => {
+            error!("Failed to build 2FA setup for {}: {}", user.username, e);
+            page.error_message = "Server error".to_string();
+        }
+    }
+    render(page)
+}
+
+/// Confirm the first code from the app, then save the secret and issue recovery codes
+pub async fn enable_two_factor(
+    auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(cipher): Extension<TwoFactorCipher>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    let mut page = settings_page(&user, user_state, &cipher);
+
+    let secret = match session.get::<String>(SETUP_SECRET_KEY).await {
+        Ok(Some(encoded)) => two_factor::secret_from_base32(&encoded),
+        _ => None,
+    };
+    let Some(secret) = secret else {
+        page.error_message = "Setup expired - please start again".to_string();
+        return render(page);
+    };
+
+    let step = match two_factor::verify_code(&secret, &form.code, two_factor::now_unix(), None) {
+        Ok(Some(step)) => step,
+        Ok(None) => {
+            page.error_message = "That code didn't match - check your app and try again".to_string();
+            page.setup = build_setup(&secret, &user.username).ok();
+            return render(page);
+        }
+        Err(e) => {
+            error!("2FA code check failed: {}", e);
+            page.error_message = "Server error".to_string();
+            return render(page);
+        }
+    };
+
+    let encrypted = match cipher.encrypt(&secret) {
+        Ok(encrypted) => encrypted,
+        Err(e) => {
+            page.error_message = e.to_string();
+            return render(page);
+        }
+    };
+    let codes = two_factor::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| two_factor::hash_recovery_code(c)).collect();
+
+    let update = doc! {
+        "$set": {
+            "totp_secret": encrypted,
+            "totp_last_step": step,
+            "recovery_codes": &hashes,
+        }
+    };
+    match auth.backend.users.update_one(doc! { "_id": user.id }, update).await {
+        Ok(_) => {
+            let _ = session.remove::<String>(SETUP_SECRET_KEY).await;
+            info!("{} enabled two-factor authentication", user.username);
+            page.enabled = true;
+            page.recovery_codes_left = codes.len();
+            page.new_recovery_codes = codes;
+            page.success_message = "Two-factor authentication is now enabled".to_string();
+        }
+        Err(e) => {
+            error!("Failed to save 2FA secret for {}: {}", user.username, e);
+            page.error_message = format!("Database error: {}", e);
+        }
+    }
+    render(page)
+}
+
+/// Replace the recovery codes (needs a current code from the app)
+pub async fn regenerate_recovery_codes(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(cipher): Extension<TwoFactorCipher>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    let users = &auth.backend.users;
+    let mut page = settings_page(&user, user_state, &cipher);
+
+    match two_factor::verify_totp(users, &cipher, &user, &form.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            page.error_message = "Invalid code".to_string();
+            return render(page);
+        }
+        Err(e) => {
+            error!("2FA check for {} failed: {}", user.username, e);
+            page.error_message = "Server error".to_string();
+            return render(page);
+        }
+    }
+
+    let codes = two_factor::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| two_factor::hash_recovery_code(c)).collect();
+    match users
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "recovery_codes": &hashes } })
+        .await
+    {
+        Ok(_) => {
+            page.recovery_codes_left = codes.len();
+            page.new_recovery_codes = codes;
+            page.success_message = "New recovery codes generated - the old ones no longer work".to_string();
+        }
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    render(page)
+}
+
+/// Turn two-factor off (needs a current code or a recovery code)
+pub async fn disable_two_factor(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(cipher): Extension<TwoFactorCipher>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    let users = &auth.backend.users;
+    let mut page = settings_page(&user, user_state, &cipher);
+
+    match two_factor::verify_second_factor(users, &cipher, &user, &form.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            page.error_message = "Invalid code".to_string();
+            return render(page);
+        }
+        Err(e) => {
+            error!("2FA check for {} failed: {}", user.username, e);
+            page.error_message = "Server error".to_string();
+            return render(page);
+        }
+    }
+
+    match two_factor::clear_two_factor(users, user.id).await {
+        Ok(_) => {
+            info!("{} disabled two-factor authentication", user.username);
+            page.enabled = false;
+            page.recovery_codes_left = 0;
+            page.success_message = "Two-factor authentication is now disabled".to_string();
+        }
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    render(page)
+}
+
+fn format_timestamp(timestamp: Option<bson::DateTime>) -> String {
+    timestamp
+        .and_then(|t| DateTime::<Utc>::from_timestamp_millis(t.timestamp_millis()))
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+fn convert_token(token: &ApiToken, now: bson::DateTime) -> ApiTokenDisplay {
+    ApiTokenDisplay {
+        id: token.id.to_hex(),
+        name: token.name.clone(),
+        display_prefix: token.display_prefix.clone(),
+        scopes: token.scopes.join(", "),
+        formatted_created_at: format_timestamp(Some(token.created_at)),
+        formatted_expires_at: format_timestamp(token.expires_at),
+        formatted_last_used_at: format_timestamp(token.last_used_at),
+        expired: token.is_expired(now),
+    }
+}
+
+/// Tokens page for `user`, listing what they currently have
+async fn tokens_page(tokens: &ApiTokens, user: &User, user_state: UserState) -> ApiTokensTemplate {
+    let mut page = ApiTokensTemplate {
+        user_state,
+        tokens: vec![],
+        available_scopes: api_tokens::available_scopes(user),
+        expiry_days: api_tokens::EXPIRY_DAYS,
+        new_token: None,
+        success_message: String::new(),
+        error_message: String::new(),
+    };
+    match tokens.list_for_user(user.id).await {
+        Ok(list) => {
+            let now = bson::DateTime::now();
+            page.tokens = list.iter().map(|token| convert_token(token, now)).collect();
+        }
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    page
+}
+
+fn render_tokens(template: ApiTokensTemplate) -> Response {
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Check a new token request, returning the scopes to store
+fn validate_token_form(form: &ApiTokenForm, user: &User) -> Result<Vec<String>, String> {
+    if form.name.is_empty() {
+        return Err("Give the token a name so you can tell it apart later".to_string());
+    }
+    if form.name.len() > 100 {
+        return Err("Token name is too long".to_string());
+    }
+    if form
+        .expires_in_days
+        .is_some_and(|days| !api_tokens::EXPIRY_DAYS.contains(&days))
+    {
+        return Err("Invalid expiry".to_string());
+    }
+
+    let granted = api_tokens::granted_permissions(user, &form.scopes);
+    if granted.len() != form.scopes.len() {
+        return Err("You can only give a token permissions you have yourself".to_string());
+    }
+    if granted.is_empty() {
+        return Err("Select at least one permission".to_string());
+    }
+    // Keep the stored order stable rather than whatever the set iterates in
+    Ok(api_tokens::token_scopes()
+        .filter(|scope| granted.contains(*scope))
+        .map(str::to_string)
+        .collect())
+}
+
+/// List the logged-in user's API tokens
+pub async fn show_api_tokens(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(tokens): Extension<ApiTokens>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    render_tokens(tokens_page(&tokens, &user, user_state).await)
+}
+
+/// Create a token and show it once
+pub async fn create_api_token(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(tokens): Extension<ApiTokens>,
+    Form(pairs): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    let form = ApiTokenForm::from_pairs(pairs);
+
+    let scopes = match validate_token_form(&form, &user) {
+        Ok(scopes) => scopes,
+        Err(error) => {
+            let mut page = tokens_page(&tokens, &user, user_state).await;
+            page.error_message = error;
+            return render_tokens(page);
+        }
+    };
+
+    let expires_at = form.expires_in_days.map(|days| {
+        bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + days * 24 * 60 * 60 * 1000)
+    });
+    let created = tokens.create(user.id, form.name, scopes, expires_at).await;
+
+    let mut page = tokens_page(&tokens, &user, user_state).await;
+    match created {
+        Ok((token, secret)) => {
+            info!("{} created API token '{}'", user.username, token.name);
+            page.new_token = Some(secret);
+            page.success_message = format!("Token '{}' created", token.name);
+        }
+        Err(e) => {
+            error!("Failed to create API token for {}: {}", user.username, e);
+            page.error_message = format!("Database error: {}", e);
+        }
+    }
+    render_tokens(page)
+}
+
+/// Revoke one of the logged-in user's tokens
+pub async fn revoke_api_token(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(tokens): Extension<ApiTokens>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+
+    let revoked = match ObjectId::parse_str(&id) {
+        Ok(token_id) => tokens.revoke(user.id, token_id).await,
+        Err(_) => Ok(false),
+    };
+
+    let mut page = tokens_page(&tokens, &user, user_state).await;
+    match revoked {
+        Ok(true) => {
+            info!("{} revoked API token {}", user.username, id);
+            page.success_message = "Token revoked".to_string();
+        }
+        Ok(false) => page.error_message = "Token not found".to_string(),
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    render_tokens(page)
+}
+
+fn convert_session(document: SessionDocument, current: Option<&str>) -> SessionDisplay {
+    let info = document.info.unwrap_or_default();
+    let format_millis = |millis: i64| match millis {
+        0 => String::new(),
+        millis => format_timestamp(Some(bson::DateTime::from_millis(millis))),
+    };
+    SessionDisplay {
+        is_current: current == Some(document.id.as_str()),
+        id: document.id,
+        formatted_created_at: format_millis(info.created_at),
+        formatted_last_seen_at: format_millis(info.last_seen_at),
+        ip: info.ip,
+        user_agent: info.user_agent,
+    }
+}
+
+/// Sessions page for `user`, with the one making this request marked
+async fn sessions_page(
+    sessions: &MongoSessionStore,
+    session: &Session,
+    user: &User,
+    user_state: UserState,
+) -> SessionsTemplate {
+    let mut page = SessionsTemplate {
+        user_state,
+        sessions: vec![],
+        success_message: String::new(),
+        error_message: String::new(),
+    };
+    let current = session.id().map(|id| id.to_string());
+    match sessions.list_for_user(user.id).await {
+        Ok(list) => {
+            page.sessions = list
+                .into_iter()
+                .map(|document| convert_session(document, current.as_deref()))
+                .collect();
+        }
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    page
+}
+
+/// List where the logged-in user is signed in
+pub async fn show_sessions(
+    auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(sessions): Extension<MongoSessionStore>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+    Html(sessions_page(&sessions, &session, &user, user_state).await.render().unwrap())
+}
+
+/// Sign out one of the logged-in user's other sessions
+pub async fn revoke_session(
+    auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(sessions): Extension<MongoSessionStore>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let user = auth.user.expect("require_staff lets only logged-in users through");
+
+    if session.id().is_some_and(|current| current.to_string() == id) {
+        let mut page = sessions_page(&sessions, &session, &user, user_state).await;
+        page.error_message = "Use Log Out to end this session".to_string();
+        return Html(page.render().unwrap());
+    }
+
+    let revoked = sessions.revoke(user.id, &id).await;
+    let mut page = sessions_page(&sessions, &session, &user, user_state).await;
+    match revoked {
+        Ok(true) => {
+            info!("{} revoked one of their sessions", user.username);
+            page.success_message = "Session signed out".to_string();
+        }
+        Ok(false) => page.error_message = "Session not found - it may have already ended".to_string(),
+        Err(e) => page.error_message = format!("Database error: {}", e),
+    }
+    Html(page.render().unwrap())
+}
+
+/// Sign out every session of the logged-in user, this one included
+pub async fn logout_everywhere(
+    mut auth: AppAuthSession,
+    Extension(sessions): Extension<MongoSessionStore>,
+) -> impl IntoResponse {
+    let user = auth.user.clone().expect("require_staff lets only logged-in users through");
+
+    if let Err(e) = sessions.revoke_all_for_user(user.id, None).await {
+        error!("Failed to remove sessions for {}: {}", user.username, e);
+        return Redirect::to("/account/sessions").into_response();
+    }
+    info!("{} logged out everywhere", user.username);
+    if let Err(e) = auth.logout().await {
+        error!("Failed to end current session for {}: {}", user.username, e);
+    }
+    Redirect::to("/login").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+
+    fn fulfilment_user() -> User {
+        User::new("packer@example.com".to_string(), String::new(), false, vec![Role::Fulfilment])
+    }
+
+    fn form(pairs: &[(&str, &str)]) -> ApiTokenForm {
+        ApiTokenForm::from_pairs(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_convert_session_marks_current() {
+        let document = SessionDocument {
+            id: "abc".to_string(),
+            data: "{}".to_string(),
+            expires_at: bson::DateTime::now(),
+            user_id: Some(ObjectId::new()),
+            info: None,
+        };
+
+        let display = convert_session(document.clone(), Some("abc"));
+        assert!(display.is_current);
+        // Sessions from before tracking started have no details yet
+        assert_eq!(display.formatted_last_seen_at, "");
+
+        assert!(!convert_session(document, Some("xyz")).is_current);
+    }
+
+    #[test]
+    fn test_token_form_from_pairs() {
+        let parsed = form(&[
+            ("csrf_token", "x"),
+            ("name", "  Label printer "),
+            ("scopes", "orders:view"),
+            ("scopes", "orders:update"),
+            ("expires_in_days", "90"),
+        ]);
+        assert_eq!(parsed.name, "Label printer");
+        assert_eq!(parsed.scopes, vec!["orders:view", "orders:update"]);
+        assert_eq!(parsed.expires_in_days, Some(90));
+
+        assert_eq!(form(&[("expires_in_days", "never")]).expires_in_days, None);
+    }
+
+    #[test]
+    fn test_validate_token_form() {
+        let user = fulfilment_user();
+
+        let scopes = validate_token_form(
+            &form(&[("name", "Printer"), ("scopes", "orders:update"), ("scopes", "orders:view")]),
+            &user,
+        )
+        .unwrap();
+        assert_eq!(scopes, vec!["orders:view", "orders:update"]);
+
+        assert!(validate_token_form(&form(&[("scopes", "orders:view")]), &user).is_err());
+        assert!(validate_token_form(&form(&[("name", "Printer")]), &user).is_err());
+        assert!(
+            validate_token_form(&form(&[("name", "Sync"), ("scopes", "products:edit")]), &user).is_err()
+        );
+        assert!(
+            validate_token_form(
+                &form(&[("name", "Printer"), ("scopes", "orders:view"), ("expires_in_days", "7")]),
+                &user
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::Query,
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Json},
+};
+
+use crate::{
+    audit::{self, AuditEntry, AuditFilter, AuditLog},
+    handlers::order_processing::create_pagination_info,
+    models::{AuditEntryDisplay, AuditPageParams, AuditTemplate, UserState},
+};
+
+/// Entries per page on the audit log
+const PAGE_SIZE: u32 = 50;
+
+/// Most entries a single export returns; narrow the filters for more
+const MAX_EXPORT_ENTRIES: i64 = 10_000;
+
+pub fn convert_to_display(entry: AuditEntry) -> AuditEntryDisplay {
+    AuditEntryDisplay {
+        timestamp: audit::format_timestamp(entry.timestamp, "%Y-%m-%dT%H:%M:%SZ"),
+        formatted_timestamp: audit::format_timestamp(entry.timestamp, "%Y-%m-%d %H:%M:%S"),
+        actor: entry.actor,
+        action: entry.action,
+        target_id: entry.target_id.unwrap_or_default(),
+        changes: entry.changes,
+        ip: entry.ip.unwrap_or_default(),
+        via_api_token: entry.token_id.is_some(),
+    }
+}
+
+/// Filterable, paginated audit log
+pub async fn list_audit_log(
+    Extension(audit_log): Extension<AuditLog>,
+    Query(filter): Query<AuditFilter>,
+    Query(params): Query<AuditPageParams>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let page = params.page.unwrap_or(1).max(1);
+    let skip = ((page - 1) * PAGE_SIZE) as u64;
+
+    let (entries, total, error_message) = match audit_log.find(&filter, skip, PAGE_SIZE as i64).await {
+        Ok((entries, total)) => (entries, total, String::new()),
+        Err(e) => (vec![], 0, format!("Database error fetching audit log: {}", e)),
+    };
+
+    let template = AuditTemplate {
+        user_state,
+        entries: entries.into_iter().map(convert_to_display).collect(),
+        pagination: create_pagination_info(page, PAGE_SIZE, total),
+        query_string: filter.query_string(),
+        filter,
+        actions: audit::actions::ALL,
+        error_message,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// The filtered log as a JSON download
+pub async fn export_audit_log(
+    Extension(audit_log): Extension<AuditLog>,
+    Query(filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    match audit_log.find(&filter, 0, MAX_EXPORT_ENTRIES).await {
+        Ok((entries, _)) => {
+            let entries: Vec<AuditEntryDisplay> = entries.into_iter().map(convert_to_display).collect();
+            (
+                [(header::CONTENT_DISPOSITION, "attachment; filename=\"audit-log.json\"")],
+                Json(entries),
+            )
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error exporting audit log: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::FieldChange;
+    use mongodb::bson::{self, oid::ObjectId};
+
+    #[test]
+    fn test_convert_to_display() {
+        let entry = AuditEntry {
+            id: ObjectId::new(),
+            timestamp: bson::DateTime::from_millis(1_740_830_400_000), // 2025-03-01 12:00:00 UTC
+            actor: "owner@example.com".to_string(),
+            action: audit::actions::PRODUCT_UPDATED.to_string(),
+            target_id: Some("abc".to_string()),
+            changes: vec![FieldChange {
+                field: "price".to_string(),
+                before: Some("4.50".to_string()),
+                after: Some("5.00".to_string()),
+            }],
+            ip: None,
+            token_id: Some(ObjectId::new()),
+        };
+
+        let display = convert_to_display(entry);
+        assert_eq!(display.timestamp, "2025-03-01T12:00:00Z");
+        assert_eq!(display.formatted_timestamp, "2025-03-01 12:00:00");
+        assert_eq!(display.ip, "");
+        assert!(display.via_api_token);
+
+        let json = serde_json::to_value(&display).unwrap();
+        assert!(json.get("formatted_timestamp").is_none());
+        assert_eq!(json["changes"][0]["after"], "5.00");
+    }
+}
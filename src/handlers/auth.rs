@@ -1,16 +1,33 @@
 use askama::Template;
 use axum::{
-    Form,
-    extract::Query,
-    response::{Html, IntoResponse, Redirect},
+    Extension, Form,
+    extract::{ConnectInfo, Query},
+    response::{Html, IntoResponse, Redirect, Response},
 };
-use axum_login::AuthSession;
-use serde::Deserialize;
+use axum_login::{AuthSession, tower_sessions::Session};
+use mongodb::bson::{self, doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tracing::{error, info, warn};
 
 use crate::{
-    auth::MongoAuth,
-    models::{Credentials, LoginTemplate},
-    user_state::extract_user_state,
+    audit::{self, Actor, AuditLog},
+    auth::{MongoAuth, hash_password, validate_new_password, verify_password},
+    email::Mailer,
+    login_throttle::{
+        LoginThrottle, clear_account_failures, describe_wait, locked_until, record_account_failure,
+    },
+    models::{
+        Credentials, ForgotPasswordForm, ForgotPasswordTemplate, LoginTemplate, NewPasswordForm,
+        NewPasswordTemplate, TwoFactorLoginTemplate, User, UserState,
+    },
+    password_reset::{PasswordResets, RESET_TOKEN_LIFETIME, ResetThrottle},
+    session_store::MongoSessionStore,
+    two_factor::{self, TwoFactorCipher},
 };
 
 // —————————————————————————————
@@ -19,6 +36,7 @@ use crate::{
 #[derive(Deserialize)]
 pub struct RedirectQuery {
     next: Option<String>,
+    reset: Option<String>,
 }
 
 fn validate_redirect_url(url: &str) -> bool {
@@ -29,24 +47,28 @@ fn validate_redirect_url(url: &str) -> bool {
 fn get_safe_redirect_url(query: Option<String>) -> String {
     match query {
         Some(url) if validate_redirect_url(&url) => url,
-        _ => "/products".to_string(), // Default redirect
+        _ => "/".to_string(), // Default redirect (dashboard picks a page for the role)
     }
 }
 
-
 // —————————————————————————————
 // Login
 // —————————————————————————————
 
 pub async fn show_login_form(
-    auth: AppAuthSession,
+    user_state: UserState,
     Query(query): Query<RedirectQuery>,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
     let next_url = query.next.unwrap_or_default();
+    let notice = if query.reset.is_some() {
+        "Your password has been changed - please log in".to_string()
+    } else {
+        String::new()
+    };
     Html(
         LoginTemplate {
             error: "".into(),
+            notice,
             next_url,
             user_state,
         }
@@ -57,53 +79,425 @@ pub async fn show_login_form(
 
 pub type AppAuthSession = AuthSession<MongoAuth>;
 
+/// Re-render the login form with an error message
+fn login_error(user_state: UserState, next_url: String, error: String) -> Response {
+    Html(
+        LoginTemplate {
+            error,
+            notice: String::new(),
+            next_url,
+            user_state,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
 #[axum::debug_handler]
 pub async fn handle_login(
     mut auth: AppAuthSession,
+    user_state: UserState,
+    session: Session,
+    Extension(throttle): Extension<LoginThrottle>,
+    Extension(audit_log): Extension<AuditLog>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Form(creds): Form<Credentials>,
 ) -> impl IntoResponse {
     let next_url = creds.next.clone().unwrap_or_default();
+    let ip = addr.ip();
+
+    // Slow down anyone hammering the form from one address
+    if let Some(wait) = throttle.retry_after(ip) {
+        warn!("Throttling login attempts from {}", ip);
+        return login_error(
+            user_state,
+            next_url,
+            format!("Too many failed attempts. Try again in {}.", describe_wait(wait)),
+        );
+    }
+
+    // Tell locked-out staff why their correct password isn't working. Only
+    // once the password is right, so a lock doesn't give away that the
+    // username exists; wrong passwords get the usual error below.
+    if let Ok(Some(user)) = auth.backend.users.find_one(doc! { "username": &creds.username }).await
+        && !user.disabled
+        && let Some(until) = locked_until(&user)
+        && verify_password(&creds.password, &user.password_hash).await
+    {
+        let wait = Duration::from_millis(
+            (until.timestamp_millis() - bson::DateTime::now().timestamp_millis()).max(0) as u64,
+        );
+        return login_error(
+            user_state,
+            next_url,
+            format!(
+                "This account is temporarily locked after too many failed attempts. Try again in {} or ask an administrator to unlock it.",
+                describe_wait(wait)
+            ),
+        );
+    }
 
     match auth.authenticate(creds.clone()).await {
-        Ok(Some(u)) => {
-            if auth.login(&u).await.is_err() {
-                let user_state = extract_user_state(&auth);
-                let tpl = LoginTemplate {
-                    error: "Internal error".into(),
-                    next_url,
-                    user_state,
-                };
-                Html(tpl.render().unwrap()).into_response()
-            } else {
-                let redirect_url = get_safe_redirect_url(creds.next);
-                Redirect::to(&redirect_url).into_response()
+        Ok(Some(u)) if u.has_two_factor() => {
+            // Password was right; hold the login until the TOTP step passes
+            let pending = PendingLogin {
+                user_id: u.id.to_hex(),
+                next: creds.next,
+                started_at: bson::DateTime::now().timestamp_millis(),
+            };
+            if let Err(e) = session.insert(PENDING_LOGIN_KEY, &pending).await {
+                error!("Failed to store pending login: {}", e);
+                return login_error(user_state, next_url, "Server error".into());
             }
+            Redirect::to("/login/2fa").into_response()
         }
+        Ok(Some(u)) => complete_login(&mut auth, &throttle, &audit_log, ip, u, creds.next)
+            .await
+            .unwrap_or_else(|| login_error(user_state, next_url, "Internal error".into())),
         Ok(None) => {
-            let user_state = extract_user_state(&auth);
-            Html(
-                LoginTemplate {
-                    error: "Bad credentials".into(),
-                    next_url,
-                    user_state,
-                }
-                .render()
-                .unwrap(),
-            )
-            .into_response()
-        }
-        Err(_) => {
-            let user_state = extract_user_state(&auth);
-            Html(
-                LoginTemplate {
-                    error: "Server error".into(),
-                    next_url,
-                    user_state,
-                }
-                .render()
-                .unwrap(),
-            )
-            .into_response()
+            warn!("Failed login for '{}' from {}", creds.username, ip);
+            throttle.record_failure(ip);
+            audit_log
+                .record(&Actor::anonymous(&creds.username, ip), audit::actions::LOGIN_FAILED, None, vec![])
+                .await;
+            if let Err(e) = record_account_failure(&auth.backend.users, &creds.username).await {
+                error!("Failed to record login failure for {}: {}", creds.username, e);
+            }
+            login_error(user_state, next_url, "Bad credentials".into())
+        }
+        Err(_) => login_error(user_state, next_url, "Server error".into()),
+    }
+}
+
+/// Log the user in and send them on; None if the session couldn't be set up
+async fn complete_login(
+    auth: &mut AppAuthSession,
+    throttle: &LoginThrottle,
+    audit_log: &AuditLog,
+    ip: IpAddr,
+    user: User,
+    next: Option<String>,
+) -> Option<Response> {
+    throttle.record_success(ip);
+    if let Err(e) = clear_account_failures(&auth.backend.users, user.id).await {
+        error!("Failed to reset login failures for {}: {}", user.username, e);
+    }
+
+    if auth.login(&user).await.is_err() {
+        return None;
+    }
+    audit_log
+        .record(&Actor::anonymous(&user.username, ip), audit::actions::LOGIN_SUCCEEDED, None, vec![])
+        .await;
+    let redirect_url = get_safe_redirect_url(next);
+    Some(Redirect::to(&redirect_url).into_response())
+}
+
+// —————————————————————————————
+// Two-factor login step
+// —————————————————————————————
+
+/// Session key for a login that passed the password check but not 2FA yet
+const PENDING_LOGIN_KEY: &str = "pending_2fa_login";
+
+/// How long the second step may take before the password must be re-entered
+const PENDING_LOGIN_TTL_MS: i64 = 5 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PendingLogin {
+    user_id: String,
+    next: Option<String>,
+    started_at: i64,
+}
+
+impl PendingLogin {
+    fn is_expired(&self, now_ms: i64) -> bool {
+        now_ms - self.started_at > PENDING_LOGIN_TTL_MS
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorForm {
+    code: String,
+}
+
+/// The pending login, if there is one and it hasn't gone stale
+async fn pending_login(session: &Session) -> Option<PendingLogin> {
+    let pending = session
+        .get::<PendingLogin>(PENDING_LOGIN_KEY)
+        .await
+        .ok()
+        .flatten()?;
+    if pending.is_expired(bson::DateTime::now().timestamp_millis()) {
+        let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+        return None;
+    }
+    Some(pending)
+}
+
+fn two_factor_error(user_state: UserState, error: String) -> Response {
+    Html(TwoFactorLoginTemplate { error, user_state }.render().unwrap()).into_response()
+}
+
+pub async fn show_two_factor_form(session: Session, user_state: UserState) -> impl IntoResponse {
+    if pending_login(&session).await.is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    two_factor_error(user_state, String::new())
+}
+
+#[allow(clippy::too_many_arguments)] // One per extractor
+pub async fn handle_two_factor(
+    mut auth: AppAuthSession,
+    session: Session,
+    user_state: UserState,
+    Extension(throttle): Extension<LoginThrottle>,
+    Extension(cipher): Extension<TwoFactorCipher>,
+    Extension(audit_log): Extension<AuditLog>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<TwoFactorForm>,
+) -> impl IntoResponse {
+    let ip = addr.ip();
+    let Some(pending) = pending_login(&session).await else {
+        return Redirect::to("/login").into_response();
+    };
+
+    if let Some(wait) = throttle.retry_after(ip) {
+        warn!("Throttling 2FA attempts from {}", ip);
+        return two_factor_error(
+            user_state,
+            format!("Too many failed attempts. Try again in {}.", describe_wait(wait)),
+        );
+    }
+
+    let users = auth.backend.users.clone();
+    let user = match ObjectId::parse_str(&pending.user_id) {
+        Ok(id) => users.find_one(doc! { "_id": id }).await,
+        Err(_) => Ok(None),
+    };
+    let user = match user {
+        Ok(Some(user)) if !user.disabled && locked_until(&user).is_none() => user,
+        Ok(_) => {
+            // Deleted, disabled or locked since the password step: start again
+            let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+            return Redirect::to("/login").into_response();
+        }
+        Err(e) => {
+            error!("Failed to load user for 2FA: {}", e);
+            return two_factor_error(user_state, "Server error".into());
+        }
+    };
+
+    match two_factor::verify_second_factor(&users, &cipher, &user, &form.code).await {
+        Ok(true) => {
+            let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+            match complete_login(&mut auth, &throttle, &audit_log, ip, user, pending.next).await {
+                Some(response) => response,
+                None => two_factor_error(user_state, "Internal error".into()),
+            }
+        }
+        Ok(false) => {
+            warn!("Failed 2FA code for '{}' from {}", user.username, ip);
+            throttle.record_failure(ip);
+            audit_log
+                .record(&Actor::anonymous(&user.username, ip), audit::actions::LOGIN_FAILED, None, vec![])
+                .await;
+            if let Err(e) = record_account_failure(&users, &user.username).await {
+                error!("Failed to record login failure for {}: {}", user.username, e);
+            }
+            two_factor_error(user_state, "Invalid code".into())
+        }
+        Err(e) => {
+            error!("2FA check for {} failed: {}", user.username, e);
+            two_factor_error(user_state, "Server error".into())
+        }
+    }
+}
+
+// —————————————————————————————
+// Forgotten password
+// —————————————————————————————
+
+/// Base URL for links in emails (the admin isn't always reached via localhost)
+pub fn public_base_url() -> String {
+    env::var("ADMIN_BASE_URL").unwrap_or_else(|_| {
+        let port = env::var("ADMIN_PORT").unwrap_or_else(|_| "3001".to_string());
+        format!("http://localhost:{}", port)
+    })
+}
+
+fn reset_email_body(link: &str) -> String {
+    format!(
+        "Someone asked to reset the password for your Foxy Fabrications admin account.\n\n\
+         To choose a new password, open this link within {} minutes:\n\n{}\n\n\
+         The link works once. If you didn't ask for this, you can ignore this email.\n",
+        RESET_TOKEN_LIFETIME.as_secs() / 60,
+        link
+    )
+}
+
+pub async fn show_forgot_password_form(user_state: UserState) -> impl IntoResponse {
+    Html(
+        ForgotPasswordTemplate {
+            message: String::new(),
+            error: String::new(),
+            user_state,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+pub async fn handle_forgot_password(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(mailer): Extension<Mailer>,
+    Extension(resets): Extension<PasswordResets>,
+    Extension(ResetThrottle(throttle)): Extension<ResetThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<ForgotPasswordForm>,
+) -> impl IntoResponse {
+    let render = |message: String, error: String| {
+        Html(
+            ForgotPasswordTemplate {
+                message,
+                error,
+                user_state,
+            }
+            .render()
+            .unwrap(),
+        )
+    };
+
+    if !mailer.is_configured() {
+        return render(
+            String::new(),
+            "Password reset by email isn't set up - ask an administrator to reset your password".into(),
+        );
+    }
+
+    // Each request counts against the address, so the form can't be used to
+    // flood staff inboxes
+    let ip = addr.ip();
+    if let Some(wait) = throttle.retry_after(ip) {
+        warn!("Throttling password reset requests from {}", ip);
+        return render(
+            String::new(),
+            format!("Too many requests. Try again in {}.", describe_wait(wait)),
+        );
+    }
+    throttle.record_failure(ip);
+
+    let username = form.username.trim().to_string();
+    match auth.backend.users.find_one(doc! { "username": &username }).await {
+        Ok(Some(user)) if !user.disabled && resets.recently_requested(user.id).await.unwrap_or(false) => {
+            info!("Not resending password reset to {}: one was sent recently", username);
+        }
+        Ok(Some(user)) if !user.disabled => match resets.create(user.id).await {
+            Ok(token) => {
+                let link = format!("{}/reset-password?token={}", public_base_url(), token);
+                // Send in the background so the response time doesn't reveal whether the account exists
+                tokio::spawn(async move {
+                    let subject = "Reset your Foxy Fabrications admin password";
+                    if let Err(e) = mailer.send(&user.username, subject, reset_email_body(&link)).await {
+                        error!("Failed to send password reset email to {}: {}", user.username, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to create password reset for {}: {}", username, e),
+        },
+        Ok(_) => info!("Password reset requested for unknown or disabled user '{}'", username),
+        Err(e) => error!("Failed to look up user for password reset: {}", e),
+    }
+
+    // Same answer whether or not the account exists
+    render(
+        "If that account exists, we've emailed it a link to reset the password.".into(),
+        String::new(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ResetTokenQuery {
+    token: Option<String>,
+}
+
+fn new_password_page(user_state: UserState, token: String, token_valid: bool, error: String) -> Response {
+    Html(
+        NewPasswordTemplate {
+            token,
+            token_valid,
+            error,
+            user_state,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+pub async fn show_reset_password_form(
+    user_state: UserState,
+    Extension(resets): Extension<PasswordResets>,
+    Query(query): Query<ResetTokenQuery>,
+) -> impl IntoResponse {
+    let token = query.token.unwrap_or_default();
+    let token_valid = match resets.check(&token).await {
+        Ok(user_id) => user_id.is_some(),
+        Err(e) => {
+            error!("Failed to check password reset token: {}", e);
+            false
+        }
+    };
+    new_password_page(user_state, token, token_valid, String::new())
+}
+
+pub async fn handle_reset_password(
+    auth: AppAuthSession,
+    user_state: UserState,
+    Extension(resets): Extension<PasswordResets>,
+    Extension(sessions): Extension<MongoSessionStore>,
+    Form(form): Form<NewPasswordForm>,
+) -> impl IntoResponse {
+    // Check the new password first so a typo doesn't use up the link
+    if let Err(error) = validate_new_password(&form.password, &form.confirm_password) {
+        return new_password_page(user_state, form.token, true, error);
+    }
+
+    let user_id = match resets.redeem(&form.token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return new_password_page(user_state, form.token, false, String::new()),
+        Err(e) => {
+            error!("Failed to redeem password reset token: {}", e);
+            return new_password_page(user_state, form.token, true, "Server error".into());
+        }
+    };
+
+    let password_hash = match hash_password(&form.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return new_password_page(user_state, form.token, false, "Server error".into());
+        }
+    };
+
+    // A reset also lifts any lockout, since it proves control of the mailbox
+    let update = doc! {
+        "$set": { "password_hash": password_hash, "failed_login_attempts": 0 },
+        "$unset": { "locked_until": "" },
+    };
+    match auth.backend.users.update_one(doc! { "_id": user_id }, update).await {
+        Ok(_) => {
+            info!("Password reset via email for user {}", user_id);
+            if let Err(e) = sessions.revoke_all_for_user(user_id, None).await {
+                error!("Failed to remove sessions for {}: {}", user_id, e);
+            }
+            Redirect::to("/login?reset=1").into_response()
+        }
+        Err(e) => {
+            error!("Failed to save reset password for {}: {}", user_id, e);
+            new_password_page(user_state, form.token, false, "Server error".into())
         }
     }
 }
@@ -111,9 +505,47 @@ pub async fn handle_login(
 // —————————————————————————————
 // Logout
 // —————————————————————————————
-pub async fn handle_logout(mut auth: AppAuthSession) -> impl IntoResponse {
+pub async fn handle_logout(
+    mut auth: AppAuthSession,
+    actor: Actor,
+    Extension(audit_log): Extension<AuditLog>,
+) -> impl IntoResponse {
     if auth.logout().await.is_err() {
         return Redirect::to("/").into_response();
     }
+    if !actor.username.is_empty() {
+        audit_log.record(&actor, audit::actions::LOGOUT, None, vec![]).await;
+    }
     Redirect::to("/").into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_redirect_rejects_external_urls() {
+        assert_eq!(get_safe_redirect_url(Some("/orders".into())), "/orders");
+        assert_eq!(get_safe_redirect_url(Some("//evil.example".into())), "/");
+        assert_eq!(get_safe_redirect_url(Some("https://evil.example".into())), "/");
+        assert_eq!(get_safe_redirect_url(None), "/");
+    }
+
+    #[test]
+    fn test_reset_email_contains_link_and_expiry() {
+        let body = reset_email_body("http://localhost:3001/reset-password?token=abc");
+        assert!(body.contains("http://localhost:3001/reset-password?token=abc"));
+        assert!(body.contains("within 60 minutes"));
+    }
+
+    #[test]
+    fn test_pending_login_expires() {
+        let pending = PendingLogin {
+            user_id: ObjectId::new().to_hex(),
+            next: None,
+            started_at: 1_000_000,
+        };
+        assert!(!pending.is_expired(1_000_000 + PENDING_LOGIN_TTL_MS));
+        assert!(pending.is_expired(1_000_001 + PENDING_LOGIN_TTL_MS));
+    }
+}
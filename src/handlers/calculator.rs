@@ -1,15 +1,8 @@
 use askama::Template;
 use axum::response::Html;
 
-use crate::{handlers::auth::AppAuthSession, models::UserState, user_state::extract_user_state};
+use crate::models::{CalculatorTemplate, UserState};
 
-#[derive(Template)]
-#[template(path = "calculator.html")]
-struct CalculatorTemplate {
-    user_state: UserState,
-}
-
-pub async fn show_calculator(auth: AppAuthSession) -> Html<String> {
-    let user_state = extract_user_state(&auth);
+pub async fn show_calculator(user_state: UserState) -> Html<String> {
     Html(CalculatorTemplate { user_state }.render().unwrap())
 }
@@ -0,0 +1,406 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::{Form, Path, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Json, Redirect, Response},
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, IndexModel,
+    bson::{Bson, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use std::collections::HashMap;
+use tracing::error;
+
+use crate::{
+    audit::{self, Actor, AuditLog},
+    models::{
+        CategoriesTemplate, Category, CategoryDisplay, CategoryForm, CategoryFormTemplate, CategoryOperationResponse,
+        CategoryQueryParams, Product, SelectOption, UserState,
+    },
+    session_store::is_duplicate_key,
+};
+
+/// Turn a category name into its slug, e.g. "Enamel Pins & Badges" -> "enamel-pins-badges"
+pub fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Check the form, returning the category's slug
+pub fn validate_category_form(form: &CategoryForm) -> Result<String, String> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err("Category name cannot be empty".to_string());
+    }
+    if name.len() > 100 {
+        return Err("Category name must be less than 100 characters".to_string());
+    }
+    if form.description.len() > 1000 {
+        return Err("Description must be less than 1,000 characters".to_string());
+    }
+    let slug = slugify(name);
+    if slug.is_empty() {
+        return Err("Category name needs at least one letter or number".to_string());
+    }
+    // Reserved by the product list filter for uncategorised products
+    if slug == "none" {
+        return Err("\"None\" can't be used as a category name".to_string());
+    }
+    Ok(slug)
+}
+
+/// Every category, by name. Errors are logged and give an empty list, so
+/// product pages still work without categories.
+pub async fn load_categories(collection: &Collection<Category>) -> Vec<Category> {
+    let result = match collection.find(doc! {}).sort(doc! { "name": 1 }).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    result.unwrap_or_else(|e| {
+        error!("Failed to load categories: {}", e);
+        vec![]
+    })
+}
+
+/// Options for the category <select> on the product forms
+pub fn category_options(categories: &[Category], selected: Option<ObjectId>) -> Vec<SelectOption> {
+    categories
+        .iter()
+        .map(|category| SelectOption {
+            value: category.id.to_hex(),
+            label: category.name.clone(),
+            selected: Some(category.id) == selected,
+        })
+        .collect()
+}
+
+fn convert_to_display(category: &Category, product_count: u64) -> CategoryDisplay {
+    CategoryDisplay {
+        id: category.id.to_hex(),
+        name: category.name.clone(),
+        slug: category.slug.clone(),
+        description: category.description.clone(),
+        product_count,
+    }
+}
+
+fn audit_fields(category: &Category) -> Vec<(&'static str, String)> {
+    vec![
+        ("name", category.name.clone()),
+        ("description", category.description.clone()),
+    ]
+}
+
+/// Number of products in each category
+async fn product_counts(products: &Collection<Product>) -> mongodb::error::Result<HashMap<ObjectId, u64>> {
+    let pipeline = vec![doc! { "$group": { "_id": "$category_id", "count": { "$sum": 1 } } }];
+    let groups: Vec<Document> = products.aggregate(pipeline).await?.try_collect().await?;
+    Ok(groups
+        .into_iter()
+        .filter_map(|group| {
+            let id = group.get_object_id("_id").ok()?;
+            let count = match group.get("count")? {
+                Bson::Int32(n) => *n as u64,
+                Bson::Int64(n) => *n as u64,
+                _ => 0,
+            };
+            Some((id, count))
+        })
+        .collect())
+}
+
+/// List categories with how many products each has
+pub async fn list_categories(
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Extension(products_collection): Extension<Collection<Product>>,
+    Query(params): Query<CategoryQueryParams>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let categories = load_categories(&categories_collection).await;
+    let (counts, error_message) = match product_counts(&products_collection).await {
+        Ok(counts) => (counts, String::new()),
+        Err(e) => (HashMap::new(), format!("Error counting products: {}", e)),
+    };
+
+    let success_message = match params.success.as_deref() {
+        Some("created") => "Category created",
+        Some("updated") => "Category updated",
+        _ => "",
+    };
+
+    let template = CategoriesTemplate {
+        categories: categories
+            .iter()
+            .map(|category| convert_to_display(category, counts.get(&category.id).copied().unwrap_or(0)))
+            .collect(),
+        user_state,
+        success_message: success_message.to_string(),
+        error_message,
+    };
+    Html(template.render().unwrap())
+}
+
+fn render_form(user_state: UserState, category: CategoryDisplay, error_message: String) -> Response {
+    let template = CategoryFormTemplate {
+        user_state,
+        category,
+        error_message,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Unique index on the slug, which imports and the storefront look categories
+/// up by. The check in the forms gives the friendly message; this stops two
+/// saves at once both getting through.
+pub async fn ensure_indexes(collection: &Collection<Category>) -> mongodb::error::Result<()> {
+    let slug_index = IndexModel::builder()
+        .keys(doc! { "slug": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("categories_slug".to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+    collection.create_index(slug_index).await?;
+    Ok(())
+}
+
+/// Is the slug taken by a category other than `except`?
+async fn slug_in_use(
+    collection: &Collection<Category>,
+    slug: &str,
+    except: Option<ObjectId>,
+) -> mongodb::error::Result<bool> {
+    let mut filter = doc! { "slug": slug };
+    if let Some(id) = except {
+        filter.insert("_id", doc! { "$ne": id });
+    }
+    Ok(collection.find_one(filter).await?.is_some())
+}
+
+/// Show create form for a new category
+pub async fn show_create_form(user_state: UserState) -> impl IntoResponse {
+    render_form(user_state, CategoryDisplay::default(), String::new())
+}
+
+/// Handle category creation
+pub async fn create_category(
+    Extension(collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    actor: Actor,
+    user_state: UserState,
+    Form(form): Form<CategoryForm>,
+) -> impl IntoResponse {
+    let entered = CategoryDisplay {
+        name: form.name.clone(),
+        description: form.description.clone(),
+        ..CategoryDisplay::default()
+    };
+    let slug = match validate_category_form(&form) {
+        Ok(slug) => slug,
+        Err(error_message) => return render_form(user_state, entered, error_message),
+    };
+    match slug_in_use(&collection, &slug, None).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error_message = format!("There's already a category called {}", form.name.trim());
+            return render_form(user_state, entered, error_message);
+        }
+        Err(e) => return render_form(user_state, entered, format!("Database error: {}", e)),
+    }
+
+    let category = Category {
+        id: ObjectId::new(),
+        name: form.name.trim().to_string(),
+        slug,
+        description: form.description.trim().to_string(),
+    };
+    match collection.insert_one(&category).await {
+        Ok(_) => {
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::CATEGORY_CREATED,
+                    Some(category.id.to_hex()),
+                    audit::diff(&[], &audit_fields(&category)),
+                )
+                .await;
+            Redirect::to("/categories?success=created").into_response()
+        }
+        Err(e) if is_duplicate_key(&e) => {
+            render_form(user_state, entered, "A category with that name already exists".to_string())
+        }
+        Err(e) => render_form(user_state, entered, format!("Database error: {}", e)),
+    }
+}
+
+/// Show edit form for a specific category
+pub async fn show_edit_form(
+    Path(id): Path<String>,
+    Extension(collection): Extension<Collection<Category>>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid category ID").into_response(),
+    };
+    match collection.find_one(doc! { "_id": obj_id }).await {
+        Ok(Some(category)) => render_form(user_state, convert_to_display(&category, 0), String::new()),
+        Ok(None) => (StatusCode::NOT_FOUND, "Category not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+}
+
+/// Handle a rename or description change
+pub async fn update_category(
+    Path(id): Path<String>,
+    Extension(collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    actor: Actor,
+    user_state: UserState,
+    Form(form): Form<CategoryForm>,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid category ID").into_response(),
+    };
+    let entered = CategoryDisplay {
+        id: id.clone(),
+        name: form.name.clone(),
+        description: form.description.clone(),
+        ..CategoryDisplay::default()
+    };
+    let slug = match validate_category_form(&form) {
+        Ok(slug) => slug,
+        Err(error_message) => return render_form(user_state, entered, error_message),
+    };
+    match slug_in_use(&collection, &slug, Some(obj_id)).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error_message = format!("There's already a category called {}", form.name.trim());
+            return render_form(user_state, entered, error_message);
+        }
+        Err(e) => return render_form(user_state, entered, format!("Database error: {}", e)),
+    }
+
+    let name = form.name.trim().to_string();
+    let description = form.description.trim().to_string();
+    let update_doc = doc! {
+        "$set": { "name": &name, "slug": &slug, "description": &description }
+    };
+    match collection.find_one_and_update(doc! { "_id": obj_id }, update_doc).await {
+        Ok(None) => (StatusCode::NOT_FOUND, "Category not found").into_response(),
+        Ok(Some(before)) => {
+            let after = Category {
+                name,
+                slug,
+                description,
+                ..before.clone()
+            };
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::CATEGORY_UPDATED,
+                    Some(id),
+                    audit::diff(&audit_fields(&before), &audit_fields(&after)),
+                )
+                .await;
+            Redirect::to("/categories?success=updated").into_response()
+        }
+        Err(e) if is_duplicate_key(&e) => {
+            render_form(user_state, entered, "A category with that name already exists".to_string())
+        }
+        Err(e) => render_form(user_state, entered, format!("Database error: {}", e)),
+    }
+}
+
+/// Delete a category; its products become uncategorised
+pub async fn delete_category(
+    Path(id): Path<String>,
+    Extension(collection): Extension<Collection<Category>>,
+    Extension(products_collection): Extension<Collection<Product>>,
+    Extension(audit_log): Extension<AuditLog>,
+    actor: Actor,
+) -> impl IntoResponse {
+    let failure = |message: String| {
+        Json(CategoryOperationResponse {
+            success: false,
+            message,
+            category_id: None,
+        })
+    };
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return failure("Invalid category ID".to_string()),
+    };
+
+    let deleted = match collection.find_one_and_delete(doc! { "_id": obj_id }).await {
+        Ok(Some(deleted)) => deleted,
+        Ok(None) => return failure("Category not found".to_string()),
+        Err(e) => return failure(format!("Database error: {}", e)),
+    };
+    audit_log
+        .record(
+            &actor,
+            audit::actions::CATEGORY_DELETED,
+            Some(id.clone()),
+            audit::diff(&audit_fields(&deleted), &[]),
+        )
+        .await;
+
+    let message = match products_collection
+        .update_many(doc! { "category_id": obj_id }, doc! { "$unset": { "category_id": "" } })
+        .await
+    {
+        Ok(result) if result.modified_count > 0 => format!(
+            "Category deleted; {} product(s) are now uncategorised",
+            result.modified_count
+        ),
+        Ok(_) => "Category deleted".to_string(),
+        Err(e) => {
+            error!("Failed to uncategorise products of deleted category {}: {}", id, e);
+            "Category deleted, but its products couldn't be updated".to_string()
+        }
+    };
+    Json(CategoryOperationResponse {
+        success: true,
+        message,
+        category_id: Some(id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(name: &str) -> CategoryForm {
+        CategoryForm {
+            name: name.to_string(),
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Enamel Pins & Badges"), "enamel-pins-badges");
+        assert_eq!(slugify("  Plushies  "), "plushies");
+        assert_eq!(slugify("3D Prints"), "3d-prints");
+        assert_eq!(slugify("!!!"), "");
+    }
+
+    #[test]
+    fn test_validate_category_form() {
+        assert_eq!(validate_category_form(&form(" Keyrings ")).unwrap(), "keyrings");
+        assert!(validate_category_form(&form("")).is_err());
+        assert!(validate_category_form(&form("***")).is_err());
+        assert!(validate_category_form(&form("None")).is_err());
+        assert!(validate_category_form(&form(&"a".repeat(101))).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use askama::Template;
+use axum::{
+    Extension,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use mongodb::Collection;
+
+use crate::{
+    auth::permissions as perms,
+    low_stock,
+    models::{DashboardTemplate, LowStockDisplay, Product, UserState},
+};
+
+/// Low-stock products listed on the dashboard; the rest are a click away
+const LOW_STOCK_SHOWN: usize = 10;
+
+/// Admin dashboard homepage
+pub async fn show_dashboard(Extension(collection): Extension<Collection<Product>>, user_state: UserState) -> Response {
+    // Only product staff have anything on the dashboard yet; send everyone
+    // else to the first section their role can see
+    if !user_state.can(perms::PRODUCTS_VIEW) {
+        let landing = if user_state.can(perms::ORDERS_VIEW) {
+            "/orders"
+        } else {
+            "/quotes"
+        };
+        return Redirect::to(landing).into_response();
+    }
+
+    let (products, error_message) = match low_stock::find_low_stock(&collection).await {
+        Ok(products) => (products, String::new()),
+        Err(e) => (vec![], format!("Database error checking stock: {}", e)),
+    };
+    let template = DashboardTemplate {
+        user_state,
+        low_stock_total: products.len(),
+        low_stock: products
+            .into_iter()
+            .take(LOW_STOCK_SHOWN)
+            .map(|product| LowStockDisplay {
+                id: product.id.to_hex(),
+                reorder_threshold: product.reorder_threshold.unwrap_or_default(),
+                name: product.name,
+                quantity: product.quantity,
+            })
+            .collect(),
+        error_message,
+    };
+    Html(template.render().unwrap()).into_response()
+}
@@ -2,49 +2,222 @@ use askama::Template;
 use axum::{
     Extension,
     extract::{Form, Query},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json, Redirect},
+    response::{Html, IntoResponse, Json},
 };
 use chrono::{DateTime, Utc};
 use futures_util::TryStreamExt;
 use mongodb::{
     Collection,
-    bson::{doc, oid::ObjectId},
+    bson::{self, Bson, Document, doc, oid::ObjectId},
 };
+use serde::Deserialize;
 use tracing::{info, error};
 
 use crate::{
-    handlers::auth::AppAuthSession,
+    audit::{self, Actor, AuditLog, FieldChange},
     models::{
-        Order, OrderDisplay, OrderOperationResponse, OrderProcessingTemplate, OrderQueryParams,
-        PaginationInfo, ShippingAddressDisplay, UpdateOrderStatusForm,
+        Order, OrderDisplay, OrderItem, OrderOperationResponse, OrderProcessingTemplate, OrderQueryParams,
+        PaginationInfo, Product, ShippingAddressDisplay, UpdateOrderStatusForm, UserState,
     },
-    user_state::extract_user_state,
+    money::Currency,
+    stock::{MovementKind, StockChange, StockError, StockLedger, StockMovement, plan_change},
 };
 
 /// Default page size for order listing
 const DEFAULT_PAGE_SIZE: u32 = 10;
 const MAX_PAGE_SIZE: u32 = 100;
 
+/// Statuses of an order that's been paid for
+const PAID_STATUSES: [&str; 4] = ["paid", "processing", "shipped", "completed"];
+
+/// What a status change does to an order's stock
+#[derive(Debug, PartialEq)]
+pub enum StockEffect {
+    Unchanged,
+    Take,
+    Restore,
+}
+
+/// Stock is taken once, when an unpaid or cancelled order is marked paid (or
+/// moved straight past paid), and put back if it's cancelled after that.
+/// Orders already paid before stock was tracked have no `stock_deducted`
+/// flag, so moving them between paid statuses leaves stock alone.
+pub fn stock_effect(previous_status: &str, new_status: &str, stock_deducted: bool) -> StockEffect {
+    if PAID_STATUSES.contains(&new_status) && !PAID_STATUSES.contains(&previous_status) && !stock_deducted {
+        StockEffect::Take
+    } else if new_status == "cancelled" && stock_deducted {
+        StockEffect::Restore
+    } else {
+        StockEffect::Unchanged
+    }
+}
+
+/// The parts of an order stock changes need, read from the raw document
+#[derive(Debug, Deserialize)]
+struct OrderStock {
+    #[serde(default)]
+    order_reference: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    items: Vec<StockLine>,
+    #[serde(default)]
+    stock_deducted: bool,
+}
+
+/// How many of a product (or one of its variants) an order takes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StockLine {
+    pub product_id: String,
+    #[serde(default, rename = "name")]
+    pub product_name: String,
+    #[serde(default)]
+    pub sku: Option<String>,
+    pub quantity: i32,
+}
+
+impl From<&OrderItem> for StockLine {
+    fn from(item: &OrderItem) -> Self {
+        StockLine {
+            product_id: item.product_id.clone(),
+            product_name: item.product_name.clone(),
+            sku: item.sku.clone(),
+            quantity: item.quantity,
+        }
+    }
+}
+
+impl StockLine {
+    fn describe(&self) -> String {
+        match &self.sku {
+            Some(sku) => format!("{} ({})", self.product_name, sku),
+            None => self.product_name.clone(),
+        }
+    }
+}
+
+/// An order's lines with the same product and variant added together
+pub fn combine_lines(lines: Vec<StockLine>) -> Vec<StockLine> {
+    let mut combined: Vec<StockLine> = Vec::new();
+    for line in lines.into_iter().filter(|line| line.quantity > 0) {
+        match combined
+            .iter_mut()
+            .find(|existing| existing.product_id == line.product_id && existing.sku == line.sku)
+        {
+            Some(existing) => existing.quantity += line.quantity,
+            None => combined.push(line),
+        }
+    }
+    combined
+}
+
+/// Lines of an unpaid order that would take more stock than there is.
+/// Products that no longer exist aren't tracked, so aren't warned about.
+pub fn stock_warnings(items: &[OrderItem], products: &[Product]) -> Vec<String> {
+    let lines = combine_lines(items.iter().map(StockLine::from).collect());
+    let mut warnings = Vec::new();
+    for line in lines {
+        let Some(product) = products.iter().find(|product| product.id.to_hex() == line.product_id) else {
+            continue;
+        };
+        match plan_change(product, line.sku.as_deref(), StockChange::By(-line.quantity)) {
+            Ok(_) => {}
+            Err(StockError::Insufficient { available }) => warnings.push(format!(
+                "{}: {} ordered, {} in stock",
+                line.describe(),
+                line.quantity,
+                available
+            )),
+            Err(e) => warnings.push(format!("{}: {}", line.describe(), e)),
+        }
+    }
+    warnings
+}
+
+/// Take an order's lines out of stock through the ledger. If one can't be
+/// taken, those already taken are put back, so the order's stock moves all
+/// at once or not at all. Returns notes about lines that weren't tracked.
+async fn take_order_stock(
+    stock_ledger: &StockLedger,
+    actor: &Actor,
+    order_reference: &str,
+    lines: &[StockLine],
+) -> Result<Vec<String>, String> {
+    let mut notes = Vec::new();
+    let mut taken: Vec<StockMovement> = Vec::new();
+    for line in lines {
+        let outcome = match ObjectId::parse_str(&line.product_id) {
+            Ok(product_id) => {
+                let movement = StockMovement::new(actor, product_id, MovementKind::Sale).with_order_reference(order_reference);
+                stock_ledger
+                    .apply(line.sku.as_deref(), StockChange::By(-line.quantity), movement)
+                    .await
+            }
+            Err(_) => Err(StockError::ProductNotFound),
+        };
+        match outcome {
+            Ok(movement) => taken.push(movement),
+            Err(StockError::ProductNotFound) => {
+                notes.push(format!("{} is no longer a product, so no stock was taken for it", line.describe()));
+            }
+            Err(e) => {
+                for movement in taken {
+                    let undo = StockMovement::new(actor, movement.product_id, MovementKind::Adjustment)
+                        .with_reason("Order couldn't be marked paid")
+                        .with_order_reference(order_reference);
+                    if let Err(undo_error) = stock_ledger
+                        .apply(movement.sku.as_deref(), StockChange::By(-movement.change), undo)
+                        .await
+                    {
+                        error!(
+                            "Failed to put back stock for product {} from order {}: {}",
+                            movement.product_id, order_reference, undo_error
+                        );
+                    }
+                }
+                return Err(format!("{}: {}", line.describe(), e));
+            }
+        }
+    }
+    Ok(notes)
+}
+
+/// Put a cancelled order's lines back in stock. Returns notes about lines
+/// that couldn't be put back.
+async fn restore_order_stock(
+    stock_ledger: &StockLedger,
+    actor: &Actor,
+    order_reference: &str,
+    lines: &[StockLine],
+) -> Vec<String> {
+    let mut notes = Vec::new();
+    for line in lines {
+        let outcome = match ObjectId::parse_str(&line.product_id) {
+            Ok(product_id) => {
+                let movement = StockMovement::new(actor, product_id, MovementKind::Return)
+                    .with_reason("Order cancelled")
+                    .with_order_reference(order_reference);
+                stock_ledger
+                    .apply(line.sku.as_deref(), StockChange::By(line.quantity), movement)
+                    .await
+            }
+            Err(_) => Err(StockError::ProductNotFound),
+        };
+        if let Err(e) = outcome {
+            notes.push(format!("{} wasn't put back in stock: {}", line.describe(), e));
+        }
+    }
+    notes
+}
+
 /// List orders with pagination and filtering
 pub async fn list_orders(
     Extension(orders_collection): Extension<Collection<Order>>,
+    Extension(products_collection): Extension<Collection<Product>>,
     Extension(database): Extension<mongodb::Database>,
     Query(params): Query<OrderQueryParams>,
-    auth: AppAuthSession,
+    user_state: UserState,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
-    }
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
-    }
-
     let show_completed = params.show_completed.as_deref().unwrap_or("false") == "true";
 
     let page = params.page.unwrap_or(1).max(1);
@@ -163,7 +336,45 @@ let total_count = all_orders.len() as u64;
         .take(page_size_usize)
         .collect();
 
-    let order_displays: Vec<OrderDisplay> = paginated_orders.into_iter().map(convert_to_display).collect();
+    // Warn about unpaid orders there isn't enough stock for
+    let unpaid: Vec<&Order> = paginated_orders
+        .iter()
+        .filter(|order| {
+            order.status != "cancelled" && stock_effect(&order.status, "paid", order.stock_deducted) == StockEffect::Take
+        })
+        .collect();
+    let unpaid_ids: Vec<ObjectId> = unpaid.iter().map(|order| order.id).collect();
+    let product_ids: Vec<ObjectId> = unpaid
+        .iter()
+        .flat_map(|order| order.items.iter())
+        .filter_map(|item| ObjectId::parse_str(&item.product_id).ok())
+        .collect();
+    let products: Vec<Product> = if product_ids.is_empty() {
+        vec![]
+    } else {
+        match products_collection.find(doc! { "_id": { "$in": product_ids } }).await {
+            Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+            Err(e) => {
+                error!("Failed to load products for stock warnings: {}", e);
+                vec![]
+            }
+        }
+    };
+
+    let order_displays: Vec<OrderDisplay> = paginated_orders
+        .into_iter()
+        .map(|order| {
+            let warnings = if unpaid_ids.contains(&order.id) {
+                stock_warnings(&order.items, &products)
+            } else {
+                vec![]
+            };
+            OrderDisplay {
+                stock_warnings: warnings,
+                ..convert_to_display(order)
+            }
+        })
+        .collect();
 
     let pagination = create_pagination_info(page, page_size, total_count);
 
@@ -179,27 +390,18 @@ let total_count = all_orders.len() as u64;
     Html(template.render().unwrap()).into_response()
 }
 
-/// Update order status
+/// Update order status, taking the order's items out of stock when it's
+/// paid and putting them back if it's cancelled
 pub async fn update_order_status(
     Extension(orders_collection): Extension<Collection<Order>>,
     Extension(database): Extension<mongodb::Database>,
-    auth: AppAuthSession,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    actor: Actor,
     Form(form): Form<UpdateOrderStatusForm>,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return Json(OrderOperationResponse {
-            success: false,
-            message: "Access denied".to_string(),
-            order_id: None,
-        })
-        .into_response();
-    }
-
     // Validate status
-    let valid_statuses = vec!["paid", "processing", "shipped", "completed", "cancelled"];
+    let valid_statuses = ["paid", "processing", "shipped", "completed", "cancelled"];
     if !valid_statuses.contains(&form.status.as_str()) {
         return Json(OrderOperationResponse {
             success: false,
@@ -222,9 +424,76 @@ pub async fn update_order_status(
         }
     };
 
-    // Get reference to completed orders collection
-    let completed_orders_collection = database.collection::<Order>("completed_orders");
-    
+    let failure = |message: String| {
+        Json(OrderOperationResponse {
+            success: false,
+            message,
+            order_id: None,
+        })
+        .into_response()
+    };
+
+    // Raw documents, so the previous status can be read without the whole
+    // order having to deserialize
+    let orders_collection = orders_collection.clone_with_type::<Document>();
+    let completed_orders_collection = database.collection::<Document>("completed_orders");
+
+    // Find which collection the order is in
+    let filter = doc! { "_id": obj_id };
+    let (collection, current) = match orders_collection.find_one(filter.clone()).await {
+        Ok(Some(current)) => (orders_collection, current),
+        Ok(None) => match completed_orders_collection.find_one(filter.clone()).await {
+            Ok(Some(current)) => (completed_orders_collection, current),
+            Ok(None) => return failure("Order not found in either collection".to_string()),
+            Err(e) => return failure(format!("Database error reading completed order: {}", e)),
+        },
+        Err(e) => return failure(format!("Database error: {}", e)),
+    };
+    let previous_status = current.get("status").cloned().unwrap_or(Bson::Null);
+    let order: OrderStock = match bson::from_document(current) {
+        Ok(order) => order,
+        Err(e) => return failure(format!("Couldn't read the order's items: {}", e)),
+    };
+    let lines = combine_lines(order.items);
+    let changed = || failure("The order changed while you were updating it; reload and try again".to_string());
+
+    // Claim the stock change on the order first, guarded on the status it was
+    // read with, so two people changing its status at once can't both act on it
+    let mut notes = Vec::new();
+    let effect = stock_effect(&order.status, &form.status, order.stock_deducted);
+    match effect {
+        StockEffect::Take => {
+            let claim = doc! { "_id": obj_id, "status": previous_status.clone(), "stock_deducted": { "$ne": true } };
+            match collection.update_one(claim, doc! { "$set": { "stock_deducted": true } }).await {
+                Ok(claimed) if claimed.modified_count > 0 => {
+                    match take_order_stock(&stock_ledger, &actor, &order.order_reference, &lines).await {
+                        Ok(untracked) => notes = untracked,
+                        Err(message) => {
+                            let release = doc! { "$set": { "stock_deducted": false } };
+                            if let Err(e) = collection.update_one(filter.clone(), release).await {
+                                error!("Failed to clear stock_deducted on order {}: {}", form.order_id, e);
+                            }
+                            return failure(format!("Couldn't take stock to mark the order {}: {}", form.status, message));
+                        }
+                    }
+                }
+                Ok(_) => return changed(),
+                Err(e) => return failure(format!("Database error: {}", e)),
+            }
+        }
+        StockEffect::Restore => {
+            let claim = doc! { "_id": obj_id, "status": previous_status.clone(), "stock_deducted": true };
+            match collection.update_one(claim, doc! { "$set": { "stock_deducted": false } }).await {
+                Ok(claimed) if claimed.modified_count > 0 => {
+                    notes = restore_order_stock(&stock_ledger, &actor, &order.order_reference, &lines).await;
+                }
+                Ok(_) => return changed(),
+                Err(e) => return failure(format!("Database error: {}", e)),
+            }
+        }
+        StockEffect::Unchanged => {}
+    }
+
     // Update document
     let update_doc = doc! {
         "$set": {
@@ -232,55 +501,52 @@ pub async fn update_order_status(
             "updated_at": Utc::now().to_rfc3339(),
         }
     };
-
-    // Try to update in main orders collection first
-    let filter = doc! { "_id": obj_id };
-    match orders_collection.update_one(filter.clone(), update_doc.clone()).await {
-        Ok(result) => {
-            if result.matched_count > 0 {
-                // Successfully updated in main collection
-                Json(OrderOperationResponse {
-                    success: true,
-                    message: format!("Order status updated to {}", form.status),
-                    order_id: Some(form.order_id.clone()),
-                })
-                .into_response()
-            } else {
-                // Not found in main collection, try completed orders collection
-                match completed_orders_collection.update_one(filter, update_doc).await {
-                    Ok(result) => {
-                        if result.matched_count > 0 {
-                            Json(OrderOperationResponse {
-                                success: true,
-                                message: format!("Order status updated to {}", form.status),
-                                order_id: Some(form.order_id.clone()),
-                            })
-                            .into_response()
-                        } else {
-                            Json(OrderOperationResponse {
-                                success: false,
-                                message: "Order not found in either collection".to_string(),
-                                order_id: None,
-                            })
-                            .into_response()
-                        }
-                    }
-                    Err(e) => Json(OrderOperationResponse {
-                        success: false,
-                        message: format!("Database error updating completed order: {}", e),
-                        order_id: None,
-                    })
-                    .into_response(),
-                }
+    // Only if nobody else has changed the order's status or stock since it was read
+    let stock_deducted = match effect {
+        StockEffect::Take => true,
+        StockEffect::Restore => false,
+        StockEffect::Unchanged => order.stock_deducted,
+    };
+    let unchanged = doc! {
+        "_id": obj_id,
+        "status": previous_status,
+        "stock_deducted": if stock_deducted { Bson::Boolean(true) } else { Bson::Document(doc! { "$ne": true }) },
+    };
+    let previous = match collection.find_one_and_update(unchanged, update_doc).await {
+        Ok(Some(previous)) => previous,
+        Ok(None) => {
+            if effect != StockEffect::Unchanged {
+                error!("Order {} changed after its stock was updated; check its stock", form.order_id);
             }
+            return changed();
         }
-        Err(e) => Json(OrderOperationResponse {
-            success: false,
-            message: format!("Database error: {}", e),
-            order_id: None,
-        })
-        .into_response(),
+        Err(e) => return failure(format!("Database error: {}", e)),
+    };
+
+    audit_log
+        .record(
+            &actor,
+            audit::actions::ORDER_STATUS_CHANGED,
+            Some(form.order_id.clone()),
+            vec![FieldChange {
+                field: "status".to_string(),
+                before: previous.get_str("status").ok().map(str::to_string),
+                after: Some(form.status.clone()),
+            }],
+        )
+        .await;
+
+    let mut message = format!("Order status updated to {}", form.status);
+    for note in notes {
+        message.push_str(". ");
+        message.push_str(&note);
     }
+    Json(OrderOperationResponse {
+        success: true,
+        message,
+        order_id: Some(form.order_id.clone()),
+    })
+    .into_response()
 }
 
 /// Convert Order to OrderDisplay for template rendering
@@ -299,6 +565,20 @@ pub fn convert_to_display(order: Order) -> OrderDisplay {
         Err(_) => order.created_at.clone(),
     };
 
+    // Amounts stored as plain numbers decode as pounds; the order records the
+    // currency they were really taken in
+    let currency = Currency::parse(&order.currency).unwrap_or(order.total.currency);
+    let items = order
+        .items
+        .into_iter()
+        .map(|item| OrderItem {
+            price: item.price.with_currency(currency),
+            line_total: item.line_total.with_currency(currency),
+            ..item
+        })
+        .collect();
+    let total = order.total.with_currency(currency);
+
     OrderDisplay {
         id: order.id.to_hex(),
         order_reference: order.order_reference,
@@ -311,17 +591,18 @@ pub fn convert_to_display(order: Order) -> OrderDisplay {
             postcode: order.shipping_address.postcode,
             country: order.shipping_address.country,
         },
-        items: order.items,
-        subtotal: order.subtotal,
-        shipping_cost: order.shipping_cost,
-        total: order.total,
+        items,
+        subtotal: order.subtotal.with_currency(currency),
+        shipping_cost: order.shipping_cost.with_currency(currency),
+        total,
         currency: order.currency,
         status: order.status,
         created_at: order.created_at,
         updated_at: order.updated_at,
-        formatted_total: format!("£{:.2}", order.total),
+        formatted_total: total.to_string(),
         formatted_created_at,
         status_class: status_class.to_string(),
+        stock_warnings: vec![],
     }
 }
 
@@ -360,6 +641,7 @@ mod tests {
     use super::*;
     use mongodb::bson::oid::ObjectId;
     use crate::models::{Order, ShippingAddress, OrderItem};
+    use crate::money::{Currency, Money};
 
     fn create_test_order() -> Order {
         Order {
@@ -376,20 +658,20 @@ mod tests {
             },
             items: vec![OrderItem {
                 product_id: ObjectId::new().to_hex(),
-                name: "Test Product".to_string(),
-                price: 19.99,
+                product_name: "Test Product".to_string(),
+                price: Money::new(1999, Currency::Gbp),
                 quantity: 2,
-                line_total: 39.98,
+                line_total: Money::new(3998, Currency::Gbp),
+                sku: None,
             }],
-            subtotal: 39.98,
-            shipping_cost: 5.00,
-            total: 44.98,
+            subtotal: Money::new(3998, Currency::Gbp),
+            shipping_cost: Money::new(500, Currency::Gbp),
+            total: Money::new(4498, Currency::Gbp),
             currency: "GBP".to_string(),
-            sumup_checkout_id: Some("checkout_123".to_string()),
-            sumup_transaction_id: None,
             status: "paid".to_string(),
             created_at: "2025-01-01T12:00:00Z".to_string(),
             updated_at: "2025-01-01T12:00:00Z".to_string(),
+            stock_deducted: true,
         }
     }
 
@@ -407,6 +689,18 @@ mod tests {
         assert_eq!(display.formatted_total, "£44.98");
     }
 
+    #[test]
+    fn test_convert_to_display_uses_the_order_currency() {
+        // A euro order saved before amounts recorded their currency
+        let mut order = create_test_order();
+        order.currency = "EUR".to_string();
+        let display = convert_to_display(order);
+
+        assert_eq!(display.formatted_total, "€44.98");
+        assert_eq!(display.total, Money::new(4498, Currency::Eur));
+        assert_eq!(display.items[0].line_total.currency, Currency::Eur);
+    }
+
     #[test]
     fn test_convert_to_display_shipping_address_with_line2() {
         let order = create_test_order();
@@ -468,6 +762,53 @@ mod tests {
         assert_eq!(display.formatted_created_at, "invalid-date");
     }
 
+    #[test]
+    fn test_stock_effect() {
+        assert_eq!(stock_effect("pending", "paid", false), StockEffect::Take);
+        assert_eq!(stock_effect("failed", "shipped", false), StockEffect::Take);
+        assert_eq!(stock_effect("cancelled", "paid", false), StockEffect::Take);
+        assert_eq!(stock_effect("pending", "paid", true), StockEffect::Unchanged);
+        assert_eq!(stock_effect("paid", "cancelled", true), StockEffect::Restore);
+        assert_eq!(stock_effect("pending", "cancelled", false), StockEffect::Unchanged);
+    }
+
+    #[test]
+    fn test_stock_effect_on_legacy_paid_orders() {
+        // Paid before stock was tracked, so there's no stock_deducted flag
+        let order: OrderStock = bson::from_document(doc! { "order_reference": "FF-1001", "status": "shipped" }).unwrap();
+        assert!(!order.stock_deducted);
+        assert_eq!(stock_effect(&order.status, "completed", order.stock_deducted), StockEffect::Unchanged);
+        assert_eq!(stock_effect(&order.status, "paid", order.stock_deducted), StockEffect::Unchanged);
+        assert_eq!(stock_effect(&order.status, "cancelled", order.stock_deducted), StockEffect::Unchanged);
+
+        // Items that can't be read are an error rather than an empty order
+        assert!(bson::from_document::<OrderStock>(doc! { "status": "pending", "items": "FOX-1" }).is_err());
+    }
+
+    #[test]
+    fn test_stock_warnings_combine_lines() {
+        let product = Product {
+            name: "Fox Badge".to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 3,
+            ..Default::default()
+        };
+        let mut order = create_test_order();
+        order.items[0].product_id = product.id.to_hex();
+        order.items[0].product_name = product.name.clone();
+        assert!(stock_warnings(&order.items, std::slice::from_ref(&product)).is_empty());
+
+        // Two lines of the same product need 4 between them
+        order.items.push(order.items[0].clone());
+        assert_eq!(
+            stock_warnings(&order.items, std::slice::from_ref(&product)),
+            vec!["Fox Badge: 4 ordered, 3 in stock"]
+        );
+
+        // Products that have gone aren't tracked
+        assert!(stock_warnings(&order.items, &[]).is_empty());
+    }
+
     #[test]
     fn test_create_pagination_info_basic() {
         let pagination = create_pagination_info(2, 10, 50);
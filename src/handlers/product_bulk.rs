@@ -0,0 +1,498 @@
+use axum::{Extension, extract::Json};
+use mongodb::{
+    Collection,
+    bson::{Document, doc, oid::ObjectId},
+    error::ErrorKind,
+    options::{DeleteOneModel, UpdateOneModel, WriteModel},
+    results::VerboseBulkWriteResult,
+};
+
+use crate::{
+    audit::{self, Actor, AuditLog},
+    handlers::{
+        categories::load_categories,
+        product_management::{
+            MAX_PRICE_MINOR_UNITS, audit_fields, delete_product_images, load_stored_products, unchanged_filter,
+        },
+    },
+    models::{BulkItemResult, BulkProductRequest, BulkProductResponse, Category, Product},
+    money::{Currency, Money},
+    stock::{MAX_STOCK, MovementKind, StockLedger},
+    storage::ImageStores,
+};
+
+/// Most products one bulk action can touch
+pub const MAX_BULK_PRODUCTS: usize = 500;
+
+/// A price change applied to each selected product
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PriceAdjustment {
+    /// Added to the price; negative to lower it. Only applies to products
+    /// priced in the same currency.
+    Amount(Money),
+    /// Hundredths of a percent, e.g. 1000 for 10%
+    Percent(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BulkAction {
+    SetQuantity(i32),
+    AdjustPrice(PriceAdjustment),
+    SetAdoptable(bool),
+    SetCategory(Option<ObjectId>),
+    Delete,
+}
+
+/// A price change typed as an amount, in the currency of its symbol: "£2",
+/// "-€1.50". Without a symbol it's in the shop's default currency.
+pub fn parse_amount(input: &str) -> Option<Money> {
+    let unsigned = input.strip_prefix('-').unwrap_or(input);
+    let currency = [Currency::Gbp, Currency::Eur, Currency::Usd]
+        .into_iter()
+        .find(|currency| unsigned.starts_with(currency.symbol()))
+        .unwrap_or_default();
+    Money::parse(input, currency).ok()
+}
+
+/// A percentage with up to two decimal places, in hundredths of a percent:
+/// "10" is 1000, "-2.5" is -250
+pub fn parse_percent(input: &str) -> Option<i64> {
+    let (negative, unsigned) = match input.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, input.strip_prefix('+').unwrap_or(input)),
+    };
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    let digits = |part: &str| !part.is_empty() && part.len() <= 6 && part.chars().all(|c| c.is_ascii_digit());
+    if !digits(whole) || !(fraction.is_empty() || digits(fraction)) || fraction.len() > 2 {
+        return None;
+    }
+    let hundredths = whole.parse::<i64>().ok()? * 100 + format!("{:0<2}", fraction).parse::<i64>().ok()?;
+    Some(if negative { -hundredths } else { hundredths })
+}
+
+impl BulkAction {
+    /// Read the action chosen on the product list and its value
+    pub fn parse(action: &str, value: &str, categories: &[Category]) -> Result<BulkAction, String> {
+        let value = value.trim();
+        match action {
+            "set_quantity" => value
+                .parse::<i32>()
+                .ok()
+                .filter(|quantity| (0..=MAX_STOCK).contains(quantity))
+                .map(BulkAction::SetQuantity)
+                .ok_or_else(|| "Quantity must be a whole number from 0 to 999,999".to_string()),
+            "adjust_price" => {
+                let invalid = || "Enter a price change such as 1.50, -2 or 10%".to_string();
+                let adjustment = match value.strip_suffix('%') {
+                    Some(percent) => {
+                        let hundredths = parse_percent(percent.trim()).ok_or_else(invalid)?;
+                        if hundredths <= -10000 {
+                            return Err("Prices can't be lowered by 100% or more".to_string());
+                        }
+                        PriceAdjustment::Percent(hundredths)
+                    }
+                    None => PriceAdjustment::Amount(parse_amount(value).ok_or_else(invalid)?),
+                };
+                Ok(BulkAction::AdjustPrice(adjustment))
+            }
+            "set_adoptable" => match value {
+                "true" => Ok(BulkAction::SetAdoptable(true)),
+                "false" => Ok(BulkAction::SetAdoptable(false)),
+                _ => Err("Choose adoptable or product".to_string()),
+            },
+            "set_category" => match value {
+                "" => Ok(BulkAction::SetCategory(None)),
+                id => ObjectId::parse_str(id)
+                    .ok()
+                    .filter(|id| categories.iter().any(|category| category.id == *id))
+                    .map(|id| BulkAction::SetCategory(Some(id)))
+                    .ok_or_else(|| "Please choose a category from the list".to_string()),
+            },
+            "delete" => Ok(BulkAction::Delete),
+            _ => Err("Choose a bulk action".to_string()),
+        }
+    }
+
+    /// The product after this action, or why it can't be applied to it.
+    /// Not used for deletes.
+    pub fn apply(&self, product: &Product) -> Result<Product, String> {
+        let mut after = product.clone();
+        match *self {
+            BulkAction::SetQuantity(quantity) => {
+                if !product.variants.is_empty() {
+                    return Err("Stock is set per variant, on its edit page".to_string());
+                }
+                after.quantity = quantity;
+            }
+            BulkAction::AdjustPrice(adjustment) => {
+                let price = product.price;
+                let change = match adjustment {
+                    PriceAdjustment::Amount(amount) if amount.currency != price.currency => {
+                        return Err(format!("Priced in a different currency ({})", price.currency.code()));
+                    }
+                    PriceAdjustment::Amount(amount) => amount.minor_units,
+                    PriceAdjustment::Percent(hundredths) => {
+                        // Rounded to the nearest penny, halves away from zero
+                        let scaled = price.minor_units as i128 * hundredths as i128;
+                        ((scaled + scaled.signum() * 5000) / 10000) as i64
+                    }
+                };
+                let in_range = |money: Option<Money>| money.is_some_and(|money| (0..=MAX_PRICE_MINOR_UNITS).contains(&money.minor_units));
+                let new_price = price.checked_add(Money::new(change, price.currency));
+                if !in_range(new_price) {
+                    return Err(format!(
+                        "The new price must be between {} and {}",
+                        Money::zero(price.currency),
+                        Money::new(MAX_PRICE_MINOR_UNITS, price.currency)
+                    ));
+                }
+                let new_price = new_price.unwrap_or(price);
+                if !product.variants.iter().all(|variant| in_range(new_price.checked_add(variant.price_delta))) {
+                    return Err("The new price would put a variant out of range".to_string());
+                }
+                after.price = new_price;
+            }
+            BulkAction::SetAdoptable(adoptable) => after.adoptable = adoptable,
+            BulkAction::SetCategory(category_id) => after.category_id = category_id,
+            BulkAction::Delete => return Err("Deleting doesn't change a product".to_string()),
+        }
+        Ok(after)
+    }
+
+    /// Fields the change was worked out from, which the write is guarded on
+    fn guarded_fields(&self) -> &'static [&'static str] {
+        match self {
+            // Stock that's moved since it was read isn't overwritten
+            BulkAction::SetQuantity(_) => &["quantity", "variants"],
+            // Percentages and the variant range check use the price as read
+            BulkAction::AdjustPrice(_) => &["price", "variants"],
+            _ => &[],
+        }
+    }
+
+    /// The `$set` for a product this action changed
+    fn update(&self, after: &Product) -> Document {
+        match self {
+            BulkAction::SetQuantity(_) => doc! { "$set": { "quantity": after.quantity } },
+            BulkAction::AdjustPrice(_) => doc! { "$set": { "price": after.price } },
+            BulkAction::SetAdoptable(_) => doc! { "$set": { "adoptable": after.adoptable } },
+            BulkAction::SetCategory(_) => doc! { "$set": { "category_id": after.category_id } },
+            BulkAction::Delete => doc! {},
+        }
+    }
+}
+
+/// One selected product waiting on the bulk write
+struct PendingWrite {
+    /// Index into the results
+    slot: usize,
+    before: Product,
+    /// None for a delete
+    after: Option<Product>,
+}
+
+fn item_result(product_id: String, name: &str, outcome: &str, message: &str) -> BulkItemResult {
+    BulkItemResult {
+        product_id,
+        name: name.to_string(),
+        outcome: outcome.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// How many products ended up with each outcome, e.g. "3 updated, 1 failed"
+pub fn summarise(results: &[BulkItemResult]) -> String {
+    let counts: Vec<String> = ["updated", "deleted", "unchanged", "skipped", "failed"]
+        .iter()
+        .filter_map(|outcome| {
+            let count = results.iter().filter(|result| result.outcome == *outcome).count();
+            (count > 0).then(|| format!("{} {}", count, outcome))
+        })
+        .collect();
+    if counts.is_empty() {
+        "No products changed".to_string()
+    } else {
+        counts.join(", ")
+    }
+}
+
+/// Apply one action to every selected product in a single bulk write (which
+/// needs MongoDB 8.0+), reporting what happened to each
+pub async fn bulk_update_products(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    Extension(stores): Extension<ImageStores>,
+    actor: Actor,
+    Json(request): Json<BulkProductRequest>,
+) -> Json<BulkProductResponse> {
+    let failure = |message: String| {
+        Json(BulkProductResponse {
+            success: false,
+            message,
+            results: vec![],
+        })
+    };
+    if request.product_ids.is_empty() {
+        return failure("Select at least one product".to_string());
+    }
+    if request.product_ids.len() > MAX_BULK_PRODUCTS {
+        return failure(format!("Bulk actions can change at most {} products at once", MAX_BULK_PRODUCTS));
+    }
+    let categories = load_categories(&categories_collection).await;
+    let action = match BulkAction::parse(&request.action, &request.value, &categories) {
+        Ok(action) => action,
+        Err(message) => return failure(message),
+    };
+
+    let ids: Vec<ObjectId> = request
+        .product_ids
+        .iter()
+        .filter_map(|id| ObjectId::parse_str(id).ok())
+        .collect();
+    let (products, stored) = match load_stored_products(&collection, doc! { "_id": { "$in": &ids } }).await {
+        Ok(loaded) => loaded,
+        Err(e) => return failure(format!("Database error: {}", e)),
+    };
+
+    // Work out each product's change; only real changes are written
+    let mut results: Vec<BulkItemResult> = Vec::new();
+    let mut pending: Vec<PendingWrite> = Vec::new();
+    let mut models: Vec<WriteModel> = Vec::new();
+    for id in &request.product_ids {
+        let slot = results.len();
+        let found = ObjectId::parse_str(id)
+            .ok()
+            .and_then(|obj_id| products.iter().position(|product| product.id == obj_id));
+        let Some((product, stored)) = found.map(|index| (&products[index], &stored[index])) else {
+            results.push(item_result(id.clone(), "", "failed", "Product not found"));
+            continue;
+        };
+        if pending.iter().any(|write| write.before.id == product.id) {
+            continue; // Selected twice
+        }
+
+        let filter = unchanged_filter(stored, action.guarded_fields());
+        if action == BulkAction::Delete {
+            results.push(item_result(id.clone(), &product.name, "failed", "Not saved"));
+            models.push(DeleteOneModel::builder().namespace(collection.namespace()).filter(filter).build().into());
+            pending.push(PendingWrite {
+                slot,
+                before: product.clone(),
+                after: None,
+            });
+            continue;
+        }
+        match action.apply(product) {
+            Err(message) => results.push(item_result(id.clone(), &product.name, "skipped", &message)),
+            Ok(after) if audit_fields(&after) == audit_fields(product) => {
+                results.push(item_result(id.clone(), &product.name, "unchanged", "Already up to date"));
+            }
+            Ok(after) => {
+                results.push(item_result(id.clone(), &product.name, "failed", "Not saved"));
+                models.push(
+                    UpdateOneModel::builder()
+                        .namespace(collection.namespace())
+                        .filter(filter)
+                        .update(action.update(&after))
+                        .build()
+                        .into(),
+                );
+                pending.push(PendingWrite {
+                    slot,
+                    before: product.clone(),
+                    after: Some(after),
+                });
+            }
+        }
+    }
+
+    if !models.is_empty() {
+        // Unordered, so one failure doesn't stop the rest
+        let outcome = collection.client().bulk_write(models).ordered(false).verbose_results().await;
+        let (written, errors) = match outcome {
+            Ok(written) => (written, Default::default()),
+            Err(e) => match *e.kind {
+                ErrorKind::BulkWrite(bulk_error) => {
+                    let written = match bulk_error.partial_result {
+                        Some(mongodb::error::PartialBulkWriteResult::Verbose(written)) => written,
+                        _ => VerboseBulkWriteResult::default(),
+                    };
+                    (written, bulk_error.write_errors)
+                }
+                other => {
+                    let message = format!("Database error: {}", other);
+                    for write in &pending {
+                        results[write.slot].message = message.clone();
+                    }
+                    let summary = summarise(&results);
+                    return Json(BulkProductResponse {
+                        success: false,
+                        message: summary,
+                        results,
+                    });
+                }
+            },
+        };
+
+        for (index, write) in pending.iter().enumerate() {
+            let result = &mut results[write.slot];
+            if let Some(error) = errors.get(&index) {
+                result.message = error.message.clone();
+                continue;
+            }
+            let found = match &write.after {
+                Some(_) => written.update_results.get(&index).map(|update| update.matched_count > 0),
+                None => written.delete_results.get(&index).map(|delete| delete.deleted_count > 0),
+            };
+            match found {
+                Some(true) => {}
+                Some(false) => {
+                    result.message = match write.after {
+                        Some(_) => "Changed since it was selected; try again",
+                        None => "Already deleted",
+                    }
+                    .to_string();
+                    continue;
+                }
+                None => continue, // Not attempted
+            }
+
+            let before = audit_fields(&write.before);
+            let (action_name, changes, outcome, message) = match &write.after {
+                Some(after) => (audit::actions::PRODUCT_UPDATED, audit::diff(&before, &audit_fields(after)), "updated", "Saved"),
+                None => (audit::actions::PRODUCT_DELETED, audit::diff(&before, &[]), "deleted", "Deleted"),
+            };
+            result.outcome = outcome.to_string();
+            result.message = message.to_string();
+            audit_log
+                .record(&actor, action_name, Some(write.before.id.to_hex()), changes)
+                .await;
+            match &write.after {
+                Some(after) => {
+                    stock_ledger
+                        .record_changes(&actor, Some(&write.before), after, MovementKind::Adjustment, "Bulk edit")
+                        .await
+                }
+                None => delete_product_images(stores.product_images.as_ref(), &write.before).await,
+            }
+        }
+    }
+
+    let success = results.iter().all(|result| result.outcome != "failed");
+    Json(BulkProductResponse {
+        success,
+        message: summarise(&results),
+        results,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ProductVariant;
+
+    fn gbp(minor_units: i64) -> Money {
+        Money::new(minor_units, Currency::Gbp)
+    }
+
+    fn product(price: i64) -> Product {
+        Product {
+            name: "Fox Pin".to_string(),
+            image_url: "/product-images/a.jpg".to_string(),
+            price: gbp(price),
+            quantity: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_actions() {
+        let category = Category {
+            id: ObjectId::new(),
+            name: "Pins".to_string(),
+            slug: "pins".to_string(),
+            description: String::new(),
+        };
+        let categories = std::slice::from_ref(&category);
+        assert_eq!(BulkAction::parse("set_quantity", " 0 ", &[]), Ok(BulkAction::SetQuantity(0)));
+        assert!(BulkAction::parse("set_quantity", "-1", &[]).is_err());
+        assert_eq!(
+            BulkAction::parse("adjust_price", "10%", &[]),
+            Ok(BulkAction::AdjustPrice(PriceAdjustment::Percent(1000)))
+        );
+        assert_eq!(
+            BulkAction::parse("adjust_price", "-1.50", &[]),
+            Ok(BulkAction::AdjustPrice(PriceAdjustment::Amount(gbp(-150))))
+        );
+        assert_eq!(
+            BulkAction::parse("adjust_price", "€2", &[]),
+            Ok(BulkAction::AdjustPrice(PriceAdjustment::Amount(Money::new(200, Currency::Eur))))
+        );
+        assert!(BulkAction::parse("adjust_price", "-100%", &[]).is_err());
+        assert!(BulkAction::parse("adjust_price", "£10%", &[]).is_err());
+        assert!(BulkAction::parse("adjust_price", "lots", &[]).is_err());
+        assert_eq!(BulkAction::parse("set_adoptable", "true", &[]), Ok(BulkAction::SetAdoptable(true)));
+        assert_eq!(
+            BulkAction::parse("set_category", &category.id.to_hex(), categories),
+            Ok(BulkAction::SetCategory(Some(category.id)))
+        );
+        assert_eq!(BulkAction::parse("set_category", "", categories), Ok(BulkAction::SetCategory(None)));
+        assert!(BulkAction::parse("set_category", &ObjectId::new().to_hex(), categories).is_err());
+        assert!(BulkAction::parse("explode", "", &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("10"), Some(1000));
+        assert_eq!(parse_percent("+2.5"), Some(250));
+        assert_eq!(parse_percent("-0.25"), Some(-25));
+        assert_eq!(parse_percent("1.234"), None);
+        assert_eq!(parse_percent("."), None);
+        assert_eq!(parse_percent("£10"), None);
+        assert_eq!(parse_percent("9999999"), None);
+    }
+
+    #[test]
+    fn test_adjust_price() {
+        let percent = |hundredths| BulkAction::AdjustPrice(PriceAdjustment::Percent(hundredths));
+        assert_eq!(percent(1000).apply(&product(450)).unwrap().price, gbp(495));
+        // 10% of £0.05 is half a penny, rounded up
+        assert_eq!(percent(1000).apply(&product(5)).unwrap().price, gbp(6));
+        assert_eq!(percent(-1000).apply(&product(5)).unwrap().price, gbp(4));
+
+        let amount = |minor_units| BulkAction::AdjustPrice(PriceAdjustment::Amount(gbp(minor_units)));
+        assert_eq!(amount(-50).apply(&product(450)).unwrap().price, gbp(400));
+        assert!(amount(-500).apply(&product(450)).is_err());
+
+        // A pound amount doesn't change a euro price
+        let euro_pin = Product {
+            price: Money::new(450, Currency::Eur),
+            ..product(450)
+        };
+        assert_eq!(amount(100).apply(&euro_pin).unwrap_err(), "Priced in a different currency (EUR)");
+        assert_eq!(percent(1000).apply(&euro_pin).unwrap().price, Money::new(495, Currency::Eur));
+
+        let mut tee = product(450);
+        tee.variants = vec![ProductVariant {
+            sku: "TEE-S".to_string(),
+            option_values: vec!["S".to_string()],
+            price_delta: gbp(-400),
+            quantity: 1,
+        }];
+        assert!(amount(-100).apply(&tee).is_err());
+        assert!(BulkAction::SetQuantity(0).apply(&tee).is_err());
+    }
+
+    #[test]
+    fn test_summarise() {
+        let results = vec![
+            item_result("a".to_string(), "A", "updated", ""),
+            item_result("b".to_string(), "B", "updated", ""),
+            item_result("c".to_string(), "C", "failed", ""),
+        ];
+        assert_eq!(summarise(&results), "2 updated, 1 failed");
+        assert_eq!(summarise(&[]), "No products changed");
+    }
+}
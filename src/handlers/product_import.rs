@@ -0,0 +1,864 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::{Form, Multipart, Query},
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Json, Redirect, Response},
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection,
+    bson::{doc, oid::ObjectId},
+    error::ErrorKind,
+    options::{Collation, CollationStrength, UpdateOneModel, WriteModel},
+    results::VerboseBulkWriteResult,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::{
+    audit::{self, Actor, AuditLog},
+    handlers::{
+        categories::load_categories,
+        product_management::{
+            MAX_PRICE_MINOR_UNITS, audit_fields, load_stored_products, parse_product_sku, parse_tags, product_filter, product_sort,
+            unchanged_filter, validate_create_product_form,
+        },
+    },
+    models::{
+        Category, CreateProductForm, ImportRowDisplay, Product, ProductExportParams, ProductImportApplyForm,
+        ProductImportTemplate, ProductQueryParams, UserState,
+    },
+    stock::{MovementKind, StockLedger},
+};
+
+/// Most rows one import can have
+pub const MAX_IMPORT_ROWS: usize = 1000;
+/// Largest import file accepted
+const MAX_IMPORT_BYTES: usize = 5 * 1024 * 1024;
+
+/// Columns of the export, in order. An import may leave any of them out.
+pub const COLUMNS: [&str; 9] = [
+    "id",
+    "sku",
+    "name",
+    "price",
+    "quantity",
+    "description",
+    "adoptable",
+    "category",
+    "tags",
+];
+
+/// A product as one row of an export or import. A column missing from an
+/// import is None and leaves the product's current value alone.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProductRow {
+    pub id: Option<String>,
+    pub sku: Option<String>,
+    pub name: Option<String>,
+    /// In major units, e.g. "4.50"
+    pub price: Option<String>,
+    pub quantity: Option<String>,
+    pub description: Option<String>,
+    /// "true" or "false"
+    pub adoptable: Option<String>,
+    /// Category slug
+    pub category: Option<String>,
+    /// Comma-separated
+    pub tags: Option<String>,
+}
+
+impl ProductRow {
+    pub fn from_product(product: &Product, categories: &[Category]) -> Self {
+        let category = product
+            .category_id
+            .and_then(|id| categories.iter().find(|category| category.id == id))
+            .map(|category| category.slug.clone())
+            .unwrap_or_default();
+        ProductRow {
+            id: Some(product.id.to_hex()),
+            sku: Some(product.sku.clone().unwrap_or_default()),
+            name: Some(product.name.clone()),
+            price: Some(product.price.to_decimal_string()),
+            quantity: Some(product.quantity.to_string()),
+            description: Some(product.description.clone()),
+            adoptable: Some(product.adoptable.to_string()),
+            category: Some(category),
+            tags: Some(product.tags.join(", ")),
+        }
+    }
+
+    /// Build a row from (column, value) pairs, rejecting unknown columns so a
+    /// misspelt header isn't silently ignored
+    fn from_fields(fields: impl IntoIterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut row = ProductRow::default();
+        for (column, value) in fields {
+            let slot = match column.trim().to_lowercase().as_str() {
+                "id" => &mut row.id,
+                "sku" => &mut row.sku,
+                "name" => &mut row.name,
+                "price" => &mut row.price,
+                "quantity" => &mut row.quantity,
+                "description" => &mut row.description,
+                "adoptable" => &mut row.adoptable,
+                "category" => &mut row.category,
+                "tags" => &mut row.tags,
+                other => {
+                    return Err(format!(
+                        "Unknown column \"{}\" - columns are {}",
+                        other,
+                        COLUMNS.join(", ")
+                    ));
+                }
+            };
+            *slot = Some(value.trim().to_string());
+        }
+        Ok(row)
+    }
+
+    /// The id or SKU the row is matched on, or its name for a new product
+    fn key(&self) -> String {
+        [&self.id, &self.sku, &self.name]
+            .into_iter()
+            .flatten()
+            .map(|value| value.trim())
+            .find(|value| !value.is_empty())
+            .unwrap_or("")
+            .to_string()
+    }
+}
+
+/// Parse an uploaded CSV file, which needs a header row
+pub fn parse_csv(bytes: &[u8]) -> Result<Vec<ProductRow>, String> {
+    // Spreadsheet programs often start UTF-8 CSVs with a byte order mark
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let mut reader = csv::Reader::from_reader(bytes);
+    let headers: Vec<String> = reader
+        .headers()
+        .map_err(|e| format!("Couldn't read the CSV header: {}", e))?
+        .iter()
+        .map(str::to_string)
+        .collect();
+
+    let mut rows = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("Row {}: {}", i + 1, e))?;
+        let fields = headers.iter().cloned().zip(record.iter().map(str::to_string));
+        rows.push(ProductRow::from_fields(fields)?);
+    }
+    Ok(rows)
+}
+
+/// Parse an uploaded JSON array of objects. Numbers and booleans are taken as
+/// written, tags may be an array, and null clears a field.
+pub fn parse_json(bytes: &[u8]) -> Result<Vec<ProductRow>, String> {
+    let objects: Vec<serde_json::Map<String, Value>> =
+        serde_json::from_slice(bytes).map_err(|e| format!("The file isn't a JSON array of products: {}", e))?;
+
+    let mut rows = Vec::new();
+    for (i, object) in objects.into_iter().enumerate() {
+        let mut fields = Vec::new();
+        for (column, value) in object {
+            let value = match value {
+                Value::Null => String::new(),
+                Value::String(text) => text,
+                Value::Number(number) => number.to_string(),
+                Value::Bool(flag) => flag.to_string(),
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Value::Object(_) => return Err(format!("Row {}: {} can't be an object", i + 1, column)),
+            };
+            fields.push((column, value));
+        }
+        rows.push(ProductRow::from_fields(fields)?);
+    }
+    Ok(rows)
+}
+
+fn parse_adoptable(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "y" | "1" => Ok(true),
+        "false" | "no" | "n" | "0" | "" => Ok(false),
+        other => Err(format!("Adoptable must be true or false, not {}", other)),
+    }
+}
+
+/// A valid row: the product as it is now (None for a new one) and as it
+/// will be after the import
+#[derive(Debug, Clone)]
+pub struct PlannedRow {
+    pub row: usize,
+    pub key: String,
+    pub before: Option<Product>,
+    pub after: Product,
+}
+
+impl PlannedRow {
+    fn display(&self) -> ImportRowDisplay {
+        let (details, warning) = match &self.before {
+            None => (
+                format!("{} · {} in stock", self.after.price, self.after.quantity),
+                "No image yet - add one from its edit page".to_string(),
+            ),
+            Some(before) => {
+                let changes: Vec<String> = audit::diff(&audit_fields(before), &audit_fields(&self.after))
+                    .into_iter()
+                    .map(|change| {
+                        format!(
+                            "{}: {} → {}",
+                            change.field,
+                            change.before.unwrap_or_default(),
+                            change.after.unwrap_or_default()
+                        )
+                    })
+                    .collect();
+                (changes.join("; "), String::new())
+            }
+        };
+        ImportRowDisplay {
+            row: self.row,
+            key: self.key.clone(),
+            name: self.after.name.clone(),
+            details,
+            warning,
+        }
+    }
+}
+
+/// Everything an import would do. It's only applied when `errors` is empty.
+#[derive(Debug, Default)]
+pub struct ImportPlan {
+    pub creates: Vec<PlannedRow>,
+    pub updates: Vec<PlannedRow>,
+    /// Rows that match a product without changing it
+    pub unchanged: usize,
+    pub errors: Vec<ImportRowDisplay>,
+}
+
+/// Work out what importing `rows` would do to `products`. Rows are matched by
+/// id, then by SKU; anything else is a new product. Every row goes through
+/// the same validation as the create form.
+pub fn plan_import(rows: &[ProductRow], products: &[Product], categories: &[Category]) -> ImportPlan {
+    let mut plan = ImportPlan::default();
+    let mut seen_ids: HashSet<ObjectId> = HashSet::new();
+    let mut seen_skus: HashSet<String> = HashSet::new();
+
+    for (i, row) in rows.iter().enumerate() {
+        let planned = plan_row(row, products, categories).and_then(|(before, after)| {
+            if before.is_some() && !seen_ids.insert(after.id) {
+                return Err(format!("{} is in the file more than once", after.name));
+            }
+            if let Some(sku) = &after.sku
+                && !seen_skus.insert(sku.to_lowercase())
+            {
+                return Err(format!("SKU {} is used more than once", sku));
+            }
+            Ok(PlannedRow {
+                row: i + 1,
+                key: row.key(),
+                before: before.cloned(),
+                after,
+            })
+        });
+
+        match planned {
+            Ok(planned) => match &planned.before {
+                None => plan.creates.push(planned),
+                Some(before) if audit_fields(before) == audit_fields(&planned.after) => plan.unchanged += 1,
+                Some(_) => plan.updates.push(planned),
+            },
+            Err(message) => plan.errors.push(ImportRowDisplay {
+                row: i + 1,
+                key: row.key(),
+                name: row.name.clone().unwrap_or_default(),
+                details: message,
+                warning: String::new(),
+            }),
+        }
+    }
+    plan
+}
+
+/// A column's value, unless it's missing or blank
+fn given(column: &Option<String>) -> Option<&str> {
+    column.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
+
+/// The product a row matches, if any, and the product it describes
+fn plan_row<'a>(
+    row: &ProductRow,
+    products: &'a [Product],
+    categories: &[Category],
+) -> Result<(Option<&'a Product>, Product), String> {
+    let same_sku = |sku: &str, other: &Option<String>| other.as_deref().is_some_and(|other| other.eq_ignore_ascii_case(sku));
+
+    let existing = match (given(&row.id), given(&row.sku)) {
+        (Some(id), _) => {
+            let obj_id = ObjectId::parse_str(id).map_err(|_| format!("{} isn't a valid product id", id))?;
+            let product = products
+                .iter()
+                .find(|product| product.id == obj_id)
+                .ok_or_else(|| format!("There's no product with id {}", id))?;
+            Some(product)
+        }
+        (None, Some(sku)) => products.iter().find(|product| same_sku(sku, &product.sku)),
+        (None, None) => None,
+    };
+
+    let sku = match &row.sku {
+        Some(value) => parse_product_sku(value)?,
+        None => existing.and_then(|product| product.sku.clone()),
+    };
+    if let Some(sku) = &sku {
+        let owner = products.iter().find(|product| {
+            Some(product.id) != existing.map(|existing| existing.id)
+                && (same_sku(sku, &product.sku) || product.variants.iter().any(|variant| variant.sku.eq_ignore_ascii_case(sku)))
+        });
+        if let Some(owner) = owner {
+            return Err(format!("{} already uses SKU {}", owner.name, sku));
+        }
+        if existing.is_some_and(|product| product.variants.iter().any(|variant| variant.sku.eq_ignore_ascii_case(sku))) {
+            return Err(format!("SKU {} is used more than once", sku));
+        }
+    }
+
+    let value = |column: &Option<String>, current: Option<String>| column.clone().or(current).unwrap_or_default();
+    let adoptable = match &row.adoptable {
+        Some(value) => parse_adoptable(value)?,
+        None => existing.is_some_and(|product| product.adoptable),
+    };
+    let form = CreateProductForm {
+        name: value(&row.name, existing.map(|product| product.name.clone())),
+        price: value(&row.price, existing.map(|product| product.price.to_decimal_string())),
+        quantity: value(&row.quantity, existing.map(|product| product.quantity.to_string())),
+        description: value(&row.description, existing.map(|product| product.description.clone())),
+        adoptable: adoptable.then(|| "on".to_string()),
+    };
+    let (mut price, quantity) = validate_create_product_form(&form)?;
+
+    let category_id = match row.category.as_deref().map(str::trim) {
+        None => existing.and_then(|product| product.category_id),
+        Some("") => None,
+        Some(category) => {
+            let found = categories
+                .iter()
+                .find(|known| known.slug == category || known.name.eq_ignore_ascii_case(category))
+                .ok_or_else(|| format!("There's no category called {}", category))?;
+            Some(found.id)
+        }
+    };
+    let tags = match &row.tags {
+        Some(tags) => parse_tags(tags)?,
+        None => existing.map(|product| product.tags.clone()).unwrap_or_default(),
+    };
+
+    let Some(existing) = existing else {
+        let product = Product {
+            id: ObjectId::new(),
+            name: form.name.trim().to_string(),
+            image_url: String::new(),
+            price,
+            quantity,
+            description: form.description,
+            adoptable,
+            images: vec![],
+            category_id,
+            tags,
+            sku,
+            reorder_threshold: None,
+            options: vec![],
+            variants: vec![],
+        };
+        return Ok((None, product));
+    };
+
+    price = price.with_currency(existing.price.currency);
+    if !existing.variants.is_empty() {
+        if quantity != existing.quantity {
+            return Err(format!("Stock for {} is set per variant, on its edit page", existing.name));
+        }
+        let variants_in_range = existing.variants.iter().all(|variant| {
+            price
+                .checked_add(variant.price_delta)
+                .is_some_and(|variant_price| (0..=MAX_PRICE_MINOR_UNITS).contains(&variant_price.minor_units))
+        });
+        if !variants_in_range {
+            return Err(format!("That price would put one of {}'s variants out of range", existing.name));
+        }
+    }
+    let product = Product {
+        name: form.name.trim().to_string(),
+        price,
+        quantity,
+        description: form.description,
+        adoptable,
+        category_id,
+        tags,
+        sku,
+        ..existing.clone()
+    };
+    Ok((Some(existing), product))
+}
+
+async fn load_products(collection: &Collection<Product>) -> mongodb::error::Result<Vec<Product>> {
+    collection.find(doc! {}).await?.try_collect().await
+}
+
+/// Fields an import reads or writes; updates only apply while these are as
+/// the plan read them
+const GUARDED_FIELDS: [&str; 9] = [
+    "name",
+    "price",
+    "quantity",
+    "description",
+    "adoptable",
+    "category_id",
+    "tags",
+    "sku",
+    "variants",
+];
+
+fn render_page(user_state: UserState, error_message: String, preview: Option<(&ImportPlan, &[ProductRow])>) -> Response {
+    let empty = ImportPlan::default();
+    let (plan, rows) = preview.unwrap_or((&empty, &[]));
+    let template = ProductImportTemplate {
+        user_state,
+        error_message,
+        has_preview: preview.is_some(),
+        creates: plan.creates.iter().map(PlannedRow::display).collect(),
+        updates: plan.updates.iter().map(PlannedRow::display).collect(),
+        errors: plan.errors.clone(),
+        unchanged: plan.unchanged,
+        rows_json: serde_json::to_string(rows).unwrap_or_default(),
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Download the products matching the list's filters as CSV or JSON
+pub async fn export_products(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Query(params): Query<ProductQueryParams>,
+    Query(export): Query<ProductExportParams>,
+) -> Response {
+    let categories = load_categories(&categories_collection).await;
+    let filter = match product_filter(&params, &categories) {
+        Ok(filter) => filter,
+        Err(error_message) => return (StatusCode::BAD_REQUEST, error_message).into_response(),
+    };
+    let collation = Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build();
+    let products: Vec<Product> = match collection
+        .find(filter)
+        .sort(product_sort(params.sort.as_deref()))
+        .collation(collation)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(products) => products,
+            Err(e) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error exporting products: {}", e))
+                    .into_response();
+            }
+        },
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error exporting products: {}", e))
+                .into_response();
+        }
+    };
+    let rows: Vec<ProductRow> = products
+        .iter()
+        .map(|product| ProductRow::from_product(product, &categories))
+        .collect();
+
+    if export.format.as_deref() == Some("json") {
+        return (
+            [(header::CONTENT_DISPOSITION, "attachment; filename=\"products.json\"")],
+            Json(rows),
+        )
+            .into_response();
+    }
+    match write_csv(&rows) {
+        Ok(csv) => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"products.csv\""),
+            ],
+            csv,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing CSV: {}", e)).into_response(),
+    }
+}
+
+/// The rows as CSV, with a header even when there are none
+pub fn write_csv(rows: &[ProductRow]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+    writer.write_record(COLUMNS)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Show the upload form
+pub async fn show_import_form(user_state: UserState) -> impl IntoResponse {
+    render_page(user_state, String::new(), None)
+}
+
+/// Read the uploaded file and preview what importing it would do. Nothing is
+/// saved yet.
+pub async fn preview_import(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    user_state: UserState,
+    multipart: Multipart,
+) -> Response {
+    let rows = match read_upload(multipart).await {
+        Ok(rows) => rows,
+        Err(error_message) => return render_page(user_state, error_message, None),
+    };
+    let products = match load_products(&collection).await {
+        Ok(products) => products,
+        Err(e) => return render_page(user_state, format!("Database error: {}", e), None),
+    };
+    let categories = load_categories(&categories_collection).await;
+    let plan = plan_import(&rows, &products, &categories);
+    render_page(user_state, String::new(), Some((&plan, &rows)))
+}
+
+/// The rows of the uploaded file, as CSV or, if it's named .json or looks
+/// like it, JSON
+async fn read_upload(mut multipart: Multipart) -> Result<Vec<ProductRow>, String> {
+    let mut upload: Option<(String, Vec<u8>)> = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| format!("Invalid form data: {}", e))?
+    {
+        if field.name() == Some("file") {
+            let file_name = field.file_name().unwrap_or("").to_lowercase();
+            let bytes = field.bytes().await.map_err(|e| format!("Couldn't read the file: {}", e))?;
+            upload = Some((file_name, bytes.to_vec()));
+        }
+    }
+
+    let (file_name, bytes) = upload
+        .filter(|(_, bytes)| !bytes.is_empty())
+        .ok_or_else(|| "Choose a CSV or JSON file to import".to_string())?;
+    if bytes.len() > MAX_IMPORT_BYTES {
+        return Err(format!("Import files can be at most {} MB", MAX_IMPORT_BYTES / 1024 / 1024));
+    }
+    let is_json = file_name.ends_with(".json") || bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[');
+    let rows = if is_json { parse_json(&bytes)? } else { parse_csv(&bytes)? };
+
+    if rows.is_empty() {
+        return Err("The file has no products in it".to_string());
+    }
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(format!("An import can have at most {} rows", MAX_IMPORT_ROWS));
+    }
+    Ok(rows)
+}
+
+/// Apply a previewed import. The rows are planned again against the current
+/// products, and nothing is saved if any row is no longer valid. The writes
+/// themselves aren't all-or-nothing: a write error stops the import with the
+/// earlier rows saved, and a product changed in the meantime is skipped while
+/// the other rows are saved.
+pub async fn apply_import(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    actor: Actor,
+    user_state: UserState,
+    Form(form): Form<ProductImportApplyForm>,
+) -> Response {
+    let rows: Vec<ProductRow> = match serde_json::from_str(&form.rows) {
+        Ok(rows) => rows,
+        Err(_) => return render_page(user_state, "The import was incomplete - upload the file again".to_string(), None),
+    };
+    if rows.len() > MAX_IMPORT_ROWS {
+        let error_message = format!("An import can have at most {} rows", MAX_IMPORT_ROWS);
+        return render_page(user_state, error_message, None);
+    }
+    let (products, stored) = match load_stored_products(&collection, doc! {}).await {
+        Ok(loaded) => loaded,
+        Err(e) => return render_page(user_state, format!("Database error: {}", e), None),
+    };
+    let categories = load_categories(&categories_collection).await;
+    let plan = plan_import(&rows, &products, &categories);
+    if !plan.errors.is_empty() {
+        let error_message = "Products have changed since the preview. Nothing was imported; fix the rows below and try again.";
+        return render_page(user_state, error_message.to_string(), Some((&plan, &rows)));
+    }
+
+    // Creates then updates, in one bulk write (which needs MongoDB 8.0+). Each
+    // update only applies if the product is still as it was planned from.
+    let mut models: Vec<WriteModel> = Vec::new();
+    for planned in &plan.creates {
+        match collection.insert_one_model(&planned.after) {
+            Ok(model) => models.push(model.into()),
+            Err(e) => return render_page(user_state, format!("Invalid product data: {}", e), Some((&plan, &rows))),
+        }
+    }
+    for planned in &plan.updates {
+        let after = &planned.after;
+        let Some(stored) = stored.iter().find(|document| document.get_object_id("_id").ok() == Some(after.id)) else {
+            continue;
+        };
+        let update_doc = doc! {
+            "$set": {
+                "name": &after.name,
+                "price": after.price,
+                "quantity": after.quantity,
+                "description": &after.description,
+                "adoptable": after.adoptable,
+                "category_id": after.category_id,
+                "tags": &after.tags,
+                "sku": after.sku.clone(),
+            }
+        };
+        models.push(
+            UpdateOneModel::builder()
+                .namespace(collection.namespace())
+                .filter(unchanged_filter(stored, &GUARDED_FIELDS))
+                .update(update_doc)
+                .build()
+                .into(),
+        );
+    }
+    if models.is_empty() {
+        return Redirect::to("/products?success=imported").into_response();
+    }
+
+    // Ordered, so a failure stops the rows after it
+    let outcome = collection.client().bulk_write(models).ordered(true).verbose_results().await;
+    let (written, write_error) = match outcome {
+        Ok(written) => (written, None),
+        Err(e) => match *e.kind {
+            ErrorKind::BulkWrite(bulk_error) => {
+                let written = match bulk_error.partial_result {
+                    Some(mongodb::error::PartialBulkWriteResult::Verbose(written)) => written,
+                    _ => VerboseBulkWriteResult::default(),
+                };
+                let message = bulk_error.write_errors.values().next().map(|error| error.message.clone());
+                (written, Some(message.unwrap_or_else(|| "the write failed".to_string())))
+            }
+            other => return render_page(user_state, format!("Database error: {}", other), Some((&plan, &rows))),
+        },
+    };
+
+    for (index, planned) in plan.creates.iter().enumerate() {
+        if !written.insert_results.contains_key(&index) {
+            continue;
+        }
+        audit_log
+            .record(
+                &actor,
+                audit::actions::PRODUCT_CREATED,
+                Some(planned.after.id.to_hex()),
+                audit::diff(&[], &audit_fields(&planned.after)),
+            )
+            .await;
+        stock_ledger
+            .record_changes(&actor, None, &planned.after, MovementKind::Initial, "Imported")
+            .await;
+    }
+    let mut changed: Vec<&str> = Vec::new();
+    for (offset, planned) in plan.updates.iter().enumerate() {
+        let (after, Some(before)) = (&planned.after, &planned.before) else {
+            continue;
+        };
+        match written.update_results.get(&(plan.creates.len() + offset)) {
+            Some(update) if update.matched_count > 0 => {}
+            Some(_) => {
+                changed.push(&after.name);
+                continue;
+            }
+            None => continue, // Not attempted
+        }
+        audit_log
+            .record(
+                &actor,
+                audit::actions::PRODUCT_UPDATED,
+                Some(after.id.to_hex()),
+                audit::diff(&audit_fields(before), &audit_fields(after)),
+            )
+            .await;
+        // A new quantity counts as a stocktake
+        stock_ledger
+            .record_changes(&actor, Some(before), after, MovementKind::Stocktake, "Imported")
+            .await;
+    }
+
+    if let Some(message) = write_error {
+        let error_message = format!("The import stopped partway - earlier rows were saved: {}", message);
+        return render_page(user_state, error_message, Some((&plan, &rows)));
+    }
+    if !changed.is_empty() {
+        let error_message = format!(
+            "{} changed while importing and {} not updated; the other rows were saved",
+            changed.join(", "),
+            if changed.len() == 1 { "was" } else { "were" }
+        );
+        return render_page(user_state, error_message, Some((&plan, &rows)));
+    }
+    Redirect::to("/products?success=imported").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::{Currency, Money};
+    use crate::models::ProductVariant;
+    use mongodb::bson::Bson;
+
+    fn product(name: &str, sku: Option<&str>) -> Product {
+        Product {
+            name: name.to_string(),
+            image_url: "/product-images/a.jpg".to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 3,
+            description: "A fox".to_string(),
+            sku: sku.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn row(fields: &[(&str, &str)]) -> ProductRow {
+        ProductRow::from_fields(fields.iter().map(|(column, value)| (column.to_string(), value.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let category = Category {
+            id: ObjectId::new(),
+            name: "Pins".to_string(),
+            slug: "pins".to_string(),
+            description: String::new(),
+        };
+        let mut pin = product("Fox Pin, \"Large\"", Some("FOX-PIN"));
+        pin.category_id = Some(category.id);
+        pin.tags = vec!["foxes".to_string(), "enamel".to_string()];
+        let rows = vec![ProductRow::from_product(&pin, std::slice::from_ref(&category))];
+
+        let csv = write_csv(&rows).unwrap();
+        assert!(String::from_utf8_lossy(&csv).starts_with("id,sku,name,price,quantity,description,adoptable,category,tags\n"));
+        assert_eq!(parse_csv(&csv).unwrap(), rows);
+        assert_eq!(rows[0].price.as_deref(), Some("4.50"));
+        assert_eq!(rows[0].category.as_deref(), Some("pins"));
+
+        let plan = plan_import(&rows, &[pin], &[category]);
+        assert_eq!((plan.creates.len(), plan.updates.len(), plan.unchanged), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_parse_csv_and_json() {
+        let csv = "\u{feff}SKU,Name,Price,Quantity\nFOX-1, Fox ,4.5,2\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        assert_eq!(rows, vec![row(&[("sku", "FOX-1"), ("name", "Fox"), ("price", "4.5"), ("quantity", "2")])]);
+        assert_eq!(rows[0].description, None);
+        assert!(parse_csv(b"name,colour\nFox,red\n").is_err());
+        assert!(parse_csv(b"name,price\nFox\n").is_err());
+
+        let json = r#"[{"name": "Fox", "price": 4.5, "quantity": 2, "adoptable": true, "tags": ["a", "b"], "sku": null}]"#;
+        let rows = parse_json(json.as_bytes()).unwrap();
+        assert_eq!(rows[0].price.as_deref(), Some("4.5"));
+        assert_eq!(rows[0].adoptable.as_deref(), Some("true"));
+        assert_eq!(rows[0].tags.as_deref(), Some("a, b"));
+        assert_eq!(rows[0].sku.as_deref(), Some(""));
+        assert!(parse_json(b"{\"name\": \"Fox\"}").is_err());
+    }
+
+    #[test]
+    fn test_plan_matches_by_id_then_sku() {
+        let by_id = product("Fox Pin", None);
+        let by_sku = product("Badger Pin", Some("BADGER"));
+        let products = vec![by_id.clone(), by_sku.clone()];
+        let rows = vec![
+            row(&[("id", &by_id.id.to_hex()), ("price", "5.00")]),
+            row(&[("sku", "badger"), ("quantity", "10")]),
+            row(&[("sku", "OTTER"), ("name", "Otter Pin"), ("price", "3"), ("quantity", "1")]),
+        ];
+
+        let plan = plan_import(&rows, &products, &[]);
+        assert!(plan.errors.is_empty(), "{:?}", plan.errors);
+        assert_eq!(plan.updates.len(), 2);
+        assert_eq!(plan.updates[0].after.price, Money::new(500, Currency::Gbp));
+        assert_eq!(plan.updates[0].after.description, "A fox");
+        assert_eq!(plan.updates[1].after.id, by_sku.id);
+        assert_eq!(plan.updates[1].after.quantity, 10);
+        assert!(plan.updates[0].display().details.contains("price: £4.50 → £5.00"));
+
+        assert_eq!(plan.creates.len(), 1);
+        assert_eq!(plan.creates[0].after.sku.as_deref(), Some("OTTER"));
+        assert_eq!(plan.creates[0].key, "OTTER");
+        assert!(!plan.creates[0].display().warning.is_empty());
+    }
+
+    #[test]
+    fn test_plan_reports_row_errors() {
+        let mut tee = product("Fox Tee", None);
+        tee.variants = vec![ProductVariant {
+            sku: "TEE-RED".to_string(),
+            option_values: vec!["Red".to_string()],
+            price_delta: Money::new(-400, Currency::Gbp),
+            quantity: 3,
+        }];
+        let pin = product("Fox Pin", Some("FOX-PIN"));
+        let products = vec![tee.clone(), pin.clone()];
+        let tee_id = tee.id.to_hex();
+        let rows = vec![
+            row(&[("name", "Cheap"), ("price", "abc"), ("quantity", "1")]),
+            row(&[("id", "nope")]),
+            row(&[("id", &ObjectId::new().to_hex())]),
+            row(&[("sku", "TEE-RED"), ("name", "Clash"), ("price", "1"), ("quantity", "1")]),
+            row(&[("id", &tee_id), ("quantity", "9")]),
+            row(&[("id", &tee_id), ("price", "3.00")]),
+            row(&[("sku", "FOX-PIN"), ("adoptable", "maybe")]),
+            row(&[("sku", "FOX-PIN"), ("quantity", "1")]),
+            row(&[("sku", "FOX-PIN"), ("quantity", "2")]),
+            row(&[("name", "Fox"), ("price", "1"), ("quantity", "1"), ("category", "nowhere")]),
+        ];
+
+        let plan = plan_import(&rows, &products, &[]);
+        let failed: Vec<usize> = plan.errors.iter().map(|error| error.row).collect();
+        assert_eq!(failed, vec![1, 2, 3, 4, 5, 6, 7, 9, 10]);
+        assert_eq!(plan.errors[0].details, "Price must be a valid number");
+        assert_eq!(plan.errors[3].details, "Fox Tee already uses SKU TEE-RED");
+        assert_eq!(plan.updates.len(), 1);
+        assert_eq!(plan.updates[0].after.id, pin.id);
+    }
+
+    #[test]
+    fn test_unchanged_filter_matches_the_stored_fields() {
+        // An older product: price as a plain number, no SKU or tags
+        let id = ObjectId::new();
+        let stored = doc! {
+            "_id": id,
+            "name": "Fox Pin",
+            "image_url": "/product-images/a.jpg",
+            "price": 4.5,
+            "quantity": 3,
+            "description": "A fox",
+            "adoptable": false,
+        };
+
+        let filter = unchanged_filter(&stored, &GUARDED_FIELDS);
+        assert_eq!(filter.get_object_id("_id").unwrap(), id);
+        assert_eq!(filter.get_f64("price").unwrap(), 4.5);
+        assert_eq!(filter.get_i32("quantity").unwrap(), 3);
+        assert_eq!(filter.get("sku"), Some(&Bson::Null));
+        assert_eq!(filter.get("variants"), Some(&Bson::Null));
+        assert!(!filter.contains_key("image_url"));
+    }
+}
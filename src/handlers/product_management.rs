@@ -1,25 +1,37 @@
 use askama::Template;
 use axum::{
     Extension,
-    extract::{Form, Multipart, Path},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json, Redirect},
+    extract::{Multipart, Path, Query, multipart::Field},
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Json, Redirect, Response},
 };
 use futures_util::TryStreamExt;
 use mongodb::{
     Collection,
-    bson::{doc, oid::ObjectId},
+    bson::{self, Bson, Document, doc, oid::ObjectId},
+    options::{Collation, CollationStrength},
 };
-use std::path::Path as StdPath;
-use tokio::fs;
+use std::collections::HashSet;
+use tracing::{error, warn};
 
 use crate::{
-    handlers::auth::AppAuthSession,
+    audit::{self, Actor, AuditLog},
+    handlers::{
+        categories::{category_options, load_categories},
+        order_processing::create_pagination_info,
+    },
+    image_cleanup,
+    image_processing::{self, EncodedImage},
+    low_stock,
+    money::{Currency, Money},
     models::{
-        CreateProductForm, CreateProductTemplate, EditProductForm, EditProductTemplate, Product, ProductDisplay, ProductManagementTemplate,
-        ProductOperationResponse, UserState,
+        Category, CreateProductForm, CreateProductTemplate, EditProductForm, EditProductTemplate, Product,
+        ProductDisplay, ProductImage, ProductImageDisplay, ProductManagementTemplate, ProductOperationResponse,
+        ProductOption, ProductOptionDisplay, ProductQueryParams, ProductVariant, ProductVariantDisplay, SelectOption,
+        UserState,
     },
-    user_state::extract_user_state,
+    stock::{MAX_STOCK, MovementKind, StockLedger},
+    storage::{self, ImageStores, Storage},
 };
 
 /// Normalize image URL to ensure it starts with /
@@ -31,222 +43,1108 @@ fn normalize_image_url(url: &str) -> String {
     }
 }
 
-/// List all products for admin management
-pub async fn list_products(
-    Extension(collection): Extension<Collection<Product>>,
-    auth: AppAuthSession,
-) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
+/// Normalize every URL on a gallery image
+fn normalize_image(image: ProductImage) -> ProductImage {
+    ProductImage {
+        url: normalize_image_url(&image.url),
+        thumbnail_url: image.thumbnail_url.as_deref().map(normalize_image_url),
+        ..image
+    }
+}
 
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
+/// Largest request accepted by the product forms, which can carry several images
+pub const MAX_PRODUCT_UPLOAD_BYTES: usize = 25 * 1024 * 1024;
+
+/// Display version of a product, with image URLs normalized
+pub fn convert_to_display(product: Product, categories: &[Category]) -> ProductDisplay {
+    let category_name = product
+        .category_id
+        .and_then(|id| categories.iter().find(|category| category.id == id))
+        .map(|category| category.name.clone())
+        .unwrap_or_default();
+    let low_stock = product.is_low_stock();
+    let images: Vec<ProductImageDisplay> = product
+        .gallery()
+        .into_iter()
+        .map(|image| {
+            let image = normalize_image(image);
+            ProductImageDisplay {
+                thumbnail_url: image.thumbnail_url.unwrap_or_else(|| image.url.clone()),
+                url: image.url,
+            }
+        })
+        .collect();
+    let image_url = normalize_image_url(&product.image_url);
+    let options = product
+        .options
+        .iter()
+        .map(|option| ProductOptionDisplay {
+            name: option.name.clone(),
+            values: option.values.join(", "),
+        })
+        .collect();
+    let variants = product
+        .variants
+        .iter()
+        .map(|variant| ProductVariantDisplay {
+            sku: variant.sku.clone(),
+            label: variant.label(),
+            price_delta: variant.price_delta.to_decimal_string(),
+            formatted_price: product
+                .price
+                .checked_add(variant.price_delta)
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+            quantity: variant.quantity,
+        })
+        .collect();
+
+    ProductDisplay {
+        id: product.id.to_hex(),
+        thumbnail_url: images
+            .first()
+            .map(|image| image.thumbnail_url.clone())
+            .unwrap_or_else(|| image_url.clone()),
+        images,
+        image_url,
+        name: product.name,
+        price: product.price.to_decimal_string(),
+        formatted_price: product.price.to_string(),
+        currency_symbol: product.price.currency.symbol().to_string(),
+        quantity: product.quantity,
+        description: product.description,
+        adoptable: product.adoptable,
+        category_name,
+        tags: product.tags,
+        low_stock,
+        reorder_threshold: product.reorder_threshold.map(|threshold| threshold.to_string()).unwrap_or_default(),
+        sku: product.sku.unwrap_or_default(),
+        options,
+        variants,
     }
+}
 
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
+/// Highest price a product or variant can have, e.g. £999,999.99
+pub const MAX_PRICE_MINOR_UNITS: i64 = 99_999_999;
+
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 40;
+
+/// Normalise comma-separated tags: trimmed, lowercase, single-spaced, no repeats
+pub fn parse_tags(input: &str) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in input.split(',') {
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        if tag.is_empty() || tags.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(format!("Tags must be {} characters or fewer", MAX_TAG_LENGTH));
+        }
+        tags.push(tag);
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("A product can have at most {} tags", MAX_TAGS));
     }
+    Ok(tags)
+}
 
-    // Get all products (including out of stock and adoptables)
-    match collection.find(doc! {}).await {
-        Ok(cursor) => {
-            let products: Vec<ProductDisplay> = cursor
-                .try_collect::<Vec<Product>>()
-                .await
-                .unwrap_or_default()
-                .into_iter()
-                .map(|p| ProductDisplay {
-                    id: p.id.to_hex(),
-                    name: p.name,
-                    image_url: normalize_image_url(&p.image_url),
-                    price: p.price,
-                    quantity: p.quantity,
-                    description: p.description,
-                    adoptable: p.adoptable,
-                })
-                .collect();
+/// The category and tags chosen on the product form
+fn read_classification(
+    form: &EditProductForm,
+    categories: &[Category],
+) -> Result<(Option<ObjectId>, Vec<String>), String> {
+    let category_id = match form.category.trim() {
+        "" => None,
+        value => {
+            let id = ObjectId::parse_str(value)
+                .ok()
+                .filter(|id| categories.iter().any(|category| category.id == *id))
+                .ok_or_else(|| "Please choose a category from the list".to_string())?;
+            Some(id)
+        }
+    };
+    Ok((category_id, parse_tags(&form.tags)?))
+}
 
-            let template = ProductManagementTemplate {
-                products,
-                user_state,
-                success_message: String::new(),
-                error_message: String::new(),
-            };
+/// Changes to a product's gallery sent by the edit form
+#[derive(Debug, Clone, Default)]
+pub struct GalleryEdit {
+    /// Existing image URLs in the order they should appear
+    pub order: Vec<String>,
+    pub remove: Vec<String>,
+    /// (existing URL, its uploaded replacement)
+    pub replace: Vec<(String, ProductImage)>,
+    /// Newly uploaded images, added at the end
+    pub added: Vec<ProductImage>,
+}
 
-            Html(template.render().unwrap()).into_response()
+impl GalleryEdit {
+    /// The gallery after this edit. Only images already in `current` can be kept
+    /// or moved, so a form can't point a product at some other file.
+    pub fn apply(&self, current: &[ProductImage]) -> Result<Vec<ProductImage>, String> {
+        let current_urls = current.iter().map(|image| &image.url);
+        let mut images: Vec<ProductImage> = Vec::new();
+        for url in self.order.iter().chain(current_urls) {
+            if let Some(image) = current.iter().find(|image| image.url == *url)
+                && !images.iter().any(|kept| kept.url == *url)
+            {
+                images.push(image.clone());
+            }
         }
-        Err(e) => {
-            let template = ProductManagementTemplate {
-                products: vec![],
-                user_state,
-                success_message: String::new(),
-                error_message: format!("Database error: {}", e),
-            };
+        images.retain(|image| !self.remove.contains(&image.url));
+        for (old, new) in &self.replace {
+            if let Some(slot) = images.iter_mut().find(|image| image.url == *old) {
+                *slot = new.clone();
+            }
+        }
+        images.extend(self.added.iter().cloned());
 
-            Html(template.render().unwrap()).into_response()
+        if images.is_empty() {
+            return Err("A product needs at least one image".to_string());
         }
+        Ok(images)
     }
 }
 
-/// Show create form for a new product
-pub async fn show_create_form(
-    auth: AppAuthSession,
-) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
+const MAX_OPTIONS: usize = 3;
+const MAX_OPTION_NAME_LENGTH: usize = 50;
+const MAX_VARIANTS: usize = 100;
+const MAX_SKU_LENGTH: usize = 64;
+
+/// SKUs are up to 64 letters, numbers, - or _
+pub fn validate_sku(sku: &str) -> Result<(), String> {
+    if sku.len() > MAX_SKU_LENGTH || !sku.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!(
+            "SKU {} is invalid - use up to {} letters, numbers, - or _",
+            sku, MAX_SKU_LENGTH
+        ));
     }
+    Ok(())
+}
 
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
+/// The optional product-level SKU from a form or import row
+pub fn parse_product_sku(input: &str) -> Result<Option<String>, String> {
+    match input.trim() {
+        "" => Ok(None),
+        sku => validate_sku(sku).map(|_| Some(sku.to_string())),
     }
+}
 
-    let template = CreateProductTemplate {
-        user_state,
-        error: String::new(),
-    };
+/// The optional reorder threshold from the product form
+pub fn parse_reorder_threshold(input: &str) -> Result<Option<i32>, String> {
+    match input.trim() {
+        "" => Ok(None),
+        threshold => threshold
+            .parse::<i32>()
+            .ok()
+            .filter(|threshold| (0..=MAX_STOCK).contains(threshold))
+            .map(Some)
+            .ok_or_else(|| "Reorder threshold must be a whole number from 0 to 999,999".to_string()),
+    }
+}
 
-    Html(template.render().unwrap()).into_response()
+/// A product other than `except` that already uses one of `skus`, on the
+/// product itself or one of its variants
+pub async fn sku_owner(
+    collection: &Collection<Product>,
+    skus: &[&str],
+    except: Option<ObjectId>,
+) -> mongodb::error::Result<Option<Product>> {
+    if skus.is_empty() {
+        return Ok(None);
+    }
+    let mut filter = doc! { "$or": [{ "sku": { "$in": skus } }, { "variants.sku": { "$in": skus } }] };
+    if let Some(id) = except {
+        filter.insert("_id", doc! { "$ne": id });
+    }
+    collection.find_one(filter).await
 }
 
-/// Handle product creation
-pub async fn create_product(
-    Extension(collection): Extension<Collection<Product>>,
-    auth: AppAuthSession,
-    mut multipart: Multipart,
-) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
+/// Option axes and variant rows sent by the edit form. Each list has one
+/// entry per row, in page order.
+#[derive(Debug, Clone, Default)]
+pub struct VariantEdit {
+    pub option_names: Vec<String>,
+    /// Comma-separated values for the option name at the same index
+    pub option_values: Vec<String>,
+    /// A variant's option values separated by "/", e.g. "Red / Large"
+    pub combinations: Vec<String>,
+    pub skus: Vec<String>,
+    pub price_deltas: Vec<String>,
+    pub quantities: Vec<String>,
+}
 
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
+impl VariantEdit {
+    /// Validate the rows into option axes and variants. Blank rows are
+    /// ignored; no variant may bring `base_price` below zero.
+    pub fn parse(&self, base_price: Money) -> Result<(Vec<ProductOption>, Vec<ProductVariant>), String> {
+        let options = self.parse_options()?;
+        let variants = self.parse_variants(&options, base_price)?;
+        Ok((options, variants))
     }
 
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
+    fn parse_options(&self) -> Result<Vec<ProductOption>, String> {
+        let mut options: Vec<ProductOption> = Vec::new();
+        for (i, name) in self.option_names.iter().enumerate() {
+            let name = name.trim();
+            let mut values: Vec<String> = Vec::new();
+            for value in self.option_values.get(i).map(String::as_str).unwrap_or("").split(',') {
+                let value = value.trim();
+                if value.is_empty() || values.iter().any(|seen| seen.eq_ignore_ascii_case(value)) {
+                    continue;
+                }
+                if value.contains('/') {
+                    return Err(format!("Option values can't contain \"/\" ({})", value));
+                }
+                values.push(value.to_string());
+            }
+
+            match (name.is_empty(), values.is_empty()) {
+                (true, true) => continue,
+                (true, false) => return Err("Each option needs a name, e.g. Colour".to_string()),
+                (false, true) => return Err(format!("Option {} needs at least one value", name)),
+                (false, false) => {}
+            }
+            if name.chars().count() > MAX_OPTION_NAME_LENGTH {
+                return Err(format!("Option names must be {} characters or fewer", MAX_OPTION_NAME_LENGTH));
+            }
+            if options.iter().any(|option| option.name.eq_ignore_ascii_case(name)) {
+                return Err(format!("There's more than one option called {}", name));
+            }
+            options.push(ProductOption {
+                name: name.to_string(),
+                values,
+            });
+        }
+
+        if options.len() > MAX_OPTIONS {
+            return Err(format!("A product can have at most {} options", MAX_OPTIONS));
+        }
+        Ok(options)
     }
 
-    // Parse multipart form data
-    let mut name = String::new();
-    let mut price = String::new();
-    let mut quantity = String::new();
-    let mut description = String::new();
-    let mut adoptable = false;
-    let mut image_filename = None;
+    fn parse_variants(&self, options: &[ProductOption], base_price: Money) -> Result<Vec<ProductVariant>, String> {
+        let field = |list: &[String], i: usize| list.get(i).map(|value| value.trim().to_string()).unwrap_or_default();
+        let rows = [&self.combinations, &self.skus, &self.price_deltas, &self.quantities]
+            .iter()
+            .map(|list| list.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut variants: Vec<ProductVariant> = Vec::new();
+        for i in 0..rows {
+            let combination = field(&self.combinations, i);
+            let sku = field(&self.skus, i);
+            let price_delta = field(&self.price_deltas, i);
+            let quantity = field(&self.quantities, i);
+            if [&combination, &sku, &price_delta, &quantity].iter().all(|value| value.is_empty()) {
+                continue;
+            }
+            if options.is_empty() {
+                return Err("Add an option such as colour or size before adding variants".to_string());
+            }
 
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        let field_name = field.name().unwrap_or("").to_string();
-        
-        match field_name.as_str() {
-            "name" => {
-                name = field.text().await.unwrap_or_default();
+            let option_values = parse_combination(&combination, options)?;
+            let label = option_values.join(" / ");
+            if variants.iter().any(|variant| variant.option_values == option_values) {
+                return Err(format!("{} is listed more than once", label));
             }
-            "price" => {
-                price = field.text().await.unwrap_or_default();
+
+            if sku.is_empty() {
+                return Err(format!("Variant {} needs a SKU", label));
             }
-            "quantity" => {
-                quantity = field.text().await.unwrap_or_default();
+            validate_sku(&sku)?;
+            if variants.iter().any(|variant| variant.sku.eq_ignore_ascii_case(&sku)) {
+                return Err(format!("SKU {} is used more than once", sku));
+            }
+
+            let price_delta = match price_delta.as_str() {
+                "" => Money::zero(base_price.currency),
+                value => Money::parse(value, base_price.currency)
+                    .map_err(|_| format!("The price change for {} must be an amount, e.g. 1.50", label))?,
+            };
+            let in_range = base_price
+                .checked_add(price_delta)
+                .is_some_and(|price| (0..=MAX_PRICE_MINOR_UNITS).contains(&price.minor_units));
+            if !in_range {
+                return Err(format!(
+                    "The price change for {} must keep its price between {} and {}",
+                    label,
+                    Money::zero(base_price.currency),
+                    Money::new(MAX_PRICE_MINOR_UNITS, base_price.currency)
+                ));
+            }
+
+            let quantity = quantity
+                .parse::<i32>()
+                .ok()
+                .filter(|quantity| (0..=MAX_STOCK).contains(quantity))
+                .ok_or_else(|| format!("Stock for {} must be a whole number from 0 to 999,999", label))?;
+
+            variants.push(ProductVariant {
+                sku,
+                option_values,
+                price_delta,
+                quantity,
+            });
+        }
+
+        if !options.is_empty() && variants.is_empty() {
+            return Err("Add a variant for each combination you sell, or remove the options".to_string());
+        }
+        if variants.len() > MAX_VARIANTS {
+            return Err(format!("A product can have at most {} variants", MAX_VARIANTS));
+        }
+        Ok(variants)
+    }
+}
+
+/// Match "red / large" against the option axes, giving each value as the
+/// option spells it
+fn parse_combination(combination: &str, options: &[ProductOption]) -> Result<Vec<String>, String> {
+    let parts: Vec<&str> = combination.split('/').map(str::trim).collect();
+    if parts.len() != options.len() || parts.iter().any(|part| part.is_empty()) {
+        let names: Vec<&str> = options.iter().map(|option| option.name.as_str()).collect();
+        return Err(format!(
+            "Variant \"{}\" needs one value for each option ({})",
+            combination,
+            names.join(" / ")
+        ));
+    }
+    parts
+        .iter()
+        .zip(options)
+        .map(|(part, option)| {
+            option
+                .values
+                .iter()
+                .find(|value| value.eq_ignore_ascii_case(part))
+                .cloned()
+                .ok_or_else(|| format!("{} isn't one of the {} values", part, option.name))
+        })
+        .collect()
+}
+
+/// Stock for an edited product. Stock only moves through the ledger, so
+/// variants the product already had keep their current stock and only new
+/// variants take what was entered.
+fn carry_over_stock(current: &Product, variants: &mut [ProductVariant], entered_quantity: i32) -> i32 {
+    for variant in variants.iter_mut() {
+        if let Some(existing) = current
+            .variants
+            .iter()
+            .find(|existing| existing.sku.eq_ignore_ascii_case(&variant.sku))
+        {
+            variant.quantity = existing.quantity;
+        }
+    }
+    if !variants.is_empty() {
+        // Stock is tracked per variant; the product's quantity is their total
+        variants.iter().map(|variant| variant.quantity).sum()
+    } else if current.variants.is_empty() {
+        current.quantity
+    } else {
+        // Variants removed, so the product's own stock starts from what was entered
+        entered_quantity
+    }
+}
+
+/// URL path product images are served under
+const PRODUCT_IMAGES_PATH: &str = "/product-images/";
+
+/// Store one processed variant, returning its URL
+async fn write_variant(
+    storage: &dyn Storage,
+    stem: &str,
+    suffix: &str,
+    variant: &EncodedImage,
+) -> Result<String, String> {
+    let filename = format!("{}{}.{}", stem, suffix, variant.extension);
+    storage.put(&filename, variant.bytes.clone()).await.map_err(|e| {
+        error!("Failed to store product image {}: {}", filename, e);
+        "Failed to save image file".to_string()
+    })?;
+    Ok(format!("{}{}", PRODUCT_IMAGES_PATH, filename))
+}
+
+/// Process an uploaded image and save the full-size and thumbnail versions
+async fn save_uploaded_image(storage: &dyn Storage, data: Vec<u8>) -> Result<ProductImage, String> {
+    let processed = tokio::task::spawn_blocking(move || image_processing::process_upload(&data))
+        .await
+        .map_err(|_| "Failed to process image".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let stem = uuid::Uuid::new_v4().to_string();
+    let url = write_variant(storage, &stem, "", &processed.full).await?;
+    let thumbnail_url = match write_variant(storage, &stem, "-thumb", &processed.thumbnail).await {
+        Ok(thumbnail_url) => thumbnail_url,
+        Err(e) => {
+            delete_image_files(storage, image_cleanup::file_name_from_url(&url).map(str::to_string)).await;
+            return Err(e);
+        }
+    };
+    Ok(ProductImage {
+        url,
+        thumbnail_url: Some(thumbnail_url),
+        width: Some(processed.full.width),
+        height: Some(processed.full.height),
+    })
+}
+
+/// Matches a product only while `fields` are as they were in `stored`, the
+/// document it was read from. Missing fields match as null.
+pub fn unchanged_filter(stored: &Document, fields: &[&str]) -> Document {
+    let field = |name: &str| stored.get(name).cloned().unwrap_or(Bson::Null);
+    let mut filter = doc! { "_id": field("_id") };
+    for name in fields {
+        filter.insert(*name, field(name));
+    }
+    filter
+}
+
+/// Products matching `filter` along with the documents they were read from,
+/// so updates can be guarded on exactly what was stored
+pub async fn load_stored_products(
+    collection: &Collection<Product>,
+    filter: Document,
+) -> mongodb::error::Result<(Vec<Product>, Vec<Document>)> {
+    let stored: Vec<Document> = collection.clone_with_type::<Document>().find(filter).await?.try_collect().await?;
+    let products = stored
+        .iter()
+        .map(|document| bson::from_document(document.clone()))
+        .collect::<Result<Vec<Product>, _>>()?;
+    Ok((products, stored))
+}
+
+/// Delete stored product image files. Failures are only logged; `clean-images`
+/// finds anything left behind.
+async fn delete_image_files(storage: &dyn Storage, names: impl IntoIterator<Item = String>) {
+    for name in names {
+        if let Err(e) = storage.delete(&name).await {
+            warn!("Failed to delete product image {}: {}", name, e);
+        }
+    }
+}
+
+/// Delete every image file a deleted product used
+pub async fn delete_product_images(storage: &dyn Storage, product: &Product) {
+    delete_image_files(storage, image_cleanup::referenced_files(std::slice::from_ref(product))).await;
+}
+
+/// Files the product used before an edit that it no longer uses
+fn unused_image_files(before: &Product, after: &Product) -> Vec<String> {
+    let kept = image_cleanup::referenced_files(std::slice::from_ref(after));
+    let mut unused: Vec<String> = image_cleanup::referenced_files(std::slice::from_ref(before))
+        .into_iter()
+        .filter(|name| !kept.contains(name))
+        .collect();
+    unused.sort();
+    unused
+}
+
+/// Image uploads from the product form, held until the rest of the form is valid
+#[derive(Debug, Default)]
+pub struct PendingUploads {
+    pub added: Vec<Vec<u8>>,
+    /// (existing URL, its replacement's bytes)
+    pub replace: Vec<(String, Vec<u8>)>,
+}
+
+impl PendingUploads {
+    /// Process and store the uploads into `gallery`. If one fails, the ones
+    /// already stored are deleted again.
+    async fn store(self, storage: &dyn Storage, gallery: &mut GalleryEdit) -> Result<(), String> {
+        for data in self.added {
+            match save_uploaded_image(storage, data).await {
+                Ok(image) => gallery.added.push(image),
+                Err(e) => {
+                    discard_uploads(storage, gallery).await;
+                    return Err(e);
+                }
             }
-            "description" => {
-                description = field.text().await.unwrap_or_default();
+        }
+        for (old, data) in self.replace {
+            match save_uploaded_image(storage, data).await {
+                Ok(image) => gallery.replace.push((old, image)),
+                Err(e) => {
+                    discard_uploads(storage, gallery).await;
+                    return Err(e);
+                }
             }
+        }
+        Ok(())
+    }
+}
+
+/// Delete the files stored for a gallery edit that wasn't saved
+async fn discard_uploads(storage: &dyn Storage, gallery: &GalleryEdit) {
+    let files: HashSet<String> = gallery
+        .added
+        .iter()
+        .chain(gallery.replace.iter().map(|(_, image)| image))
+        .flat_map(|image| [Some(&image.url), image.thumbnail_url.as_ref()])
+        .flatten()
+        .filter_map(|url| image_cleanup::file_name_from_url(url).map(str::to_string))
+        .collect();
+    delete_image_files(storage, files).await;
+}
+
+/// Read a file field's upload, if one was chosen
+async fn read_image_field(field: Field<'_>) -> Result<Option<Vec<u8>>, String> {
+    if field.file_name().is_none_or(str::is_empty) {
+        return Ok(None);
+    }
+    let data = field
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read image upload: {}", e))?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(data.to_vec()))
+}
+
+/// Multipart field name for replacing the gallery image at `url`
+const REPLACE_FIELD_PREFIX: &str = "replace:";
+
+/// Read the create/edit product form: text fields plus gallery and variant
+/// changes. Uploads are only read here; they're stored once the form is valid.
+async fn read_product_form(
+    mut multipart: Multipart,
+) -> Result<(EditProductForm, GalleryEdit, PendingUploads, VariantEdit), String> {
+    let mut form = EditProductForm::default();
+    let mut gallery = GalleryEdit::default();
+    let mut uploads = PendingUploads::default();
+    let mut variants = VariantEdit::default();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| format!("Invalid form data: {}", e))?
+    {
+        let field_name = field.name().unwrap_or("").to_string();
+
+        match field_name.as_str() {
+            "name" => form.name = field.text().await.unwrap_or_default(),
+            "price" => form.price = field.text().await.unwrap_or_default(),
+            "quantity" => form.quantity = field.text().await.unwrap_or_default(),
+            "description" => form.description = field.text().await.unwrap_or_default(),
+            "category" => form.category = field.text().await.unwrap_or_default(),
+            "tags" => form.tags = field.text().await.unwrap_or_default(),
+            "sku" => form.sku = field.text().await.unwrap_or_default(),
+            "reorder_threshold" => form.reorder_threshold = field.text().await.unwrap_or_default(),
             "adoptable" => {
                 let value = field.text().await.unwrap_or_default();
-                adoptable = value == "true" || value == "on";
+                if value == "true" || value == "on" {
+                    form.adoptable = Some("on".to_string());
+                }
             }
+            "gallery" => gallery.order.push(field.text().await.unwrap_or_default()),
+            "remove_image" => gallery.remove.push(field.text().await.unwrap_or_default()),
+            "option_name" => variants.option_names.push(field.text().await.unwrap_or_default()),
+            "option_values" => variants.option_values.push(field.text().await.unwrap_or_default()),
+            "variant_options" => variants.combinations.push(field.text().await.unwrap_or_default()),
+            "variant_sku" => variants.skus.push(field.text().await.unwrap_or_default()),
+            "variant_price_delta" => variants.price_deltas.push(field.text().await.unwrap_or_default()),
+            "variant_quantity" => variants.quantities.push(field.text().await.unwrap_or_default()),
             "image" => {
-                if let Some(filename) = field.file_name() {
-                    if !filename.is_empty() {
-                        // Capture filename before consuming field
-                        let filename = filename.to_string();
-                        let data = field.bytes().await.unwrap_or_default();
-                        
-                        // Generate unique filename
-                        let extension = StdPath::new(&filename)
-                            .extension()
-                            .and_then(|s| s.to_str())
-                            .unwrap_or("jpg");
-                        let unique_filename = format!("{}.{}", uuid::Uuid::new_v4(), extension);
-                        
-                        // Save file to product-images directory
-                        let file_path = format!("product-images/{}", unique_filename);
-                        
-                        match fs::write(&file_path, &data).await {
-                            Ok(_) => {
-                                image_filename = Some(format!("/product-images/{}", unique_filename));
-                            }
-                            Err(_) => {
-                                return show_create_form_with_error(user_state, "Failed to save image file".to_string()).await;
-                            }
-                        }
-                    }
+                if let Some(data) = read_image_field(field).await? {
+                    uploads.added.push(data);
+                }
+            }
+            name if name.starts_with(REPLACE_FIELD_PREFIX) => {
+                let old = name[REPLACE_FIELD_PREFIX.len()..].to_string();
+                if let Some(data) = read_image_field(field).await? {
+                    uploads.replace.push((old, data));
                 }
             }
             _ => {}
         }
     }
 
+    Ok((form, gallery, uploads, variants))
+}
+
+/// The product fields recorded in the audit log
+pub fn audit_fields(product: &Product) -> Vec<(&'static str, String)> {
+    vec![
+        ("name", product.name.clone()),
+        ("price", product.price.to_string()),
+        ("quantity", product.quantity.to_string()),
+        ("description", product.description.clone()),
+        ("adoptable", product.adoptable.to_string()),
+        ("category", product.category_id.map(|id| id.to_hex()).unwrap_or_default()),
+        ("tags", product.tags.join(", ")),
+        ("sku", product.sku.clone().unwrap_or_default()),
+        (
+            "reorder_threshold",
+            product.reorder_threshold.map(|threshold| threshold.to_string()).unwrap_or_default(),
+        ),
+        (
+            "options",
+            product
+                .options
+                .iter()
+                .map(|option| format!("{}: {}", option.name, option.values.join(", ")))
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        (
+            "variants",
+            product
+                .variants
+                .iter()
+                .map(|variant| {
+                    format!(
+                        "{} ({}) {} x{}",
+                        variant.sku,
+                        variant.label(),
+                        variant.price_delta,
+                        variant.quantity
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        ("image_url", product.image_url.clone()),
+        (
+            "images",
+            product
+                .gallery()
+                .iter()
+                .map(|image| image.url.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    ]
+}
+
+/// Serve a product image from storage. Public, like the storefront's images;
+/// file names are random and never reused, so they can be cached for good.
+pub async fn serve_product_image(
+    Path(filename): Path<String>,
+    Extension(stores): Extension<ImageStores>,
+) -> Response {
+    match stores.product_images.get(&filename).await {
+        Ok(Some(bytes)) => (
+            [
+                (header::CONTENT_TYPE, storage::content_type_for(&filename)),
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(storage::StorageError::InvalidKey(_)) => StatusCode::BAD_REQUEST.into_response(),
+        Err(e) => {
+            error!("Failed to read product image {}: {}", filename, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Products per page on the product list
+const PAGE_SIZE: u32 = 25;
+
+const STOCK_FILTERS: [(&str, &str); 3] = [
+    ("in_stock", "In stock"),
+    ("low_stock", "Low stock"),
+    ("out_of_stock", "Out of stock"),
+];
+const TYPE_FILTERS: [(&str, &str); 2] = [("adoptable", "Adoptables"), ("product", "Products")];
+const SORT_OPTIONS: [(&str, &str); 6] = [
+    ("name", "Name (A–Z)"),
+    ("name_desc", "Name (Z–A)"),
+    ("price", "Price (low–high)"),
+    ("price_desc", "Price (high–low)"),
+    ("quantity", "Stock (low–high)"),
+    ("quantity_desc", "Stock (high–low)"),
+];
+
+/// Options for a fixed <select>, marking `selected`
+fn select_options(choices: &[(&str, &str)], selected: Option<&str>) -> Vec<SelectOption> {
+    let selected = selected.unwrap_or("").trim();
+    choices
+        .iter()
+        .map(|(value, label)| SelectOption {
+            value: value.to_string(),
+            label: label.to_string(),
+            selected: *value == selected,
+        })
+        .collect()
+}
+
+/// Escape text so it matches literally inside a Mongo regular expression
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Mongo filter for the product list's search box and filters
+pub fn product_filter(params: &ProductQueryParams, categories: &[Category]) -> Result<Document, String> {
+    let mut filter = doc! {};
+    if let Some(search) = params.q.as_deref().map(str::trim).filter(|search| !search.is_empty()) {
+        let pattern = doc! { "$regex": escape_regex(search), "$options": "i" };
+        filter.insert(
+            "$or",
+            vec![doc! { "name": pattern.clone() }, doc! { "description": pattern }],
+        );
+    }
+    match params.category.as_deref().map(str::trim) {
+        None | Some("") => {}
+        // Matches products where the field is missing as well as null
+        Some("none") => {
+            filter.insert("category_id", Bson::Null);
+        }
+        Some(slug) => {
+            let category = categories
+                .iter()
+                .find(|category| category.slug == slug)
+                .ok_or_else(|| "That category no longer exists".to_string())?;
+            filter.insert("category_id", category.id);
+        }
+    }
+    if let Some(tag) = params.tag.as_deref().map(str::trim).filter(|tag| !tag.is_empty()) {
+        filter.insert("tags", tag.to_lowercase());
+    }
+    match params.stock.as_deref().map(str::trim) {
+        Some("in_stock") => {
+            filter.insert("quantity", doc! { "$gt": 0 });
+        }
+        Some("out_of_stock") => {
+            filter.insert("quantity", doc! { "$lte": 0 });
+        }
+        Some("low_stock") => {
+            filter.extend(low_stock::filter());
+        }
+        _ => {}
+    }
+    match params.kind.as_deref().map(str::trim) {
+        Some("adoptable") => {
+            filter.insert("adoptable", true);
+        }
+        Some("product") => {
+            filter.insert("adoptable", false);
+        }
+        _ => {}
+    }
+    Ok(filter)
+}
+
+/// Mongo sort for the product list, by name unless asked otherwise. `_id`
+/// breaks ties so pages don't overlap.
+pub fn product_sort(sort: Option<&str>) -> Document {
+    let (field, direction) = match sort.unwrap_or("").trim() {
+        "name_desc" => ("name", -1),
+        "price" => ("price.minor_units", 1),
+        "price_desc" => ("price.minor_units", -1),
+        "quantity" => ("quantity", 1),
+        "quantity_desc" => ("quantity", -1),
+        _ => ("name", 1),
+    };
+    doc! { field: direction, "_id": 1 }
+}
+
+/// Every tag in use, alphabetically
+async fn all_tags(collection: &Collection<Product>) -> Vec<String> {
+    match collection.distinct("tags", doc! {}).await {
+        Ok(values) => {
+            let mut tags: Vec<String> = values
+                .into_iter()
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect();
+            tags.sort();
+            tags
+        }
+        Err(e) => {
+            error!("Failed to load product tags: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// List products for admin management, a page at a time, with search, filters and sorting
+pub async fn list_products(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Query(params): Query<ProductQueryParams>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let categories = load_categories(&categories_collection).await;
+
+    let selected_category = params.category.as_deref().unwrap_or("").trim();
+    let mut category_filter = vec![SelectOption {
+        value: "none".to_string(),
+        label: "Uncategorised".to_string(),
+        selected: selected_category == "none",
+    }];
+    category_filter.extend(categories.iter().map(|category| SelectOption {
+        value: category.slug.clone(),
+        label: category.name.clone(),
+        selected: category.slug == selected_category,
+    }));
+
+    let selected_tag = params.tag.as_deref().unwrap_or("").trim().to_lowercase();
+    let tag_filter = all_tags(&collection)
+        .await
+        .into_iter()
+        .map(|tag| SelectOption {
+            selected: tag == selected_tag,
+            value: tag.clone(),
+            label: tag,
+        })
+        .collect();
+
+    let success_message = match params.success.as_deref() {
+        Some("created") => "Product created",
+        Some("updated") => "Product updated",
+        Some("imported") => "Import applied",
+        _ => "",
+    };
+
+    // Get a page of matching products (including out of stock and adoptables)
+    let page = params.page.unwrap_or(1).max(1);
+    let skip = ((page - 1) * PAGE_SIZE) as u64;
+    let collation = Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build();
+    let result = match product_filter(&params, &categories) {
+        Ok(filter) => match collection.count_documents(filter.clone()).await {
+            Ok(total) => match collection
+                .find(filter)
+                .sort(product_sort(params.sort.as_deref()))
+                .collation(collation)
+                .skip(skip)
+                .limit(PAGE_SIZE as i64)
+                .await
+            {
+                Ok(cursor) => cursor
+                    .try_collect::<Vec<Product>>()
+                    .await
+                    .map(|products| (products, total))
+                    .map_err(|e| format!("Database error: {}", e)),
+                Err(e) => Err(format!("Database error: {}", e)),
+            },
+            Err(e) => Err(format!("Database error: {}", e)),
+        },
+        Err(error_message) => Err(error_message),
+    };
+    let (products, total, error_message) = match result {
+        Ok((products, total)) => (products, total, String::new()),
+        Err(error_message) => (vec![], 0, error_message),
+    };
+
+    let search = params.q.as_deref().unwrap_or("").trim().to_string();
+    let stock_filter = select_options(&STOCK_FILTERS, params.stock.as_deref());
+    let type_filter = select_options(&TYPE_FILTERS, params.kind.as_deref());
+    let is_filtered = !search.is_empty()
+        || !selected_category.is_empty()
+        || !selected_tag.is_empty()
+        || stock_filter.iter().chain(&type_filter).any(|option| option.selected);
+
+    let template = ProductManagementTemplate {
+        products: products
+            .into_iter()
+            .map(|product| convert_to_display(product, &categories))
+            .collect(),
+        pagination: create_pagination_info(page, PAGE_SIZE, total),
+        user_state,
+        success_message: success_message.to_string(),
+        error_message,
+        search,
+        category_filter,
+        tag_filter,
+        stock_filter,
+        type_filter,
+        sort_options: select_options(&SORT_OPTIONS, params.sort.as_deref()),
+        bulk_categories: category_options(&categories, None),
+        is_filtered,
+        query_string: params.query_string(),
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Show create form for a new product
+pub async fn show_create_form(
+    Extension(categories_collection): Extension<Collection<Category>>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let categories = load_categories(&categories_collection).await;
+    let template = CreateProductTemplate {
+        user_state,
+        categories: category_options(&categories, None),
+        error: String::new(),
+    };
+
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Handle product creation
+#[allow(clippy::too_many_arguments)] // One per extractor
+pub async fn create_product(
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    Extension(stores): Extension<ImageStores>,
+    actor: Actor,
+    user_state: UserState,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    // Variants are added from the edit form once the product exists
+    let storage = stores.product_images.as_ref();
+    let (form, mut gallery, uploads, _) = match read_product_form(multipart).await {
+        Ok(parsed) => parsed,
+        Err(error_msg) => {
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+    };
+
     // Validate required fields
-    if name.trim().is_empty() {
-        return show_create_form_with_error(user_state, "Product name is required".to_string()).await;
+    if form.name.trim().is_empty() {
+        return show_create_form_with_error(
+            &categories_collection,
+            user_state,
+            "Product name is required".to_string(),
+        )
+        .await;
     }
 
-    if price.trim().is_empty() {
-        return show_create_form_with_error(user_state, "Price is required".to_string()).await;
+    if form.price.trim().is_empty() {
+        return show_create_form_with_error(
+            &categories_collection,
+            user_state,
+            "Price is required".to_string(),
+        )
+        .await;
     }
 
-    if quantity.trim().is_empty() {
-        return show_create_form_with_error(user_state, "Quantity is required".to_string()).await;
+    if form.quantity.trim().is_empty() {
+        return show_create_form_with_error(
+            &categories_collection,
+            user_state,
+            "Quantity is required".to_string(),
+        )
+        .await;
     }
 
-    if description.trim().is_empty() {
-        return show_create_form_with_error(user_state, "Description is required".to_string()).await;
+    if form.description.trim().is_empty() {
+        return show_create_form_with_error(
+            &categories_collection,
+            user_state,
+            "Description is required".to_string(),
+        )
+        .await;
     }
 
-    if image_filename.is_none() {
-        return show_create_form_with_error(user_state, "Product image is required".to_string()).await;
+    if uploads.added.is_empty() {
+        return show_create_form_with_error(
+            &categories_collection,
+            user_state,
+            "Product image is required".to_string(),
+        )
+        .await;
     }
 
     // Create form struct for validation
-    let form = CreateProductForm {
-        name: name.clone(),
-        price: price.clone(),
-        quantity: quantity.clone(),
-        description: description.clone(),
-        adoptable: if adoptable { Some("on".to_string()) } else { None },
+    let create_form = CreateProductForm {
+        name: form.name.clone(),
+        price: form.price.clone(),
+        quantity: form.quantity.clone(),
+        description: form.description.clone(),
+        adoptable: form.adoptable.clone(),
     };
 
     // Validate using existing validation function
-    let validation_result = validate_create_product_form(&form);
-    if let Err(error_msg) = validation_result {
-        return show_create_form_with_error(user_state, error_msg).await;
+    let (validated_price, validated_quantity) = match validate_create_product_form(&create_form) {
+        Ok(validated) => validated,
+        Err(error_msg) => {
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+    };
+    let categories = load_categories(&categories_collection).await;
+    let (category_id, tags) = match read_classification(&form, &categories) {
+        Ok(classification) => classification,
+        Err(error_msg) => {
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+    };
+    let sku = match parse_product_sku(&form.sku) {
+        Ok(sku) => sku,
+        Err(error_msg) => {
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+    };
+    let reorder_threshold = match parse_reorder_threshold(&form.reorder_threshold) {
+        Ok(threshold) => threshold,
+        Err(error_msg) => {
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+    };
+    let skus: Vec<&str> = sku.iter().map(String::as_str).collect();
+    match sku_owner(&collection, &skus, None).await {
+        Ok(None) => {}
+        Ok(Some(other)) => {
+            let error_msg = format!("{} already uses SKU {}", other.name, form.sku.trim());
+            return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+        }
+        Err(e) => {
+            return show_create_form_with_error(&categories_collection, user_state, format!("Database error: {}", e))
+                .await;
+        }
     }
 
-    let (_validated_price, validated_quantity) = validation_result.unwrap();
+    if let Err(error_msg) = uploads.store(storage, &mut gallery).await {
+        return show_create_form_with_error(&categories_collection, user_state, error_msg).await;
+    }
 
-    // Create new product
+    // Create new product; the first uploaded image is the primary one
+    let images = gallery.added.clone();
     let new_product = Product {
         id: ObjectId::new(),
-        name,
-        image_url: image_filename.unwrap(),
-        price,
+        name: form.name,
+        image_url: images[0].url.clone(),
+        price: validated_price,
         quantity: validated_quantity,
-        description,
-        adoptable,
+        description: form.description,
+        adoptable: form.adoptable.is_some(),
+        images,
+        category_id,
+        tags,
+        sku,
+        reorder_threshold,
+        options: vec![],
+        variants: vec![],
     };
 
     // Insert into database
     match collection.insert_one(&new_product).await {
         Ok(_) => {
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::PRODUCT_CREATED,
+                    Some(new_product.id.to_hex()),
+                    audit::diff(&[], &audit_fields(&new_product)),
+                )
+                .await;
+            stock_ledger
+                .record_changes(&actor, None, &new_product, MovementKind::Initial, "Product created")
+                .await;
             Redirect::to("/products?success=created").into_response()
         }
         Err(e) => {
+            discard_uploads(storage, &gallery).await;
             show_create_form_with_error(
+                &categories_collection,
                 user_state,
                 format!("Database error: {}", e),
             )
@@ -259,20 +1157,9 @@ pub async fn create_product(
 pub async fn show_edit_form(
     Path(id): Path<String>,
     Extension(collection): Extension<Collection<Product>>,
-    auth: AppAuthSession,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    user_state: UserState,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
-    }
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
-    }
-
     // Parse the hex string into an ObjectID
     let obj_id = match ObjectId::parse_str(&id) {
         Ok(oid) => oid,
@@ -284,18 +1171,10 @@ pub async fn show_edit_form(
     // Find the product
     match collection.find_one(doc! { "_id": obj_id }).await {
         Ok(Some(product)) => {
-            let product_display = ProductDisplay {
-                id: product.id.to_hex(),
-                name: product.name,
-                image_url: normalize_image_url(&product.image_url),
-                price: product.price,
-                quantity: product.quantity,
-                description: product.description,
-                adoptable: product.adoptable,
-            };
-
+            let categories = load_categories(&categories_collection).await;
             let template = EditProductTemplate {
-                product: product_display,
+                categories: category_options(&categories, product.category_id),
+                product: convert_to_display(product, &categories),
                 user_state,
                 error_message: String::new(),
             };
@@ -311,25 +1190,19 @@ pub async fn show_edit_form(
     }
 }
 
-/// Handle product update
+/// Handle product update, including changes to the image gallery
+#[allow(clippy::too_many_arguments)] // One per extractor
 pub async fn update_product(
     Path(id): Path<String>,
     Extension(collection): Extension<Collection<Product>>,
-    auth: AppAuthSession,
-    Form(form): Form<EditProductForm>,
+    Extension(categories_collection): Extension<Collection<Category>>,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    Extension(stores): Extension<ImageStores>,
+    actor: Actor,
+    user_state: UserState,
+    multipart: Multipart,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
-    }
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
-    }
-
     // Parse the hex string into an ObjectID
     let obj_id = match ObjectId::parse_str(&id) {
         Ok(oid) => oid,
@@ -338,41 +1211,188 @@ pub async fn update_product(
         }
     };
 
+    let storage = stores.product_images.as_ref();
+    let (form, mut gallery, uploads, variant_edit) = match read_product_form(multipart).await {
+        Ok(parsed) => parsed,
+        Err(error_msg) => {
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await;
+        }
+    };
+
+    // The product as stored: its currency, stock and gallery carry over. The
+    // raw document is kept to guard the save on.
+    let stored = match collection.clone_with_type::<Document>().find_one(doc! { "_id": obj_id }).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Product not found").into_response(),
+        Err(e) => {
+            let error_msg = format!("Database error: {}", e);
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    let current: Product = match bson::from_document(stored.clone()) {
+        Ok(product) => product,
+        Err(e) => {
+            let error_msg = format!("Invalid product data: {}", e);
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+
     // Validate form data
-    let validation_result = validate_product_form(&form);
-    if let Err(error_msg) = validation_result {
-        // Return to edit form with error
-        return show_edit_form_with_error(obj_id, &collection, user_state, error_msg).await;
+    let (validated_price, entered_quantity) = match validate_product_form(&form, current.price.currency) {
+        Ok(validated) => validated,
+        Err(error_msg) => {
+            // Return to edit form with error
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    let (options, mut variants) = match variant_edit.parse(validated_price) {
+        Ok(parsed) => parsed,
+        Err(error_msg) => {
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    let sku = match parse_product_sku(&form.sku) {
+        Ok(sku) => sku,
+        Err(error_msg) => {
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    let reorder_threshold = match parse_reorder_threshold(&form.reorder_threshold) {
+        Ok(threshold) => threshold,
+        Err(error_msg) => {
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    if let Some(sku) = &sku
+        && variants.iter().any(|variant| variant.sku.eq_ignore_ascii_case(sku))
+    {
+        let error_msg = format!("SKU {} is used more than once", sku);
+        return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await;
     }
+    let skus: Vec<&str> = sku
+        .iter()
+        .map(String::as_str)
+        .chain(variants.iter().map(|variant| variant.sku.as_str()))
+        .collect();
+    match sku_owner(&collection, &skus, Some(obj_id)).await {
+        Ok(None) => {}
+        Ok(Some(other)) => {
+            let error_msg = format!("{} already uses one of these SKUs", other.name);
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await;
+        }
+        Err(e) => {
+            let error_msg = format!("Database error: {}", e);
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await;
+        }
+    }
+    let categories = load_categories(&categories_collection).await;
+    let (category_id, tags) = match read_classification(&form, &categories) {
+        Ok(classification) => classification,
+        Err(error_msg) => {
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
 
-    let (_validated_price, validated_quantity) = validation_result.unwrap();
+    let validated_quantity = carry_over_stock(&current, &mut variants, entered_quantity);
+    if let Err(error_msg) = uploads.store(storage, &mut gallery).await {
+        return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await;
+    }
+    let current_images: Vec<ProductImage> = current.gallery().into_iter().map(normalize_image).collect();
+    let images = match gallery.apply(&current_images) {
+        Ok(images) => images,
+        Err(error_msg) => {
+            discard_uploads(storage, &gallery).await;
+            return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                .await;
+        }
+    };
+    let (images_bson, options_bson, variants_bson) =
+        match (bson::to_bson(&images), bson::to_bson(&options), bson::to_bson(&variants)) {
+            (Ok(images), Ok(options), Ok(variants)) => (images, options, variants),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                discard_uploads(storage, &gallery).await;
+                let error_msg = format!("Invalid product data: {}", e);
+                return show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg)
+                    .await;
+            }
+        };
 
     // Update the product in database
     let update_doc = doc! {
         "$set": {
             "name": &form.name,
-            "price": &form.price,
+            "price": validated_price,
             "quantity": validated_quantity,
             "description": &form.description,
             "adoptable": form.adoptable.is_some(),
+            "image_url": &images[0].url,
+            "images": images_bson,
+            "category_id": category_id,
+            "tags": &tags,
+            "sku": sku.clone(),
+            "reorder_threshold": reorder_threshold,
+            "options": options_bson,
+            "variants": variants_bson,
         }
     };
 
+    // Returns the product as it was, so the audit entry can show what changed.
+    // Only saved if no stock has moved since it was read.
     match collection
-        .update_one(doc! { "_id": obj_id }, update_doc)
+        // The edit form rewrites all the stock, including every variant's
+        .find_one_and_update(unchanged_filter(&stored, &["quantity", "variants"]), update_doc)
         .await
     {
-        Ok(result) => {
-            if result.matched_count == 0 {
-                (StatusCode::NOT_FOUND, "Product not found").into_response()
-            } else {
-                Redirect::to("/products?success=updated").into_response()
-            }
+        Ok(None) => {
+            discard_uploads(storage, &gallery).await;
+            let error_msg = "Stock changed while you were editing; check it and save again".to_string();
+            show_edit_form_with_error(obj_id, &collection, &categories_collection, user_state, error_msg).await
+        }
+        Ok(Some(before)) => {
+            let after = Product {
+                name: form.name.clone(),
+                price: validated_price,
+                quantity: validated_quantity,
+                description: form.description.clone(),
+                adoptable: form.adoptable.is_some(),
+                image_url: images[0].url.clone(),
+                images,
+                category_id,
+                tags,
+                sku,
+                reorder_threshold,
+                options,
+                variants,
+                ..before.clone()
+            };
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::PRODUCT_UPDATED,
+                    Some(id),
+                    audit::diff(&audit_fields(&before), &audit_fields(&after)),
+                )
+                .await;
+            stock_ledger
+                .record_changes(&actor, Some(&before), &after, MovementKind::Adjustment, "Variants changed")
+                .await;
+            // Removed and replaced images
+            delete_image_files(storage, unused_image_files(&before, &after)).await;
+            Redirect::to("/products?success=updated").into_response()
         }
         Err(e) => {
+            discard_uploads(storage, &gallery).await;
             show_edit_form_with_error(
                 obj_id,
                 &collection,
+                &categories_collection,
                 user_state,
                 format!("Database error: {}", e),
             )
@@ -381,24 +1401,14 @@ pub async fn update_product(
     }
 }
 
-/// Delete a product
+/// Delete a product and its images
 pub async fn delete_product(
     Path(id): Path<String>,
     Extension(collection): Extension<Collection<Product>>,
-    auth: AppAuthSession,
+    Extension(audit_log): Extension<AuditLog>,
+    Extension(stores): Extension<ImageStores>,
+    actor: Actor,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return Json(ProductOperationResponse {
-            success: false,
-            message: "Access denied".to_string(),
-            product_id: None,
-        })
-        .into_response();
-    }
-
     // Parse the hex string into an ObjectID
     let obj_id = match ObjectId::parse_str(&id) {
         Ok(oid) => oid,
@@ -413,23 +1423,29 @@ pub async fn delete_product(
     };
 
     // Delete the product
-    match collection.delete_one(doc! { "_id": obj_id }).await {
-        Ok(result) => {
-            if result.deleted_count == 0 {
-                Json(ProductOperationResponse {
-                    success: false,
-                    message: "Product not found".to_string(),
-                    product_id: None,
-                })
-                .into_response()
-            } else {
-                Json(ProductOperationResponse {
-                    success: true,
-                    message: "Product deleted successfully".to_string(),
-                    product_id: Some(id),
-                })
-                .into_response()
-            }
+    match collection.find_one_and_delete(doc! { "_id": obj_id }).await {
+        Ok(None) => Json(ProductOperationResponse {
+            success: false,
+            message: "Product not found".to_string(),
+            product_id: None,
+        })
+        .into_response(),
+        Ok(Some(deleted)) => {
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::PRODUCT_DELETED,
+                    Some(id.clone()),
+                    audit::diff(&audit_fields(&deleted), &[]),
+                )
+                .await;
+            delete_product_images(stores.product_images.as_ref(), &deleted).await;
+            Json(ProductOperationResponse {
+                success: true,
+                message: "Product deleted successfully".to_string(),
+                product_id: Some(id),
+            })
+            .into_response()
         }
         Err(e) => Json(ProductOperationResponse {
             success: false,
@@ -440,8 +1456,9 @@ pub async fn delete_product(
     }
 }
 
-/// Helper function to validate product form data
-pub fn validate_product_form(form: &EditProductForm) -> Result<(f64, i32), String> {
+/// Helper function to validate product form data. The price is read in the
+/// product's own currency.
+pub fn validate_product_form(form: &EditProductForm, currency: Currency) -> Result<(Money, i32), String> {
     // Validate name
     if form.name.trim().is_empty() {
         return Err("Product name cannot be empty".to_string());
@@ -452,18 +1469,15 @@ pub fn validate_product_form(form: &EditProductForm) -> Result<(f64, i32), Strin
     }
 
     // Validate price
-    let price = form
-        .price
-        .trim()
-        .parse::<f64>()
+    let price = Money::parse(&form.price, currency)
         .map_err(|_| "Price must be a valid number".to_string())?;
 
-    if price < 0.0 {
+    if price.is_negative() {
         return Err("Price cannot be negative".to_string());
     }
 
-    if price > 999999.99 {
-        return Err("Price cannot exceed £999,999.99".to_string());
+    if price.minor_units > MAX_PRICE_MINOR_UNITS {
+        return Err(format!("Price cannot exceed {}999,999.99", price.currency.symbol()));
     }
 
     // Validate quantity
@@ -490,7 +1504,7 @@ pub fn validate_product_form(form: &EditProductForm) -> Result<(f64, i32), Strin
 }
 
 /// Helper function to validate create product form data
-pub fn validate_create_product_form(form: &CreateProductForm) -> Result<(f64, i32), String> {
+pub fn validate_create_product_form(form: &CreateProductForm) -> Result<(Money, i32), String> {
     // Validate name
     if form.name.trim().is_empty() {
         return Err("Product name cannot be empty".to_string());
@@ -501,18 +1515,15 @@ pub fn validate_create_product_form(form: &CreateProductForm) -> Result<(f64, i3
     }
 
     // Validate price
-    let price = form
-        .price
-        .trim()
-        .parse::<f64>()
+    let price = Money::parse(&form.price, Currency::default())
         .map_err(|_| "Price must be a valid number".to_string())?;
 
-    if price < 0.0 {
+    if price.is_negative() {
         return Err("Price cannot be negative".to_string());
     }
 
-    if price > 999999.99 {
-        return Err("Price cannot exceed £999,999.99".to_string());
+    if price.minor_units > MAX_PRICE_MINOR_UNITS {
+        return Err(format!("Price cannot exceed {}999,999.99", price.currency.symbol()));
     }
 
     // Validate quantity
@@ -540,11 +1551,14 @@ pub fn validate_create_product_form(form: &CreateProductForm) -> Result<(f64, i3
 
 /// Helper function to show create form with error message
 async fn show_create_form_with_error(
+    categories_collection: &Collection<Category>,
     user_state: UserState,
     error_message: String,
 ) -> axum::response::Response {
+    let categories = load_categories(categories_collection).await;
     let template = CreateProductTemplate {
         user_state,
+        categories: category_options(&categories, None),
         error: error_message,
     };
 
@@ -555,23 +1569,16 @@ async fn show_create_form_with_error(
 async fn show_edit_form_with_error(
     obj_id: ObjectId,
     collection: &Collection<Product>,
+    categories_collection: &Collection<Category>,
     user_state: UserState,
     error_message: String,
 ) -> axum::response::Response {
     match collection.find_one(doc! { "_id": obj_id }).await {
         Ok(Some(product)) => {
-            let product_display = ProductDisplay {
-                id: product.id.to_hex(),
-                name: product.name,
-                image_url: normalize_image_url(&product.image_url),
-                price: product.price,
-                quantity: product.quantity,
-                description: product.description,
-                adoptable: product.adoptable,
-            };
-
+            let categories = load_categories(categories_collection).await;
             let template = EditProductTemplate {
-                product: product_display,
+                categories: category_options(&categories, product.category_id),
+                product: convert_to_display(product, &categories),
                 user_state,
                 error_message,
             };
@@ -591,6 +1598,363 @@ async fn show_edit_form_with_error(
 mod tests {
     use super::*;
 
+    fn urls(list: &[&str]) -> Vec<String> {
+        list.iter().map(|url| url.to_string()).collect()
+    }
+
+    fn images(list: &[&str]) -> Vec<ProductImage> {
+        list.iter().map(|url| ProductImage::from(url.to_string())).collect()
+    }
+
+    #[test]
+    fn test_gallery_edit_reorders_removes_replaces_and_adds() {
+        let current = images(&["/product-images/a.jpg", "/product-images/b.jpg", "/product-images/c.jpg"]);
+        let edit = GalleryEdit {
+            order: urls(&["/product-images/c.jpg", "/product-images/a.jpg", "/product-images/b.jpg"]),
+            remove: urls(&["/product-images/a.jpg"]),
+            replace: vec![("/product-images/b.jpg".to_string(), "/product-images/b2.jpg".to_string().into())],
+            added: images(&["/product-images/d.jpg"]),
+        };
+
+        assert_eq!(
+            edit.apply(&current).unwrap(),
+            images(&["/product-images/c.jpg", "/product-images/b2.jpg", "/product-images/d.jpg"])
+        );
+    }
+
+    #[test]
+    fn test_gallery_edit_ignores_unknown_urls_and_keeps_unlisted_ones() {
+        let current = images(&["/product-images/a.jpg", "/product-images/b.jpg"]);
+        let edit = GalleryEdit {
+            order: urls(&["/etc/passwd", "/product-images/b.jpg"]),
+            ..GalleryEdit::default()
+        };
+
+        assert_eq!(
+            edit.apply(&current).unwrap(),
+            images(&["/product-images/b.jpg", "/product-images/a.jpg"])
+        );
+    }
+
+    #[test]
+    fn test_gallery_edit_needs_an_image() {
+        let current = images(&["/product-images/a.jpg"]);
+        let edit = GalleryEdit {
+            remove: urls(&["/product-images/a.jpg"]),
+            ..GalleryEdit::default()
+        };
+        assert!(edit.apply(&current).is_err());
+    }
+
+    fn variant_edit(options: &[(&str, &str)], rows: &[(&str, &str, &str, &str)]) -> VariantEdit {
+        VariantEdit {
+            option_names: options.iter().map(|(name, _)| name.to_string()).collect(),
+            option_values: options.iter().map(|(_, values)| values.to_string()).collect(),
+            combinations: rows.iter().map(|row| row.0.to_string()).collect(),
+            skus: rows.iter().map(|row| row.1.to_string()).collect(),
+            price_deltas: rows.iter().map(|row| row.2.to_string()).collect(),
+            quantities: rows.iter().map(|row| row.3.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_variant_edit_parses_options_and_rows() {
+        let edit = variant_edit(
+            &[("Colour", "Red, blue, red"), ("", ""), ("Size", "S, L")],
+            &[
+                ("red / l", "FOX-RED-L", "1.5", "3"),
+                ("", "", "", ""),
+                ("Blue/S", "FOX-BLUE-S", "", "0"),
+            ],
+        );
+
+        let (options, variants) = edit.parse(Money::new(1000, Currency::Gbp)).unwrap();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[0].values, vec!["Red", "blue"]);
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].option_values, vec!["Red", "L"]);
+        assert_eq!(variants[0].price_delta, Money::new(150, Currency::Gbp));
+        assert_eq!(variants[0].quantity, 3);
+        assert_eq!(variants[1].label(), "blue / S");
+        assert_eq!(variants[1].price_delta, Money::zero(Currency::Gbp));
+    }
+
+    #[test]
+    fn test_variant_edit_without_options_is_empty() {
+        let five_pounds = Money::new(500, Currency::Gbp);
+        let (options, variants) = VariantEdit::default().parse(five_pounds).unwrap();
+        assert!(options.is_empty());
+        assert!(variants.is_empty());
+
+        let stray_row = variant_edit(&[], &[("Red", "FOX-RED", "", "1")]);
+        assert!(stray_row.parse(five_pounds).is_err());
+    }
+
+    #[test]
+    fn test_variant_edit_rejects_bad_rows() {
+        let five_pounds = Money::new(500, Currency::Gbp);
+        let options = [("Colour", "Red, Blue")];
+        let cases: [&[(&str, &str, &str, &str)]; 7] = [
+            &[],                                                          // Options but no variants
+            &[("Green", "FOX-GREEN", "", "1")],                           // Not an option value
+            &[("Red / Large", "FOX-RED", "", "1")],                       // Too many values
+            &[("Red", "", "", "1")],                                      // Missing SKU
+            &[("Red", "FOX RED", "", "1")],                               // SKU with a space
+            &[("Red", "FOX-1", "", "1"), ("Blue", "fox-1", "", "1")],     // Repeated SKU
+            &[("Red", "FOX-RED", "-6.00", "1")],                          // Below £0
+        ];
+        for rows in cases {
+            assert!(variant_edit(&options, rows).parse(five_pounds).is_err(), "{:?}", rows);
+        }
+
+        let repeated = [("Red", "FOX-1", "", "1"), ("red", "FOX-2", "", "1")];
+        assert!(variant_edit(&options, &repeated).parse(five_pounds).is_err());
+        let negative_stock = [("Red", "FOX-1", "", "-1")];
+        assert!(variant_edit(&options, &negative_stock).parse(five_pounds).is_err());
+        let unnamed = variant_edit(&[("", "Red")], &[]);
+        assert!(unnamed.parse(five_pounds).is_err());
+    }
+
+    #[test]
+    fn test_carry_over_stock_keeps_existing_levels() {
+        let five_pounds = Money::new(500, Currency::Gbp);
+        let options = [("Colour", "Red, Blue")];
+        let (_, current_variants) = variant_edit(&options, &[("Red", "FOX-RED", "", "4")]).parse(five_pounds).unwrap();
+        let current = Product {
+            name: "Fox Badge".to_string(),
+            price: five_pounds,
+            quantity: 4,
+            variants: current_variants,
+            ..Default::default()
+        };
+
+        // Existing variants keep their stock; new ones take what was entered
+        let rows = [("Red", "fox-red", "", "99"), ("Blue", "FOX-BLUE", "", "2")];
+        let (_, mut variants) = variant_edit(&options, &rows).parse(five_pounds).unwrap();
+        assert_eq!(carry_over_stock(&current, &mut variants, 0), 6);
+        assert_eq!(variants[0].quantity, 4);
+        assert_eq!(variants[1].quantity, 2);
+
+        // Dropping the variants starts the product's own stock from the form
+        assert_eq!(carry_over_stock(&current, &mut [], 7), 7);
+        let plain = Product { variants: vec![], ..current };
+        assert_eq!(carry_over_stock(&plain, &mut [], 7), 4);
+    }
+
+    #[test]
+    fn test_unchanged_filter() {
+        let id = ObjectId::new();
+        let variants = bson::to_bson(&[ProductVariant {
+            sku: "FOX-RED".to_string(),
+            option_values: vec!["Red".to_string()],
+            price_delta: Money::zero(Currency::Gbp),
+            quantity: 4,
+        }])
+        .unwrap();
+        let stored = doc! { "_id": id, "name": "Fox Tee", "quantity": 4, "variants": variants.clone() };
+        let filter = unchanged_filter(&stored, &["quantity", "variants"]);
+        assert_eq!(filter, doc! { "_id": id, "quantity": 4, "variants": variants });
+
+        // Older products have no variants field
+        let stored = doc! { "_id": id, "quantity": 2 };
+        let filter = unchanged_filter(&stored, &["quantity", "variants"]);
+        assert_eq!(filter, doc! { "_id": id, "quantity": 2, "variants": Bson::Null });
+    }
+
+    #[test]
+    fn test_unused_image_files_after_removing_and_replacing() {
+        let mut gallery = images(&["/product-images/a.jpg", "/product-images/b.jpg"]);
+        gallery[0].thumbnail_url = Some("/product-images/a-thumb.jpg".to_string());
+        let before = Product {
+            name: "Fox Badge".to_string(),
+            image_url: "/product-images/old.jpg".to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 1,
+            images: gallery,
+            ..Default::default()
+        };
+        let after = Product {
+            image_url: "/product-images/b.jpg".to_string(),
+            images: images(&["/product-images/b.jpg", "/product-images/c.jpg"]),
+            ..before.clone()
+        };
+
+        assert_eq!(unused_image_files(&before, &after), urls(&["a-thumb.jpg", "a.jpg", "old.jpg"]));
+        assert!(unused_image_files(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn test_display_falls_back_to_image_url_for_older_products() {
+        let product = Product {
+            name: "Fox Badge".to_string(),
+            image_url: "product-images/fox.jpg".to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 3,
+            ..Default::default()
+        };
+
+        let display = convert_to_display(product, &[]);
+        assert_eq!(display.image_url, "/product-images/fox.jpg");
+        assert_eq!(display.thumbnail_url, "/product-images/fox.jpg");
+        assert_eq!(display.images.len(), 1);
+        assert_eq!(display.images[0].url, "/product-images/fox.jpg");
+    }
+
+    #[test]
+    fn test_gallery_reads_plain_url_entries() {
+        let stored = doc! {
+            "_id": ObjectId::new(),
+            "name": "Fox Badge",
+            "image_url": "/product-images/a.jpg",
+            "price": "4.50",
+            "quantity": 3,
+            "description": "",
+            "adoptable": false,
+            "images": [
+                "/product-images/a.jpg",
+                { "url": "/product-images/b.jpg", "thumbnail_url": "/product-images/b-thumb.jpg", "width": 800, "height": 600 },
+            ],
+        };
+        let product: Product = bson::from_document(stored).unwrap();
+
+        assert_eq!(product.images[0], ProductImage::from("/product-images/a.jpg".to_string()));
+        assert_eq!(product.images[1].thumbnail_url.as_deref(), Some("/product-images/b-thumb.jpg"));
+        assert_eq!(product.images[1].width, Some(800));
+
+        let display = convert_to_display(product, &[]);
+        assert_eq!(display.images[0].thumbnail_url, "/product-images/a.jpg");
+        assert_eq!(display.images[1].thumbnail_url, "/product-images/b-thumb.jpg");
+    }
+
+    #[test]
+    fn test_parse_tags_normalises() {
+        assert_eq!(
+            parse_tags(" Foxes, enamel  PIN,foxes,, ").unwrap(),
+            vec!["foxes".to_string(), "enamel pin".to_string()]
+        );
+        assert!(parse_tags("").unwrap().is_empty());
+        assert!(parse_tags(&"x".repeat(41)).is_err());
+
+        let too_many: Vec<String> = (0..21).map(|n| format!("tag{}", n)).collect();
+        assert!(parse_tags(&too_many.join(",")).is_err());
+    }
+
+    #[test]
+    fn test_product_filter() {
+        let category = Category {
+            id: ObjectId::new(),
+            name: "Pins".to_string(),
+            slug: "pins".to_string(),
+            description: String::new(),
+        };
+        let params = |category: &str, tag: &str| ProductQueryParams {
+            category: Some(category.to_string()),
+            tag: Some(tag.to_string()),
+            ..ProductQueryParams::default()
+        };
+
+        let filter = product_filter(&params("pins", " Foxes "), std::slice::from_ref(&category)).unwrap();
+        assert_eq!(filter.get_object_id("category_id").unwrap(), category.id);
+        assert_eq!(filter.get_str("tags").unwrap(), "foxes");
+
+        let filter = product_filter(&params("none", ""), &[]).unwrap();
+        assert_eq!(filter.get("category_id"), Some(&Bson::Null));
+        assert!(filter.get("tags").is_none());
+
+        assert!(product_filter(&params("", ""), &[]).unwrap().is_empty());
+        assert!(product_filter(&params("gone", ""), &[]).is_err());
+    }
+
+    #[test]
+    fn test_product_filter_search_stock_and_type() {
+        let params = ProductQueryParams {
+            q: Some(" fox (large) ".to_string()),
+            stock: Some("out_of_stock".to_string()),
+            kind: Some("adoptable".to_string()),
+            ..ProductQueryParams::default()
+        };
+        let filter = product_filter(&params, &[]).unwrap();
+
+        let search = filter.get_array("$or").unwrap();
+        assert_eq!(
+            search[0].as_document().unwrap(),
+            &doc! { "name": { "$regex": "fox \\(large\\)", "$options": "i" } }
+        );
+        assert_eq!(filter.get_document("quantity").unwrap(), &doc! { "$lte": 0 });
+        assert!(filter.get_bool("adoptable").unwrap());
+
+        let unknown = ProductQueryParams {
+            stock: Some("lots".to_string()),
+            q: Some("  ".to_string()),
+            ..ProductQueryParams::default()
+        };
+        assert!(product_filter(&unknown, &[]).unwrap().is_empty());
+
+        let low = ProductQueryParams {
+            stock: Some("low_stock".to_string()),
+            ..ProductQueryParams::default()
+        };
+        let filter = product_filter(&low, &[]).unwrap();
+        assert_eq!(
+            filter.get_document("$expr").unwrap(),
+            &doc! { "$lte": ["$quantity", "$reorder_threshold"] }
+        );
+    }
+
+    #[test]
+    fn test_parse_reorder_threshold() {
+        assert_eq!(parse_reorder_threshold(" "), Ok(None));
+        assert_eq!(parse_reorder_threshold(" 3 "), Ok(Some(3)));
+        assert_eq!(parse_reorder_threshold("0"), Ok(Some(0)));
+        assert!(parse_reorder_threshold("-1").is_err());
+        assert!(parse_reorder_threshold("lots").is_err());
+    }
+
+    #[test]
+    fn test_product_sort() {
+        assert_eq!(product_sort(None), doc! { "name": 1, "_id": 1 });
+        assert_eq!(product_sort(Some("price_desc")), doc! { "price.minor_units": -1, "_id": 1 });
+        assert_eq!(product_sort(Some("quantity")), doc! { "quantity": 1, "_id": 1 });
+        assert_eq!(product_sort(Some("bogus")), doc! { "name": 1, "_id": 1 });
+    }
+
+    #[test]
+    fn test_product_query_string_keeps_filters_but_not_page() {
+        let params = ProductQueryParams {
+            q: Some("fox & hound".to_string()),
+            tag: Some(String::new()),
+            kind: Some("product".to_string()),
+            sort: Some("price".to_string()),
+            page: Some(3),
+            success: Some("updated".to_string()),
+            ..ProductQueryParams::default()
+        };
+        assert_eq!(params.query_string(), "q=fox%20%26%20hound&type=product&sort=price");
+    }
+
+    #[test]
+    fn test_read_classification_rejects_unknown_categories() {
+        let category = Category {
+            id: ObjectId::new(),
+            name: "Pins".to_string(),
+            slug: "pins".to_string(),
+            description: String::new(),
+        };
+        let form = |value: String| EditProductForm {
+            category: value,
+            tags: "Foxes".to_string(),
+            ..EditProductForm::default()
+        };
+
+        let (category_id, tags) =
+            read_classification(&form(category.id.to_hex()), std::slice::from_ref(&category)).unwrap();
+        assert_eq!(category_id, Some(category.id));
+        assert_eq!(tags, vec!["foxes".to_string()]);
+
+        assert_eq!(read_classification(&form(String::new()), &[]).unwrap().0, None);
+        assert!(read_classification(&form(ObjectId::new().to_hex()), &[category]).is_err());
+    }
+
     #[test]
     fn test_validate_product_form_valid() {
         let form = EditProductForm {
@@ -599,12 +1963,16 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_ok());
         let (price, quantity) = result.unwrap();
-        assert_eq!(price, 19.99);
+        assert_eq!(price, Money::new(1999, Currency::Gbp));
         assert_eq!(quantity, 10);
     }
 
@@ -616,9 +1984,13 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Product name cannot be empty");
     }
@@ -631,9 +2003,13 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Price must be a valid number");
     }
@@ -646,9 +2022,13 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Price cannot be negative");
     }
@@ -661,9 +2041,13 @@ mod tests {
             quantity: "invalid".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Quantity must be a valid number");
     }
@@ -676,9 +2060,13 @@ mod tests {
             quantity: "-5".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Quantity cannot be negative");
     }
@@ -691,9 +2079,13 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err(),
@@ -709,9 +2101,13 @@ mod tests {
             quantity: "10".to_string(),
             description: "a".repeat(5001),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err(),
@@ -727,11 +2123,25 @@ mod tests {
             quantity: "10".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Price cannot exceed £999,999.99");
+
+        // A euro product keeps its currency, and the limit is shown in it
+        let result = validate_product_form(&form, Currency::Eur);
+        assert_eq!(result.unwrap_err(), "Price cannot exceed €999,999.99");
+        let form = EditProductForm {
+            price: "€12.50".to_string(),
+            ..form
+        };
+        let (price, _) = validate_product_form(&form, Currency::Eur).unwrap();
+        assert_eq!(price, Money::new(1250, Currency::Eur));
     }
 
     #[test]
@@ -742,9 +2152,13 @@ mod tests {
             quantity: "1000000".to_string(),
             description: "A great product".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Quantity cannot exceed 999,999");
     }
@@ -757,12 +2171,16 @@ mod tests {
             quantity: "1".to_string(),
             description: "A cute pet".to_string(),
             adoptable: Some("on".to_string()),
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_ok());
         let (price, quantity) = result.unwrap();
-        assert_eq!(price, 0.0);
+        assert_eq!(price, Money::zero(Currency::Gbp));
         assert_eq!(quantity, 1);
     }
 
@@ -774,12 +2192,16 @@ mod tests {
             quantity: "0".to_string(),
             description: "".to_string(),
             adoptable: None,
+            category: String::new(),
+            tags: String::new(),
+            sku: String::new(),
+            reorder_threshold: String::new(),
         };
 
-        let result = validate_product_form(&form);
+        let result = validate_product_form(&form, Currency::Gbp);
         assert!(result.is_ok());
         let (price, quantity) = result.unwrap();
-        assert_eq!(price, 0.0);
+        assert_eq!(price, Money::zero(Currency::Gbp));
         assert_eq!(quantity, 0);
     }
 }
@@ -3,24 +3,24 @@ use axum::{
     Extension,
     extract::{Form, Path, Query},
     http::{StatusCode, header},
-    response::{Html, IntoResponse, Json, Response, Redirect},
+    response::{Html, IntoResponse, Json, Response},
     body::Body,
 };
 use chrono::{DateTime, Utc};
 use futures_util::TryStreamExt;
 use mongodb::{
     Collection,
-    bson::{doc, oid::ObjectId},
+    bson::{Document, doc, oid::ObjectId},
 };
-use tokio::fs;
+use tracing::error;
 
 use crate::{
-    handlers::auth::AppAuthSession,
+    audit::{self, Actor, AuditLog, FieldChange},
     models::{
         CustomBadgeQuote, QuoteDisplay, QuoteOperationResponse, QuoteProcessingTemplate, 
-        QuoteQueryParams, PaginationInfo, UpdateQuoteStatusForm,
+        QuoteQueryParams, PaginationInfo, UpdateQuoteStatusForm, UserState,
     },
-    user_state::extract_user_state,
+    storage::{self, ImageStores},
 };
 
 /// Default page size for quote listing
@@ -31,20 +31,8 @@ const MAX_PAGE_SIZE: u32 = 100;
 pub async fn list_quotes(
     Extension(quotes_collection): Extension<Collection<CustomBadgeQuote>>,
     Query(params): Query<QuoteQueryParams>,
-    auth: AppAuthSession,
+    user_state: UserState,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
-    }
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
-    }
-
     let status_filter = params.status_filter.as_deref().unwrap_or("all");
 
     let page = params.page.unwrap_or(1).max(1);
@@ -119,23 +107,12 @@ pub async fn list_quotes(
 /// Update quote status
 pub async fn update_quote_status(
     Extension(quotes_collection): Extension<Collection<CustomBadgeQuote>>,
-    auth: AppAuthSession,
+    Extension(audit_log): Extension<AuditLog>,
+    actor: Actor,
     Form(form): Form<UpdateQuoteStatusForm>,
 ) -> impl IntoResponse {
-    let user_state = extract_user_state(&auth);
-
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return Json(QuoteOperationResponse {
-            success: false,
-            message: "Access denied".to_string(),
-            quote_id: None,
-        })
-        .into_response();
-    }
-
     // Validate status
-    let valid_statuses = vec!["pending", "quoted", "accepted", "completed", "cancelled"];
+    let valid_statuses = ["pending", "quoted", "accepted", "completed", "cancelled"];
     if !valid_statuses.contains(&form.status.as_str()) {
         return Json(QuoteOperationResponse {
             success: false,
@@ -166,25 +143,39 @@ pub async fn update_quote_status(
         }
     };
 
+    // Raw document, so the previous status can be read for the audit log
     let filter = doc! { "_id": obj_id };
-    match quotes_collection.update_one(filter, update_doc).await {
-        Ok(result) => {
-            if result.matched_count > 0 {
-                Json(QuoteOperationResponse {
-                    success: true,
-                    message: format!("Quote status updated to {}", form.status),
-                    quote_id: Some(form.quote_id.clone()),
-                })
-                .into_response()
-            } else {
-                Json(QuoteOperationResponse {
-                    success: false,
-                    message: "Quote not found".to_string(),
-                    quote_id: None,
-                })
-                .into_response()
-            }
+    match quotes_collection
+        .clone_with_type::<Document>()
+        .find_one_and_update(filter, update_doc)
+        .await
+    {
+        Ok(Some(previous)) => {
+            audit_log
+                .record(
+                    &actor,
+                    audit::actions::QUOTE_STATUS_CHANGED,
+                    Some(form.quote_id.clone()),
+                    vec![FieldChange {
+                        field: "status".to_string(),
+                        before: previous.get_str("status").ok().map(str::to_string),
+                        after: Some(form.status.clone()),
+                    }],
+                )
+                .await;
+            Json(QuoteOperationResponse {
+                success: true,
+                message: format!("Quote status updated to {}", form.status),
+                quote_id: Some(form.quote_id.clone()),
+            })
+            .into_response()
         }
+        Ok(None) => Json(QuoteOperationResponse {
+            success: false,
+            message: "Quote not found".to_string(),
+            quote_id: None,
+        })
+        .into_response(),
         Err(e) => Json(QuoteOperationResponse {
             success: false,
             message: format!("Database error: {}", e),
@@ -194,40 +185,30 @@ pub async fn update_quote_status(
     }
 }
 
-/// Serve badge images from private_uploads directory (admin only)
+/// Serve customers' badge images from storage (admin only)
 pub async fn serve_badge_image(
-    auth: AppAuthSession,
     Path(filename): Path<String>,
+    Extension(stores): Extension<ImageStores>,
 ) -> Result<Response<Body>, StatusCode> {
-    // Ensure user is admin
-    if !extract_user_state(&auth).is_admin {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     // Security: Only allow badge files and prevent path traversal
     if !filename.starts_with("badge_") || filename.contains("..") || filename.contains('/') {
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let filepath = format!("./private_uploads/{}", filename);
-    
-    match fs::read(&filepath).await {
-        Ok(contents) => {
-            let content_type = match filepath.rsplit('.').next().unwrap_or("jpg").to_lowercase().as_str() {
-                "jpg" | "jpeg" => "image/jpeg",
-                "png" => "image/png",
-                "gif" => "image/gif",
-                _ => "application/octet-stream",
-            };
-            
+    match stores.badge_uploads.get(&filename).await {
+        Ok(Some(contents)) => {
             Ok(Response::builder()
                 .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_TYPE, storage::content_type_for(&filename))
                 .header(header::CACHE_CONTROL, "private, max-age=3600") // Cache for 1 hour
                 .body(Body::from(contents))
                 .unwrap())
         }
-        Err(_) => Err(StatusCode::NOT_FOUND),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to read badge image {}: {}", filename, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
     }
 }
 
@@ -267,7 +248,7 @@ fn convert_to_display(quote: CustomBadgeQuote) -> QuoteDisplay {
         estimated_price: quote.estimated_price,
         created_at: quote.created_at,
         status: quote.status,
-        formatted_price: format!("£{:.2}", quote.estimated_price),
+        formatted_price: quote.estimated_price.to_string(),
         formatted_created_at,
         status_class: status_class.to_string(),
         sided_text: sided_text.to_string(),
@@ -308,6 +289,7 @@ fn create_pagination_info(current_page: u32, page_size: u32, total_items: u64) -
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::money::{Currency, Money};
     use mongodb::bson::oid::ObjectId;
 
     #[test]
@@ -315,13 +297,14 @@ mod tests {
         let quote = CustomBadgeQuote {
             id: ObjectId::new(),
             num_colors: "3".to_string(),
-            double_sided: "no".to_string(),
+            double_sided: false,
             print_size: "100mm".to_string(),
             thickness: "10mm".to_string(),
             email: "test@example.com".to_string(),
             image_path: Some("test.jpg".to_string()),
-            estimated_price: 25.50,
+            estimated_price: Money::new(2550, Currency::Gbp),
             created_at: "2025-01-01T12:00:00Z".to_string(),
+            updated_at: "2025-01-01T12:00:00Z".to_string(),
             status: "pending".to_string(),
         };
 
@@ -332,7 +315,7 @@ mod tests {
         assert_eq!(display.print_size, "100mm");
         assert_eq!(display.thickness, "10mm");
         assert_eq!(display.email, "test@example.com");
-        assert_eq!(display.estimated_price, 25.50);
+        assert_eq!(display.estimated_price, Money::new(2550, Currency::Gbp));
         assert_eq!(display.formatted_price, "£25.50");
         assert_eq!(display.status, "pending");
         assert_eq!(display.status_class, "status-pending");
@@ -345,13 +328,14 @@ mod tests {
         let quote = CustomBadgeQuote {
             id: ObjectId::new(),
             num_colors: "5".to_string(),
-            double_sided: "yes".to_string(),
+            double_sided: true,
             print_size: "125mm".to_string(),
             thickness: "15mm".to_string(),
             email: "customer@test.com".to_string(),
             image_path: None,
-            estimated_price: 42.75,
+            estimated_price: Money::new(4275, Currency::Gbp),
             created_at: "2025-01-05T15:30:00Z".to_string(),
+            updated_at: "2025-01-05T15:30:00Z".to_string(),
             status: "quoted".to_string(),
         };
 
@@ -377,13 +361,14 @@ mod tests {
             let quote = CustomBadgeQuote {
                 id: ObjectId::new(),
                 num_colors: "2".to_string(),
-                double_sided: "no".to_string(),
+                double_sided: false,
                 print_size: "80mm".to_string(),
                 thickness: "5mm".to_string(),
                 email: "test@example.com".to_string(),
                 image_path: None,
-                estimated_price: 15.00,
+                estimated_price: Money::new(1500, Currency::Gbp),
                 created_at: "2025-01-01T12:00:00Z".to_string(),
+                updated_at: "2025-01-01T12:00:00Z".to_string(),
                 status: status.to_string(),
             };
 
@@ -0,0 +1,251 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::{Form, Path, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use mongodb::{
+    Collection,
+    bson::{doc, oid::ObjectId},
+};
+
+use crate::{
+    audit::{self, Actor},
+    handlers::order_processing::create_pagination_info,
+    models::{
+        Product, ProductStockTemplate, StockAdjustmentForm, StockLineDisplay, StockMovementDisplay, StockPageParams,
+        UserState,
+    },
+    stock::{MAX_STOCK, MovementKind, StockChange, StockLedger, StockMovement},
+};
+
+/// Movements per page of a product's stock history
+const PAGE_SIZE: u32 = 50;
+
+/// A stock change entered on the stock page
+#[derive(Debug, Clone, PartialEq)]
+pub struct StockAdjustment {
+    pub kind: MovementKind,
+    pub sku: Option<String>,
+    pub change: StockChange,
+    pub reason: String,
+}
+
+/// Read the stock page's form. Adjustments go up or down and need a reason;
+/// returns only add stock; a stocktake sets the level to what was counted.
+pub fn parse_adjustment(form: &StockAdjustmentForm) -> Result<StockAdjustment, String> {
+    let amount = form.amount.trim().trim_start_matches('+').parse::<i32>().ok();
+    let reason = form.reason.trim().to_string();
+    let (kind, change) = match form.kind.as_str() {
+        "adjustment" => {
+            let amount = amount
+                .filter(|amount| *amount != 0 && amount.abs() <= MAX_STOCK)
+                .ok_or("Enter how much stock changed by, such as 3 or -2")?;
+            if reason.is_empty() {
+                return Err("Give a reason for the adjustment".to_string());
+            }
+            (MovementKind::Adjustment, StockChange::By(amount))
+        }
+        "return" => {
+            let amount = amount
+                .filter(|amount| (1..=MAX_STOCK).contains(amount))
+                .ok_or("Enter how many came back")?;
+            (MovementKind::Return, StockChange::By(amount))
+        }
+        "stocktake" => {
+            let counted = amount
+                .filter(|amount| (0..=MAX_STOCK).contains(amount))
+                .ok_or("Enter the counted stock, from 0 to 999,999")?;
+            (MovementKind::Stocktake, StockChange::To(counted))
+        }
+        _ => return Err("Choose the kind of stock change".to_string()),
+    };
+    let sku = Some(form.sku.trim()).filter(|sku| !sku.is_empty()).map(str::to_string);
+    Ok(StockAdjustment { kind, sku, change, reason })
+}
+
+pub fn convert_to_display(movement: StockMovement) -> StockMovementDisplay {
+    StockMovementDisplay {
+        formatted_timestamp: audit::format_timestamp(movement.timestamp, "%Y-%m-%d %H:%M:%S"),
+        kind: movement.kind.label().to_string(),
+        sku: movement.sku.unwrap_or_default(),
+        change: format!("{:+}", movement.change),
+        quantity_after: movement.quantity_after,
+        reason: movement.reason,
+        order_reference: movement.order_reference.unwrap_or_default(),
+        actor: movement.actor,
+    }
+}
+
+fn stock_line_displays(product: &Product) -> Vec<StockLineDisplay> {
+    if product.variants.is_empty() {
+        return vec![StockLineDisplay {
+            sku: String::new(),
+            label: product.name.clone(),
+            quantity: product.quantity,
+        }];
+    }
+    product
+        .variants
+        .iter()
+        .map(|variant| StockLineDisplay {
+            sku: variant.sku.clone(),
+            label: variant.option_values.join(" / "),
+            quantity: variant.quantity,
+        })
+        .collect()
+}
+
+async fn render_stock_page(
+    product: Product,
+    stock_ledger: &StockLedger,
+    page: u32,
+    user_state: UserState,
+    success_message: String,
+    mut error_message: String,
+) -> Response {
+    let skip = ((page - 1) * PAGE_SIZE) as u64;
+    let (movements, total) = match stock_ledger.history(product.id, skip, PAGE_SIZE as i64).await {
+        Ok(history) => history,
+        Err(e) => {
+            if error_message.is_empty() {
+                error_message = format!("Database error fetching stock history: {}", e);
+            }
+            (vec![], 0)
+        }
+    };
+
+    let template = ProductStockTemplate {
+        user_state,
+        product_id: product.id.to_hex(),
+        lines: stock_line_displays(&product),
+        has_variants: !product.variants.is_empty(),
+        total_quantity: product.quantity,
+        product_name: product.name,
+        movements: movements.into_iter().map(convert_to_display).collect(),
+        pagination: create_pagination_info(page, PAGE_SIZE, total),
+        success_message,
+        error_message,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+async fn find_product(collection: &Collection<Product>, id: &str) -> Result<Product, Response> {
+    let obj_id = ObjectId::parse_str(id).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid product ID").into_response())?;
+    match collection.find_one(doc! { "_id": obj_id }).await {
+        Ok(Some(product)) => Ok(product),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Product not found").into_response()),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response()),
+    }
+}
+
+/// A product's stock levels and movement history, newest first
+pub async fn show_stock_history(
+    Path(id): Path<String>,
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    Query(params): Query<StockPageParams>,
+    user_state: UserState,
+) -> Response {
+    let product = match find_product(&collection, &id).await {
+        Ok(product) => product,
+        Err(response) => return response,
+    };
+    let success_message = match params.success.as_deref() {
+        Some("adjusted") => "Stock updated",
+        _ => "",
+    };
+    let page = params.page.unwrap_or(1).max(1);
+    render_stock_page(product, &stock_ledger, page, user_state, success_message.to_string(), String::new()).await
+}
+
+/// Change a product's stock by hand and record why
+pub async fn adjust_stock(
+    Path(id): Path<String>,
+    Extension(collection): Extension<Collection<Product>>,
+    Extension(stock_ledger): Extension<StockLedger>,
+    actor: Actor,
+    user_state: UserState,
+    Form(form): Form<StockAdjustmentForm>,
+) -> Response {
+    let product = match find_product(&collection, &id).await {
+        Ok(product) => product,
+        Err(response) => return response,
+    };
+    let adjustment = match parse_adjustment(&form) {
+        Ok(adjustment) => adjustment,
+        Err(error_message) => {
+            return render_stock_page(product, &stock_ledger, 1, user_state, String::new(), error_message).await;
+        }
+    };
+
+    let movement = StockMovement::new(&actor, product.id, adjustment.kind).with_reason(&adjustment.reason);
+    match stock_ledger
+        .apply(adjustment.sku.as_deref(), adjustment.change, movement)
+        .await
+    {
+        Ok(_) => Redirect::to(&format!("/products/stock/{}?success=adjusted", product.id.to_hex())).into_response(),
+        Err(e) => render_stock_page(product, &stock_ledger, 1, user_state, String::new(), e.to_string()).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(kind: &str, sku: &str, amount: &str, reason: &str) -> StockAdjustmentForm {
+        StockAdjustmentForm {
+            kind: kind.to_string(),
+            sku: sku.to_string(),
+            amount: amount.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_adjustment() {
+        assert_eq!(
+            parse_adjustment(&form("adjustment", " TEE-S ", "-2", " Damaged ")),
+            Ok(StockAdjustment {
+                kind: MovementKind::Adjustment,
+                sku: Some("TEE-S".to_string()),
+                change: StockChange::By(-2),
+                reason: "Damaged".to_string(),
+            })
+        );
+        assert!(parse_adjustment(&form("adjustment", "", "+3", "")).is_err());
+        assert!(parse_adjustment(&form("adjustment", "", "0", "Found one")).is_err());
+
+        let returned = parse_adjustment(&form("return", "", "+1", "")).unwrap();
+        assert_eq!((returned.kind, returned.sku, returned.change), (MovementKind::Return, None, StockChange::By(1)));
+        assert!(parse_adjustment(&form("return", "", "-1", "")).is_err());
+
+        assert_eq!(parse_adjustment(&form("stocktake", "", "0", "")).unwrap().change, StockChange::To(0));
+        assert!(parse_adjustment(&form("stocktake", "", "1000000", "")).is_err());
+        assert!(parse_adjustment(&form("sale", "", "1", "")).is_err());
+    }
+
+    #[test]
+    fn test_convert_to_display() {
+        let actor = Actor {
+            username: "owner@example.com".to_string(),
+            ip: None,
+            token_id: None,
+        };
+        let movement = StockMovement {
+            sku: Some("TEE-S".to_string()),
+            change: 3,
+            quantity_after: 7,
+            order_reference: Some("FF-1001".to_string()),
+            timestamp: mongodb::bson::DateTime::from_millis(1_740_830_400_000), // 2025-03-01 12:00:00 UTC
+            ..StockMovement::new(&actor, ObjectId::new(), MovementKind::Return)
+        };
+
+        let display = convert_to_display(movement);
+        assert_eq!(display.formatted_timestamp, "2025-03-01 12:00:00");
+        assert_eq!(display.kind, "Return");
+        assert_eq!(display.change, "+3");
+        assert_eq!(display.order_reference, "FF-1001");
+    }
+}
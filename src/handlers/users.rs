@@ -0,0 +1,558 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::{Form, Path, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Json, Redirect, Response},
+};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection,
+    bson::{doc, oid::ObjectId},
+};
+use tracing::{error, info};
+
+use crate::{
+    auth::{hash_password, permissions as perms, validate_new_password},
+    handlers::auth::AppAuthSession,
+    login_throttle::{clear_account_failures, locked_until},
+    models::{
+        CreateUserTemplate, EditUserTemplate, ResetPasswordForm, RoleOption, User, UserDisplay,
+        UserForm, UserOperationResponse, UserQueryParams, UsersTemplate, UserState,
+    },
+    session_store::MongoSessionStore,
+    two_factor,
+};
+
+/// List staff accounts, with their lockout state
+pub async fn list_users(
+    Extension(users_collection): Extension<Collection<User>>,
+    Query(params): Query<UserQueryParams>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let (users, error_message) = match users_collection
+        .find(doc! {})
+        .sort(doc! { "username": 1 })
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<User>>().await {
+            Ok(users) => (users, String::new()),
+            Err(e) => (vec![], format!("Error loading users: {}", e)),
+        },
+        Err(e) => (vec![], format!("Database error fetching users: {}", e)),
+    };
+
+    let success_message = match params.success.as_deref() {
+        Some("created") => "User created",
+        Some("updated") => "User updated",
+        _ => "",
+    };
+
+    let template = UsersTemplate {
+        users: users.iter().map(convert_to_display).collect(),
+        user_state,
+        success_message: success_message.to_string(),
+        error_message,
+    };
+    Html(template.render().unwrap())
+}
+
+/// Show create form for a new user
+pub async fn show_create_form(user_state: UserState) -> impl IntoResponse {
+    let template = CreateUserTemplate {
+        user_state,
+        username: String::new(),
+        is_admin: false,
+        roles: RoleOption::all(&[]),
+        error_message: String::new(),
+    };
+    Html(template.render().unwrap())
+}
+
+/// Handle user creation
+pub async fn create_user(
+    Extension(users_collection): Extension<Collection<User>>,
+    user_state: UserState,
+    Form(pairs): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let form = UserForm::from_pairs(pairs);
+
+    let mut error = validate_user_form(&form)
+        .and_then(|_| validate_new_password(&form.password, &form.confirm_password))
+        .err();
+    if error.is_none() {
+        match users_collection
+            .find_one(doc! { "username": &form.username })
+            .await
+        {
+            Ok(Some(_)) => error = Some(format!("A user called {} already exists", form.username)),
+            Ok(None) => {}
+            Err(e) => error = Some(format!("Database error: {}", e)),
+        }
+    }
+
+    if let Some(error_message) = error {
+        let template = CreateUserTemplate {
+            user_state,
+            username: form.username,
+            is_admin: form.is_admin,
+            roles: RoleOption::all(&form.roles),
+            error_message,
+        };
+        return Html(template.render().unwrap()).into_response();
+    }
+
+    let password_hash = match hash_password(&form.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password").into_response();
+        }
+    };
+
+    let user = User::new(form.username, password_hash, form.is_admin, form.roles);
+
+    match users_collection.insert_one(&user).await {
+        Ok(_) => {
+            info!("User {} created by {}", user.username, user_state.username);
+            Redirect::to("/users?success=created").into_response()
+        }
+        Err(e) => {
+            let template = CreateUserTemplate {
+                user_state,
+                username: user.username,
+                is_admin: user.is_admin,
+                roles: RoleOption::all(&user.roles),
+                error_message: format!("Database error: {}", e),
+            };
+            Html(template.render().unwrap()).into_response()
+        }
+    }
+}
+
+/// Show edit form for a specific user
+pub async fn show_edit_form(
+    Path(id): Path<String>,
+    Extension(users_collection): Extension<Collection<User>>,
+    auth: AppAuthSession,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user ID").into_response(),
+    };
+    render_edit_form(obj_id, &users_collection, &auth, user_state, String::new(), String::new()).await
+}
+
+/// Handle changes to a user's admin flag, roles and disabled state
+pub async fn update_user(
+    Path(id): Path<String>,
+    Extension(users_collection): Extension<Collection<User>>,
+    auth: AppAuthSession,
+    user_state: UserState,
+    Form(pairs): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user ID").into_response(),
+    };
+    let form = UserForm::from_pairs(pairs);
+    if let Err(error_message) = validate_access(&form) {
+        return render_edit_form(obj_id, &users_collection, &auth, user_state, String::new(), error_message).await;
+    }
+
+    // Stop admins locking themselves out of user management
+    if is_current_user(&auth, obj_id) {
+        let still_manages_users = form.is_admin
+            || form
+                .roles
+                .iter()
+                .any(|role| role.permissions().contains(&perms::USERS_MANAGE));
+        if form.disabled || !still_manages_users {
+            return render_edit_form(
+                obj_id,
+                &users_collection,
+                &auth,
+                user_state,
+                String::new(),
+                "You can't disable your own account or remove your own access to user management".to_string(),
+            )
+            .await;
+        }
+    }
+
+    let role_names: Vec<&str> = form.roles.iter().map(|role| role.as_str()).collect();
+    let update_doc = doc! {
+        "$set": {
+            "is_admin": form.is_admin,
+            "roles": role_names,
+            "disabled": form.disabled,
+        }
+    };
+
+    match users_collection.update_one(doc! { "_id": obj_id }, update_doc).await {
+        Ok(result) if result.matched_count == 0 => {
+            (StatusCode::NOT_FOUND, "User not found").into_response()
+        }
+        Ok(_) => {
+            info!("User {} updated by {}", id, user_state.username);
+            Redirect::to("/users?success=updated").into_response()
+        }
+        Err(e) => {
+            render_edit_form(
+                obj_id,
+                &users_collection,
+                &auth,
+                user_state,
+                String::new(),
+                format!("Database error: {}", e),
+            )
+            .await
+        }
+    }
+}
+
+/// Set a new password for a user (also clears any lockout)
+pub async fn reset_password(
+    Path(id): Path<String>,
+    Extension(users_collection): Extension<Collection<User>>,
+    Extension(sessions): Extension<MongoSessionStore>,
+    auth: AppAuthSession,
+    user_state: UserState,
+    Form(form): Form<ResetPasswordForm>,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user ID").into_response(),
+    };
+
+    if let Err(error) = validate_new_password(&form.password, &form.confirm_password) {
+        return render_edit_form(obj_id, &users_collection, &auth, user_state, String::new(), error).await;
+    }
+
+    let password_hash = match hash_password(&form.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password").into_response();
+        }
+    };
+
+    let update_doc = doc! {
+        "$set": { "password_hash": password_hash, "failed_login_attempts": 0 },
+        "$unset": { "locked_until": "" },
+    };
+    let (success, error) = match users_collection.update_one(doc! { "_id": obj_id }, update_doc).await {
+        Ok(result) if result.matched_count == 0 => {
+            return (StatusCode::NOT_FOUND, "User not found").into_response();
+        }
+        Ok(_) => {
+            info!("Password for user {} reset by {}", id, user_state.username);
+            // The session auth hash is the password hash, so old sessions end here;
+            // removing them also clears them off the user's sessions page
+            if let Err(e) = sessions.revoke_all_for_user(obj_id, None).await {
+                error!("Failed to remove sessions for user {}: {}", id, e);
+            }
+            ("Password reset - the user has been logged out everywhere".to_string(), String::new())
+        }
+        Err(e) => (String::new(), format!("Database error: {}", e)),
+    };
+    render_edit_form(obj_id, &users_collection, &auth, user_state, success, error).await
+}
+
+/// Delete a user
+pub async fn delete_user(
+    Path(id): Path<String>,
+    Extension(users_collection): Extension<Collection<User>>,
+    auth: AppAuthSession,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => {
+            return Json(UserOperationResponse {
+                success: false,
+                message: "Invalid user ID".to_string(),
+                user_id: None,
+            });
+        }
+    };
+
+    if is_current_user(&auth, obj_id) {
+        return Json(UserOperationResponse {
+            success: false,
+            message: "You can't delete your own account".to_string(),
+            user_id: None,
+        });
+    }
+
+    match users_collection.delete_one(doc! { "_id": obj_id }).await {
+        Ok(result) if result.deleted_count == 0 => Json(UserOperationResponse {
+            success: false,
+            message: "User not found".to_string(),
+            user_id: None,
+        }),
+        Ok(_) => {
+            info!("User {} deleted by {}", id, user_state.username);
+            Json(UserOperationResponse {
+                success: true,
+                message: "User deleted".to_string(),
+                user_id: Some(id),
+            })
+        }
+        Err(e) => Json(UserOperationResponse {
+            success: false,
+            message: format!("Database error: {}", e),
+            user_id: None,
+        }),
+    }
+}
+
+fn is_current_user(auth: &AppAuthSession, id: ObjectId) -> bool {
+    auth.user.as_ref().is_some_and(|user| user.id == id)
+}
+
+/// Render the edit page for `id` with the given messages
+async fn render_edit_form(
+    id: ObjectId,
+    users_collection: &Collection<User>,
+    auth: &AppAuthSession,
+    user_state: UserState,
+    success_message: String,
+    error_message: String,
+) -> Response {
+    match users_collection.find_one(doc! { "_id": id }).await {
+        Ok(Some(user)) => {
+            let template = EditUserTemplate {
+                user_state,
+                roles: RoleOption::all(&user.roles),
+                is_self: is_current_user(auth, id),
+                user: convert_to_display(&user),
+                success_message,
+                error_message,
+            };
+            Html(template.render().unwrap()).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Helper function to validate the create user form (password checked separately)
+pub fn validate_user_form(form: &UserForm) -> Result<(), String> {
+    if form.username.is_empty() {
+        return Err("Username cannot be empty".to_string());
+    }
+    if form.username.len() > 255 {
+        return Err("Username must be less than 255 characters".to_string());
+    }
+    if form.username.chars().any(char::is_whitespace) {
+        return Err("Username cannot contain spaces".to_string());
+    }
+    validate_access(form)
+}
+
+/// Every account needs a role or the admin flag, or it can't do anything
+pub fn validate_access(form: &UserForm) -> Result<(), String> {
+    if !form.is_admin && form.roles.is_empty() {
+        return Err("Pick at least one role, or make the user an admin".to_string());
+    }
+    Ok(())
+}
+
+/// Clear a user's failed login count and lift any lockout
+pub async fn unlock_user(
+    Extension(users_collection): Extension<Collection<User>>,
+    Path(id): Path<String>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => {
+            return Json(UserOperationResponse {
+                success: false,
+                message: "Invalid user ID".to_string(),
+                user_id: None,
+            });
+        }
+    };
+
+    match clear_account_failures(&users_collection, obj_id).await {
+        Ok(true) => {
+            info!("User {} unlocked by {}", id, user_state.username);
+            Json(UserOperationResponse {
+                success: true,
+                message: "Account unlocked".to_string(),
+                user_id: Some(id),
+            })
+        }
+        Ok(false) => Json(UserOperationResponse {
+            success: false,
+            message: "User not found".to_string(),
+            user_id: None,
+        }),
+        Err(e) => {
+            error!("Failed to unlock user {}: {}", id, e);
+            Json(UserOperationResponse {
+                success: false,
+                message: format!("Database error: {}", e),
+                user_id: None,
+            })
+        }
+    }
+}
+
+/// Turn off two-factor for a user who has lost their phone and recovery codes
+pub async fn reset_two_factor(
+    Extension(users_collection): Extension<Collection<User>>,
+    Path(id): Path<String>,
+    user_state: UserState,
+) -> impl IntoResponse {
+    let obj_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => {
+            return Json(UserOperationResponse {
+                success: false,
+                message: "Invalid user ID".to_string(),
+                user_id: None,
+            });
+        }
+    };
+
+    match two_factor::clear_two_factor(&users_collection, obj_id).await {
+        Ok(true) => {
+            info!("Two-factor reset for user {} by {}", id, user_state.username);
+            Json(UserOperationResponse {
+                success: true,
+                message: "Two-factor authentication reset".to_string(),
+                user_id: Some(id),
+            })
+        }
+        Ok(false) => Json(UserOperationResponse {
+            success: false,
+            message: "User not found".to_string(),
+            user_id: None,
+        }),
+        Err(e) => {
+            error!("Failed to reset 2FA for user {}: {}", id, e);
+            Json(UserOperationResponse {
+                success: false,
+                message: format!("Database error: {}", e),
+                user_id: None,
+            })
+        }
+    }
+}
+
+/// Convert User to UserDisplay for templates
+pub fn convert_to_display(user: &User) -> UserDisplay {
+    let locked = locked_until(user);
+    UserDisplay {
+        id: user.id.to_hex(),
+        username: user.username.clone(),
+        is_admin: user.is_admin,
+        roles: user
+            .roles
+            .iter()
+            .map(|r| r.label())
+            .collect::<Vec<_>>()
+            .join(", "),
+        failed_login_attempts: user.failed_login_attempts,
+        is_locked: locked.is_some(),
+        formatted_locked_until: locked
+            .and_then(|until| DateTime::<Utc>::from_timestamp_millis(until.timestamp_millis()))
+            .map(|until| until.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default(),
+        two_factor_enabled: user.has_two_factor(),
+        disabled: user.disabled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+    use mongodb::bson;
+
+    fn sample_user() -> User {
+        User::new("packer@example.com".to_string(), String::new(), false, vec![Role::Fulfilment, Role::Quotes])
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_user_form_collects_repeated_roles() {
+        let form = UserForm::from_pairs(pairs(&[
+            ("csrf_token", "abc"),
+            ("username", "  packer@example.com "),
+            ("roles", "fulfilment"),
+            ("roles", "quotes"),
+            ("roles", "quotes"),
+            ("roles", "superuser"),
+        ]));
+
+        assert_eq!(form.username, "packer@example.com");
+        assert_eq!(form.roles, vec![Role::Fulfilment, Role::Quotes]);
+        assert!(!form.is_admin);
+        assert!(!form.disabled);
+    }
+
+    #[test]
+    fn test_validate_user_form() {
+        let mut form = UserForm::from_pairs(pairs(&[("username", "packer@example.com")]));
+        assert!(validate_user_form(&form).is_err()); // No role or admin flag
+
+        form.roles.push(Role::Viewer);
+        assert!(validate_user_form(&form).is_ok());
+
+        form.username = "two words".to_string();
+        assert!(validate_user_form(&form).is_err());
+
+        form.username = String::new();
+        assert!(validate_user_form(&form).is_err());
+    }
+
+    #[test]
+    fn test_validate_access_on_edit() {
+        // The edit form has no username, but still needs a role or admin
+        let mut form = UserForm::from_pairs(pairs(&[("disabled", "on")]));
+        assert!(validate_access(&form).is_err());
+
+        form.is_admin = true;
+        assert!(validate_access(&form).is_ok());
+    }
+
+    #[test]
+    fn test_convert_to_display_unlocked_user() {
+        let display = convert_to_display(&sample_user());
+
+        assert_eq!(display.roles, "Fulfilment, Quotes");
+        assert!(!display.is_locked);
+        assert_eq!(display.formatted_locked_until, "");
+        assert!(!display.two_factor_enabled);
+    }
+
+    #[test]
+    fn test_convert_to_display_locked_user() {
+        let mut user = sample_user();
+        user.failed_login_attempts = 6;
+        user.locked_until = Some(bson::DateTime::from_millis(
+            bson::DateTime::now().timestamp_millis() + 60_000,
+        ));
+
+        let display = convert_to_display(&user);
+        assert!(display.is_locked);
+        assert_eq!(display.failed_login_attempts, 6);
+        assert!(!display.formatted_locked_until.is_empty());
+    }
+}
@@ -5,7 +5,6 @@ use axum::{
 use serde::Serialize;
 use std::env;
 use tokio::fs;
-use tracing::warn;
 
 #[derive(Serialize)]
 pub struct VersionInfo {
@@ -28,7 +27,7 @@ pub async fn info() -> impl IntoResponse {
     let (image, build_time, git_commit) = if version_content.contains(',') {
         let parts: Vec<&str> = version_content.splitn(3, ',').collect();
         (
-            parts.get(0).unwrap_or(&"unknown").to_string(),
+            parts.first().unwrap_or(&"unknown").to_string(),
             parts.get(1).unwrap_or(&"unknown").to_string(),
             parts.get(2).unwrap_or(&"unknown").to_string(),
         )
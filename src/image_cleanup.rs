@@ -0,0 +1,170 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use crate::{
+    models::Product,
+    storage::{Storage, StorageError, StoredFile},
+};
+
+/// The stored file name behind a product image URL, e.g. "/product-images/a.jpg" -> "a.jpg"
+pub fn file_name_from_url(url: &str) -> Option<&str> {
+    let name = url.rsplit('/').next()?;
+    (!name.is_empty()).then_some(name)
+}
+
+/// Every file some product points at: gallery images, thumbnails and the
+/// primary `image_url`
+pub fn referenced_files(products: &[Product]) -> HashSet<String> {
+    let mut referenced = HashSet::new();
+    for product in products {
+        let urls = product
+            .gallery()
+            .into_iter()
+            .flat_map(|image| [Some(image.url), image.thumbnail_url])
+            .flatten()
+            .chain([product.image_url.clone()]);
+        for url in urls {
+            if let Some(name) = file_name_from_url(&url) {
+                referenced.insert(name.to_string());
+            }
+        }
+    }
+    referenced
+}
+
+/// Files no product uses. Anything modified after `cutoff` is left alone, as
+/// it may belong to a product form that's still being submitted.
+pub fn find_orphans(files: &[StoredFile], referenced: &HashSet<String>, cutoff: SystemTime) -> Vec<StoredFile> {
+    let mut orphans: Vec<StoredFile> = files
+        .iter()
+        .filter(|file| !referenced.contains(&file.key))
+        .filter(|file| file.modified.is_none_or(|modified| modified <= cutoff))
+        .cloned()
+        .collect();
+    orphans.sort_by(|a, b| a.key.cmp(&b.key));
+    orphans
+}
+
+/// Files with identical contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    /// SHA-256 of the contents, hex encoded
+    pub hash: String,
+    pub keys: Vec<String>,
+}
+
+/// Group files by content hash. Only files whose size matches another file's
+/// are read, so this doesn't download the whole store.
+pub async fn find_duplicates(storage: &dyn Storage, files: &[StoredFile]) -> Result<Vec<DuplicateGroup>, StorageError> {
+    let mut by_size: HashMap<u64, Vec<&StoredFile>> = HashMap::new();
+    for file in files {
+        by_size.entry(file.size).or_default().push(file);
+    }
+
+    let mut by_hash: HashMap<String, Vec<String>> = HashMap::new();
+    for candidates in by_size.values().filter(|group| group.len() > 1) {
+        for file in candidates {
+            let Some(bytes) = storage.get(&file.key).await? else {
+                continue; // Removed since it was listed
+            };
+            by_hash
+                .entry(hex::encode(Sha256::digest(&bytes)))
+                .or_default()
+                .push(file.key.clone());
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = by_hash
+        .into_iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|(hash, mut keys)| {
+            keys.sort();
+            DuplicateGroup { hash, keys }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.keys.cmp(&b.keys));
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::ProductImage,
+        money::{Currency, Money},
+        storage::LocalStorage,
+    };
+    use std::time::Duration;
+
+    fn product(image_url: &str, images: Vec<ProductImage>) -> Product {
+        Product {
+            name: "Fox Badge".to_string(),
+            image_url: image_url.to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 1,
+            images,
+            ..Default::default()
+        }
+    }
+
+    fn file(key: &str, modified: SystemTime) -> StoredFile {
+        StoredFile {
+            key: key.to_string(),
+            size: 10,
+            modified: Some(modified),
+        }
+    }
+
+    #[test]
+    fn test_referenced_files_include_thumbnails_and_legacy_urls() {
+        let products = vec![
+            product("product-images/old.jpg", vec![]),
+            product(
+                "/product-images/new.jpg",
+                vec![ProductImage {
+                    url: "/product-images/new.jpg".to_string(),
+                    thumbnail_url: Some("/product-images/new-thumb.jpg".to_string()),
+                    width: None,
+                    height: None,
+                }],
+            ),
+        ];
+
+        let referenced = referenced_files(&products);
+        let mut names: Vec<&str> = referenced.iter().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["new-thumb.jpg", "new.jpg", "old.jpg"]);
+    }
+
+    #[test]
+    fn test_find_orphans_skips_used_and_recent_files() {
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(48 * 60 * 60);
+        let files = vec![file("used.jpg", old), file("orphan.jpg", old), file("uploading.jpg", now)];
+        let referenced = HashSet::from(["used.jpg".to_string()]);
+
+        let orphans = find_orphans(&files, &referenced, now - Duration::from_secs(24 * 60 * 60));
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].key, "orphan.jpg");
+    }
+
+    #[tokio::test]
+    async fn test_find_duplicates_by_content() {
+        let root = std::env::temp_dir().join(format!("ffa-cleanup-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::open(&root).await.unwrap();
+        storage.put("a.jpg", b"same bytes".to_vec()).await.unwrap();
+        storage.put("b.jpg", b"same bytes".to_vec()).await.unwrap();
+        storage.put("c.jpg", b"diff bytes".to_vec()).await.unwrap();
+        storage.put("d.jpg", b"other".to_vec()).await.unwrap();
+
+        let files = storage.list().await.unwrap();
+        let groups = find_duplicates(&storage, &files).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keys, vec!["a.jpg".to_string(), "b.jpg".to_string()]);
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
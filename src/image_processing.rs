@@ -0,0 +1,199 @@
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+};
+use std::io::Cursor;
+use thiserror::Error;
+
+/// Longest side of the full-size image kept for a product
+pub const MAX_DIMENSION: u32 = 2000;
+
+/// Longest side of the thumbnail used in listings
+pub const THUMBNAIL_DIMENSION: u32 = 400;
+
+/// Anything bigger than this is refused before decoding
+const MAX_SOURCE_DIMENSION: u32 = 12_000;
+
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("Unsupported image file - please upload a JPEG, PNG, WebP or GIF")]
+    Unsupported,
+    #[error("Could not read the image: {0}")]
+    Decode(image::ImageError),
+    #[error("Could not process the image: {0}")]
+    Encode(image::ImageError),
+}
+
+/// One re-encoded version of an upload
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub bytes: Vec<u8>,
+    /// File extension matching the encoding ("jpg" or "webp")
+    pub extension: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The variants produced from one upload
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub full: EncodedImage,
+    pub thumbnail: EncodedImage,
+}
+
+/// Decode an upload (sniffing the format from its bytes, not the filename),
+/// apply its EXIF rotation, shrink it and re-encode it. Re-encoding drops all
+/// metadata. Images with transparency become lossless WebP, the rest JPEG.
+/// Animated GIFs keep only their first frame.
+///
+/// This is CPU-heavy, so call it from `spawn_blocking`.
+pub fn process_upload(data: &[u8]) -> Result<ProcessedImage, ImageError> {
+    let format = image::guess_format(data).map_err(|_| ImageError::Unsupported)?;
+    if !matches!(
+        format,
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Gif
+    ) {
+        return Err(ImageError::Unsupported);
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(ImageError::Decode)?;
+    let orientation = decoder.orientation().map_err(ImageError::Decode)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(ImageError::Decode)?;
+    image.apply_orientation(orientation);
+
+    let full = fit_within(image, MAX_DIMENSION);
+    let thumbnail = fit_within(full.clone(), THUMBNAIL_DIMENSION);
+    let transparent = has_transparency(&full);
+
+    Ok(ProcessedImage {
+        full: encode(&full, transparent)?,
+        thumbnail: encode(&thumbnail, transparent)?,
+    })
+}
+
+/// Shrink so neither side exceeds `max`, keeping the aspect ratio. Never enlarges.
+fn fit_within(image: DynamicImage, max: u32) -> DynamicImage {
+    if image.width() <= max && image.height() <= max {
+        image
+    } else {
+        image.resize(max, max, FilterType::Lanczos3)
+    }
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+fn encode(image: &DynamicImage, transparent: bool) -> Result<EncodedImage, ImageError> {
+    let mut bytes = Vec::new();
+    let extension = if transparent {
+        // The image crate only writes lossless WebP
+        DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut bytes))
+            .map_err(ImageError::Encode)?;
+        "webp"
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))
+            .map_err(ImageError::Encode)?;
+        "jpg"
+    };
+
+    Ok(EncodedImage {
+        bytes,
+        extension,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn encoded(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn opaque(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([200, 90, 30])))
+    }
+
+    #[test]
+    fn test_rejects_files_that_are_not_images() {
+        assert!(matches!(process_upload(b"<?php echo 'hi'; ?>"), Err(ImageError::Unsupported)));
+        assert!(matches!(process_upload(&[]), Err(ImageError::Unsupported)));
+
+        // Right magic bytes, broken body
+        let mut truncated = encoded(opaque(20, 20), ImageFormat::Png);
+        truncated.truncate(40);
+        assert!(matches!(process_upload(&truncated), Err(ImageError::Decode(_))));
+    }
+
+    #[test]
+    fn test_large_photo_is_shrunk_to_jpeg_with_thumbnail() {
+        let png = encoded(opaque(3000, 1500), ImageFormat::Png);
+        let processed = process_upload(&png).unwrap();
+
+        assert_eq!(processed.full.extension, "jpg");
+        assert_eq!((processed.full.width, processed.full.height), (2000, 1000));
+        assert_eq!((processed.thumbnail.width, processed.thumbnail.height), (400, 200));
+        assert_eq!(image::guess_format(&processed.full.bytes).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_small_images_are_not_enlarged() {
+        let jpeg = encoded(opaque(120, 80), ImageFormat::Jpeg);
+        let processed = process_upload(&jpeg).unwrap();
+
+        assert_eq!((processed.full.width, processed.full.height), (120, 80));
+        assert_eq!((processed.thumbnail.width, processed.thumbnail.height), (120, 80));
+    }
+
+    #[test]
+    fn test_transparent_images_become_webp() {
+        let mut pixels = RgbaImage::from_pixel(50, 50, Rgba([0, 0, 0, 255]));
+        pixels.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        let png = encoded(DynamicImage::ImageRgba8(pixels), ImageFormat::Png);
+
+        let processed = process_upload(&png).unwrap();
+        assert_eq!(processed.full.extension, "webp");
+        assert_eq!(processed.thumbnail.extension, "webp");
+        assert_eq!(image::guess_format(&processed.full.bytes).unwrap(), ImageFormat::WebP);
+
+        // An alpha channel that's fully opaque doesn't count
+        let solid = RgbaImage::from_pixel(50, 50, Rgba([0, 0, 0, 255]));
+        let png = encoded(DynamicImage::ImageRgba8(solid), ImageFormat::Png);
+        assert_eq!(process_upload(&png).unwrap().full.extension, "jpg");
+    }
+
+    #[test]
+    fn test_exif_rotation_is_applied_and_stripped() {
+        let jpeg = encoded(opaque(60, 20), ImageFormat::Jpeg);
+
+        // APP1 segment with a single Orientation = 6 (rotate 90° clockwise) tag
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0, 0, 0, 0, 0]);
+        let length = (exif.len() + 2) as u16;
+        let mut tagged = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        tagged.extend_from_slice(&length.to_be_bytes());
+        tagged.extend_from_slice(&exif);
+        tagged.extend_from_slice(&jpeg[2..]);
+
+        let processed = process_upload(&tagged).unwrap();
+        assert_eq!((processed.full.width, processed.full.height), (20, 60));
+        assert!(!processed.full.bytes.windows(4).any(|window| window == b"Exif"));
+    }
+}
@@ -0,0 +1,226 @@
+use mongodb::{
+    Collection,
+    bson::{self, doc, oid::ObjectId},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::models::User;
+
+/// Failed attempts allowed from one IP before backoff kicks in
+const IP_FREE_ATTEMPTS: u32 = 5;
+/// First IP backoff delay; doubles with every further failure
+const IP_BASE_DELAY: Duration = Duration::from_secs(2);
+/// Longest an IP is ever made to wait
+const IP_MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+/// Forget an IP's failures after this long without another one
+const IP_FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Consecutive failures before an account is locked
+pub const ACCOUNT_LOCK_THRESHOLD: i32 = 5;
+/// First account lockout; doubles with every further failure
+const ACCOUNT_BASE_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Longest an account stays locked without an admin unlocking it
+const ACCOUNT_MAX_LOCKOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Exponential backoff: `base * 2^(failures - free)`, capped at `max`
+fn exponential_backoff(failures: u32, free: u32, base: Duration, max: Duration) -> Option<Duration> {
+    if failures < free {
+        return None;
+    }
+    let exponent = (failures - free).min(20);
+    Some(base.saturating_mul(1 << exponent).min(max))
+}
+
+/// How long an IP must wait after `failures` consecutive failed logins
+pub fn ip_backoff(failures: u32) -> Option<Duration> {
+    exponential_backoff(failures, IP_FREE_ATTEMPTS, IP_BASE_DELAY, IP_MAX_DELAY)
+}
+
+/// How long an account is locked after `failures` consecutive failed logins
+pub fn account_lockout(failures: i32) -> Option<Duration> {
+    exponential_backoff(
+        failures.max(0) as u32,
+        ACCOUNT_LOCK_THRESHOLD as u32,
+        ACCOUNT_BASE_LOCKOUT,
+        ACCOUNT_MAX_LOCKOUT,
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IpAttempts {
+    failures: u32,
+    last_failure: Instant,
+}
+
+/// Per-IP failed login counter, kept in memory (it only needs to slow a
+/// single attacker down; the durable lockout lives on the User document)
+#[derive(Debug, Clone, Default)]
+pub struct LoginThrottle {
+    attempts: Arc<Mutex<HashMap<IpAddr, IpAttempts>>>,
+}
+
+impl LoginThrottle {
+    /// Time left before this IP may try again, if it is being throttled
+    pub fn retry_after(&self, ip: IpAddr) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let entry = attempts.get(&ip)?;
+        let wait_until = entry.last_failure + ip_backoff(entry.failures)?;
+        wait_until.checked_duration_since(Instant::now())
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        // Drop stale entries so the map can't grow without bound
+        attempts.retain(|_, a| now.duration_since(a.last_failure) < IP_FORGET_AFTER);
+        let entry = attempts.entry(ip).or_insert(IpAttempts {
+            failures: 0,
+            last_failure: now,
+        });
+        entry.failures += 1;
+        entry.last_failure = now;
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        self.attempts.lock().unwrap().remove(&ip);
+    }
+}
+
+/// When the account's lock ends, if it is locked right now
+pub fn locked_until(user: &User) -> Option<bson::DateTime> {
+    user.locked_until.filter(|until| *until > bson::DateTime::now())
+}
+
+/// Count a failed password for this username, locking the account once it
+/// passes the threshold. Unknown usernames are ignored.
+pub async fn record_account_failure(
+    users: &Collection<User>,
+    username: &str,
+) -> mongodb::error::Result<()> {
+    let updated = users
+        .find_one_and_update(
+            doc! { "username": username },
+            doc! { "$inc": { "failed_login_attempts": 1 } },
+        )
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await?;
+
+    if let Some(user) = updated
+        && let Some(lockout) = account_lockout(user.failed_login_attempts)
+    {
+        let until = bson::DateTime::from_millis(
+            bson::DateTime::now().timestamp_millis() + lockout.as_millis() as i64,
+        );
+        users
+            .update_one(
+                doc! { "_id": user.id },
+                doc! { "$set": { "locked_until": until } },
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Clear the failure count and any lock (successful login or admin unlock)
+pub async fn clear_account_failures(
+    users: &Collection<User>,
+    user_id: ObjectId,
+) -> mongodb::error::Result<bool> {
+    let result = users
+        .update_one(
+            doc! { "_id": user_id },
+            doc! {
+                "$set": { "failed_login_attempts": 0 },
+                "$unset": { "locked_until": "" },
+            },
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Human-friendly wait time for error messages
+pub fn describe_wait(wait: Duration) -> String {
+    let secs = wait.as_secs().max(1);
+    if secs < 60 {
+        format!("{} second{}", secs, if secs == 1 { "" } else { "s" })
+    } else {
+        let mins = secs.div_ceil(60);
+        format!("{} minute{}", mins, if mins == 1 { "" } else { "s" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_ip_backoff_allows_free_attempts() {
+        for failures in 0..IP_FREE_ATTEMPTS {
+            assert_eq!(ip_backoff(failures), None);
+        }
+    }
+
+    #[test]
+    fn test_ip_backoff_doubles_then_caps() {
+        assert_eq!(ip_backoff(5), Some(Duration::from_secs(2)));
+        assert_eq!(ip_backoff(6), Some(Duration::from_secs(4)));
+        assert_eq!(ip_backoff(7), Some(Duration::from_secs(8)));
+        assert_eq!(ip_backoff(100), Some(IP_MAX_DELAY));
+    }
+
+    #[test]
+    fn test_account_lockout_after_threshold() {
+        assert_eq!(account_lockout(4), None);
+        assert_eq!(account_lockout(5), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(account_lockout(6), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(account_lockout(50), Some(ACCOUNT_MAX_LOCKOUT));
+        assert_eq!(account_lockout(-1), None);
+    }
+
+    #[test]
+    fn test_throttle_blocks_ip_after_repeated_failures() {
+        let throttle = LoginThrottle::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let other = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8));
+
+        for _ in 0..IP_FREE_ATTEMPTS - 1 {
+            throttle.record_failure(ip);
+        }
+        assert!(throttle.retry_after(ip).is_none());
+
+        throttle.record_failure(ip);
+        assert!(throttle.retry_after(ip).is_some());
+        assert!(throttle.retry_after(other).is_none());
+
+        throttle.record_success(ip);
+        assert!(throttle.retry_after(ip).is_none());
+    }
+
+    #[test]
+    fn test_locked_until_ignores_expired_locks() {
+        let mut user = User {
+            failed_login_attempts: 5,
+            locked_until: Some(bson::DateTime::from_millis(0)),
+            ..User::new("staff@example.com".to_string(), String::new(), false, vec![])
+        };
+        assert!(locked_until(&user).is_none());
+
+        let future = bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + 60_000);
+        user.locked_until = Some(future);
+        assert_eq!(locked_until(&user), Some(future));
+    }
+
+    #[test]
+    fn test_describe_wait() {
+        assert_eq!(describe_wait(Duration::from_secs(1)), "1 second");
+        assert_eq!(describe_wait(Duration::from_secs(30)), "30 seconds");
+        assert_eq!(describe_wait(Duration::from_secs(61)), "2 minutes");
+        assert_eq!(describe_wait(Duration::from_secs(15 * 60)), "15 minutes");
+    }
+}
@@ -0,0 +1,183 @@
+use chrono::{DateTime, Duration, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection,
+    bson::{Document, doc},
+};
+use std::env;
+use tracing::{error, info};
+
+use crate::{email::Mailer, handlers::auth::public_base_url, models::Product};
+
+/// Hour of the day (UTC) the digest goes out when LOW_STOCK_DIGEST_HOUR isn't set
+const DEFAULT_DIGEST_HOUR: u32 = 7;
+
+/// Products at or below their reorder threshold. Products without one
+/// don't match.
+pub fn filter() -> Document {
+    doc! {
+        "reorder_threshold": { "$type": "number" },
+        "$expr": { "$lte": ["$quantity", "$reorder_threshold"] },
+    }
+}
+
+/// Low-stock products, emptiest first
+pub async fn find_low_stock(collection: &Collection<Product>) -> mongodb::error::Result<Vec<Product>> {
+    collection
+        .find(filter())
+        .sort(doc! { "quantity": 1, "name": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Plain-text digest listing each low-stock product with a link to its stock page
+pub fn digest_body(products: &[Product], base_url: &str) -> String {
+    let mut body = format!(
+        "{} product{} at or below {} reorder threshold:\n\n",
+        products.len(),
+        if products.len() == 1 { " is" } else { "s are" },
+        if products.len() == 1 { "its" } else { "their" },
+    );
+    for product in products {
+        body.push_str(&format!(
+            "- {}: {} in stock (reorder at {})\n  {}/products/stock/{}\n",
+            product.name,
+            product.quantity,
+            product.reorder_threshold.unwrap_or_default(),
+            base_url,
+            product.id.to_hex()
+        ));
+    }
+    body.push_str(&format!("\nAll low-stock products: {}/products?stock=low_stock\n", base_url));
+    body
+}
+
+/// How long from `now` until the next `hour`:00 UTC
+pub fn until_next(now: DateTime<Utc>, hour: u32) -> Duration {
+    let today = now
+        .date_naive()
+        .and_hms_opt(hour, 0, 0)
+        .expect("digest hour is validated")
+        .and_utc();
+    let next = if today > now { today } else { today + Duration::days(1) };
+    next - now
+}
+
+/// Daily email listing low-stock products, sent when LOW_STOCK_DIGEST_TO is set
+pub struct LowStockDigest {
+    products: Collection<Product>,
+    mailer: Mailer,
+    recipients: Vec<String>,
+    hour: u32,
+}
+
+impl LowStockDigest {
+    /// Configure from LOW_STOCK_DIGEST_TO (comma-separated addresses) and
+    /// LOW_STOCK_DIGEST_HOUR. None when there's no one to send it to.
+    pub fn from_env(products: Collection<Product>, mailer: Mailer) -> Result<Option<Self>, String> {
+        let recipients: Vec<String> = env::var("LOW_STOCK_DIGEST_TO")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(str::to_string)
+            .collect();
+        if recipients.is_empty() {
+            return Ok(None);
+        }
+        let hour = match env::var("LOW_STOCK_DIGEST_HOUR") {
+            Ok(value) => value
+                .parse::<u32>()
+                .ok()
+                .filter(|hour| *hour < 24)
+                .ok_or_else(|| format!("LOW_STOCK_DIGEST_HOUR must be an hour from 0 to 23, not {}", value))?,
+            Err(_) => DEFAULT_DIGEST_HOUR,
+        };
+        Ok(Some(Self {
+            products,
+            mailer,
+            recipients,
+            hour,
+        }))
+    }
+
+    /// Check stock every day at the configured hour, emailing a digest when
+    /// anything is low
+    pub fn spawn(self) {
+        info!(
+            "📉 Low-stock digest daily at {:02}:00 UTC to {}",
+            self.hour,
+            self.recipients.join(", ")
+        );
+        tokio::spawn(async move {
+            loop {
+                let wait = until_next(Utc::now(), self.hour);
+                tokio::time::sleep(wait.to_std().unwrap_or_default()).await;
+                self.send().await;
+            }
+        });
+    }
+
+    async fn send(&self) {
+        let products = match find_low_stock(&self.products).await {
+            Ok(products) => products,
+            Err(e) => {
+                error!("Low-stock check failed: {}", e);
+                return;
+            }
+        };
+        if products.is_empty() {
+            info!("📉 Nothing is low on stock");
+            return;
+        }
+
+        let subject = format!("Low stock: {} product{}", products.len(), if products.len() == 1 { "" } else { "s" });
+        let body = digest_body(&products, &public_base_url());
+        for recipient in &self.recipients {
+            if let Err(e) = self.mailer.send(recipient, &subject, body.clone()).await {
+                error!("Failed to send low-stock digest to {}: {}", recipient, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::{Currency, Money};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_until_next() {
+        let now = Utc.with_ymd_and_hms(2025, 3, 1, 6, 30, 0).unwrap();
+        assert_eq!(until_next(now, 7), Duration::minutes(30));
+        assert_eq!(until_next(now, 6), Duration::hours(23) + Duration::minutes(30));
+
+        let on_the_hour = Utc.with_ymd_and_hms(2025, 3, 1, 7, 0, 0).unwrap();
+        assert_eq!(until_next(on_the_hour, 7), Duration::days(1));
+    }
+
+    #[test]
+    fn test_digest_body() {
+        let product = Product {
+            name: "Fox Badge".to_string(),
+            price: Money::new(450, Currency::Gbp),
+            quantity: 2,
+            reorder_threshold: Some(5),
+            ..Default::default()
+        };
+        assert!(product.is_low_stock());
+
+        let body = digest_body(std::slice::from_ref(&product), "https://admin.example.com");
+        assert!(body.starts_with("1 product is at or below its reorder threshold:"));
+        assert!(body.contains("- Fox Badge: 2 in stock (reorder at 5)"));
+        assert!(body.contains(&format!("https://admin.example.com/products/stock/{}", product.id.to_hex())));
+
+        let untracked = Product {
+            reorder_threshold: None,
+            ..product
+        };
+        assert!(!untracked.is_low_stock());
+    }
+}
@@ -1,41 +1,82 @@
 use anyhow::Result;
 use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
+    extract::DefaultBodyLimit,
+    middleware::{from_fn, from_fn_with_state},
     routing::{delete, get, post},
     Extension, Router,
 };
 use axum_login::{
-    tower_sessions::{MemoryStore, SessionManagerLayer}, AuthManagerLayerBuilder,
+    tower_sessions::{
+        cookie::{time::Duration, SameSite},
+        Expiry, SessionManagerLayer,
+    },
+    AuthManagerLayerBuilder,
 };
 use dotenv::dotenv;
-use mongodb::{Client, Collection};
+use mongodb::{Client, Collection, Database};
 use std::env;
 use tower::ServiceBuilder;
 use tower_cookies::CookieManagerLayer;
 use tower_http::services::ServeDir;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 // Import modules
+mod api_tokens;
+mod audit;
 mod auth;
+mod cli;
+mod csrf;
+mod email;
+mod image_cleanup;
+mod image_processing;
+mod login_throttle;
+mod low_stock;
+mod middleware;
 mod models;
+mod money;
+mod password_reset;
+mod session_store;
+mod stock;
+mod storage;
+mod two_factor;
 mod user_state;
 mod handlers {
+    pub mod account;
+    pub mod audit;
     pub mod auth;
     pub mod calculator;
+    pub mod categories;
+    pub mod dashboard;
     pub mod order_processing;
+    pub mod product_bulk;
+    pub mod product_import;
     pub mod product_management;
     pub mod quote_processing;
+    pub mod stock;
+    pub mod users;
     pub mod version;
 }
 
-use auth::MongoAuth;
+use auth::{permissions as perms, MongoAuth};
+use clap::Parser;
+use cli::{Cli, Command};
+use api_tokens::ApiTokens;
+use audit::AuditLog;
+use login_throttle::LoginThrottle;
+use low_stock::LowStockDigest;
+use email::Mailer;
+use password_reset::{PasswordResets, ResetThrottle};
+use session_store::MongoSessionStore;
+use stock::StockLedger;
+use storage::ImageStores;
+use two_factor::TwoFactorCipher;
 use handlers::{
-    auth as auth_h, calculator as calc_h, order_processing as op_h, 
-    product_management as pm_h, quote_processing as qp_h, version as ver_h,
+    account as account_h, audit as audit_h, auth as auth_h, calculator as calc_h, categories as cat_h,
+    dashboard as dash_h, order_processing as op_h, product_bulk as bulk_h, product_import as import_h, product_management as pm_h, quote_processing as qp_h, stock as stock_h,
+    users as users_h, version as ver_h,
 };
-use models::{CustomBadgeQuote, Order, Product, User};
+use models::{Category, CustomBadgeQuote, Order, Product, User};
 
 /// Debug function to log directory contents at startup
 async fn debug_log_directories() {
@@ -100,17 +141,22 @@ async fn debug_log_directories() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // Initialize tracing (quieter for the account commands, whose output is for people)
+    let default_filter = match command {
+        Command::Serve => "foxy_fabrications_admin=debug,tower_http=debug",
+        _ => "foxy_fabrications_admin=warn",
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "foxy_fabrications_admin=debug,tower_http=debug".into()),
+                .unwrap_or_else(|_| default_filter.into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    info!("🚀 Starting Foxy Fabrications Admin Server");
-
     // Load environment variables
     dotenv().ok();
 
@@ -123,13 +169,39 @@ async fn main() -> Result<()> {
     db.run_command(mongodb::bson::doc! {"ping": 1}).await?;
     info!("✅ MongoDB connected successfully");
 
-    // Ensure product-images directory exists
-    if let Err(e) = tokio::fs::create_dir_all("product-images").await {
-        info!("⚠️ Failed to create product-images directory: {}. This may cause upload issues.", e);
-    } else {
-        info!("✅ Product-images directory ready for uploads");
+    match command {
+        Command::Serve => serve(db).await,
+        command => cli::run(command, &db).await,
     }
-    
+}
+
+/// Bulk product edits and imports use the client bulk write command, which
+/// needs MongoDB 8.0 or newer; say so at startup rather than on first use
+async fn warn_if_bulk_writes_unsupported(db: &Database) {
+    let version = match db.run_command(mongodb::bson::doc! { "buildInfo": 1 }).await {
+        Ok(info) => info.get_str("version").unwrap_or_default().to_string(),
+        Err(e) => {
+            warn!("Couldn't read the MongoDB server version: {}", e);
+            return;
+        }
+    };
+    let major = version.split('.').next().and_then(|major| major.parse::<u32>().ok());
+    if major.is_some_and(|major| major < 8) {
+        warn!(
+            "⚠️ MongoDB {} is older than 8.0 - bulk product edits and imports will fail until it's upgraded",
+            version
+        );
+    }
+}
+
+/// Run the admin web server
+async fn serve(db: Database) -> Result<()> {
+    info!("🚀 Starting Foxy Fabrications Admin Server");
+    warn_if_bulk_writes_unsupported(&db).await;
+
+    // Product images and badge uploads, on local disk or in an S3-compatible bucket
+    let image_stores = ImageStores::from_env().await.map_err(anyhow::Error::msg)?;
+
     // Debug: Log directory contents for troubleshooting
     debug_log_directories().await;
 
@@ -138,61 +210,196 @@ async fn main() -> Result<()> {
     let products_coll: Collection<Product> = db.collection("products");
     let orders_coll: Collection<Order> = db.collection("orders");
     let badge_quotes_coll: Collection<CustomBadgeQuote> = db.collection("badge_quotes");
+    let categories_coll: Collection<Category> = db.collection("categories");
+
+    // Setup session store and auth. Sessions live in MongoDB so staff stay
+    // logged in across restarts and every replica sees the same sessions.
+    let session_store = MongoSessionStore::new(&db);
+    session_store.ensure_indexes().await?;
+    info!("✅ Session store ready (collection: {})", session_store::SESSIONS_COLLECTION);
 
-    // Setup session store and auth
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store);
+    let session_lifetime_hours: i64 = env::var("SESSION_LIFETIME_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(12);
+    // Secure cookies need HTTPS, so only enable them where the admin is served over TLS
+    let secure_cookies = env::var("SESSION_SECURE_COOKIE")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    info!(
+        "🍪 Sessions expire after {}h of inactivity (secure cookies: {})",
+        session_lifetime_hours, secure_cookies
+    );
+
+    let session_layer = SessionManagerLayer::new(session_store.clone())
+        .with_name("foxy_admin_session")
+        .with_expiry(Expiry::OnInactivity(Duration::hours(session_lifetime_hours)))
+        .with_secure(secure_cookies)
+        .with_http_only(true)
+        .with_same_site(SameSite::Lax);
     let auth_backend = MongoAuth {
         users: users_coll.clone(),
     };
     let auth_layer = AuthManagerLayerBuilder::new(auth_backend, session_layer).build();
 
-    // Protected admin routes - each handler manages its own auth to allow redirects
-    let protected_admin_routes = Router::new()
-        // Product Management Routes
+    // Outgoing email (password resets); disabled until SMTP_HOST is set
+    let mailer = Mailer::from_env().map_err(anyhow::Error::msg)?;
+    if !mailer.is_configured() {
+        info!("⚠️ SMTP_HOST not set - password reset emails disabled");
+    }
+    // Daily email of products at or below their reorder threshold
+    if let Some(digest) = LowStockDigest::from_env(products_coll.clone(), mailer.clone()).map_err(anyhow::Error::msg)? {
+        digest.spawn();
+    }
+    let password_resets = PasswordResets::new(&db);
+    password_resets.ensure_indexes().await?;
+
+    // TOTP secrets are encrypted with this key; without it 2FA can't be enrolled
+    let two_factor_cipher = TwoFactorCipher::from_env().map_err(anyhow::Error::msg)?;
+    if two_factor_cipher.is_configured() {
+        info!("🔑 Two-factor authentication available");
+    } else {
+        info!("⚠️ TOTP_ENCRYPTION_KEY not set - two-factor enrolment disabled");
+    }
+
+    // Personal API tokens, accepted as `Authorization: Bearer` on the admin routes
+    let api_tokens = ApiTokens::new(&db, users_coll.clone());
+    api_tokens.ensure_indexes().await?;
+
+    // Who did what: logins and admin changes
+    let audit_log = AuditLog::new(&db);
+    audit_log.ensure_indexes().await?;
+    let stock_ledger = StockLedger::new(&db);
+    stock_ledger.ensure_indexes().await?;
+    cat_h::ensure_indexes(&categories_coll).await?;
+
+    // Protected admin routes, grouped by the permission each one needs. Every
+    // route also sits behind require_staff, so nothing here can be left open.
+    let product_view_routes = Router::new()
         .route("/products", get(pm_h::list_products))
+        .route("/products/export", get(import_h::export_products))
+        .route("/products/stock/{id}", get(stock_h::show_stock_history))
+        .route("/categories", get(cat_h::list_categories))
+        .route_layer(from_fn_with_state(perms::PRODUCTS_VIEW, middleware::require_permission));
+
+    let product_edit_routes = Router::new()
         .route("/products/new", get(pm_h::show_create_form).post(pm_h::create_product))
         .route("/products/edit/{id}", get(pm_h::show_edit_form).post(pm_h::update_product))
         .route("/products/delete/{id}", delete(pm_h::delete_product))
-        // Order Processing Routes
+        .route("/products/import", get(import_h::show_import_form).post(import_h::preview_import))
+        .route("/products/import/apply", post(import_h::apply_import))
+        .route("/products/bulk", post(bulk_h::bulk_update_products))
+        .route("/products/stock/{id}/adjust", post(stock_h::adjust_stock))
+        .route("/categories/new", get(cat_h::show_create_form).post(cat_h::create_category))
+        .route("/categories/edit/{id}", get(cat_h::show_edit_form).post(cat_h::update_category))
+        .route("/categories/delete/{id}", delete(cat_h::delete_category))
+        .route_layer(from_fn_with_state(perms::PRODUCTS_EDIT, middleware::require_permission))
+        // Room for several images per request
+        .layer(DefaultBodyLimit::max(pm_h::MAX_PRODUCT_UPLOAD_BYTES));
+
+    let order_view_routes = Router::new()
         .route("/orders", get(op_h::list_orders))
+        .route_layer(from_fn_with_state(perms::ORDERS_VIEW, middleware::require_permission));
+
+    let order_update_routes = Router::new()
         .route("/orders/update-status", post(op_h::update_order_status))
-        // Quote Processing Routes
+        .route_layer(from_fn_with_state(perms::ORDERS_UPDATE, middleware::require_permission));
+
+    let quote_view_routes = Router::new()
         .route("/quotes", get(qp_h::list_quotes))
-        .route("/quotes/update-status", post(qp_h::update_quote_status))
         .route("/quotes/image/{filename}", get(qp_h::serve_badge_image))
-        // Admin Tools
+        // The calculator is used for pricing badge quotes
         .route("/calculator", get(calc_h::show_calculator))
-        .layer(Extension(products_coll.clone()))
-        .layer(Extension(users_coll.clone()))
-        .layer(Extension(orders_coll.clone()))
-        .layer(Extension(badge_quotes_coll.clone()))
-        .layer(Extension(db.clone()));
-    
-    // Dashboard route (handles its own auth to redirect properly)
-    let dashboard_routes = Router::new()
-        .route("/", get(dashboard))
+        .route_layer(from_fn_with_state(perms::QUOTES_VIEW, middleware::require_permission));
+
+    let quote_update_routes = Router::new()
+        .route("/quotes/update-status", post(qp_h::update_quote_status))
+        .route_layer(from_fn_with_state(perms::QUOTES_UPDATE, middleware::require_permission));
+
+    let user_admin_routes = Router::new()
+        .route("/users", get(users_h::list_users))
+        .route("/users/new", get(users_h::show_create_form).post(users_h::create_user))
+        .route("/users/edit/{id}", get(users_h::show_edit_form).post(users_h::update_user))
+        .route("/users/edit/{id}/password", post(users_h::reset_password))
+        .route("/users/delete/{id}", delete(users_h::delete_user))
+        .route("/users/{id}/unlock", post(users_h::unlock_user))
+        .route("/users/{id}/reset-2fa", post(users_h::reset_two_factor))
+        .route_layer(from_fn_with_state(perms::USERS_MANAGE, middleware::require_permission));
+
+    let audit_routes = Router::new()
+        .route("/audit", get(audit_h::list_audit_log))
+        .route("/audit/export", get(audit_h::export_audit_log))
+        .route_layer(from_fn_with_state(perms::AUDIT_VIEW, middleware::require_permission));
+
+    // Account settings are for every staff member, so no permission group,
+    // but they need a browser session rather than an API token
+    let account_routes = Router::new()
+        .route("/account/password", get(account_h::show_change_password_form).post(account_h::change_password))
+        .route("/account/2fa", get(account_h::show_two_factor_settings))
+        .route("/account/2fa/setup", post(account_h::start_two_factor_setup))
+        .route("/account/2fa/enable", post(account_h::enable_two_factor))
+        .route("/account/2fa/recovery-codes", post(account_h::regenerate_recovery_codes))
+        .route("/account/2fa/disable", post(account_h::disable_two_factor))
+        .route("/account/tokens", get(account_h::show_api_tokens).post(account_h::create_api_token))
+        .route("/account/tokens/{id}/revoke", post(account_h::revoke_api_token))
+        .route("/account/sessions", get(account_h::show_sessions))
+        .route("/account/sessions/{id}/revoke", post(account_h::revoke_session))
+        .route("/account/sessions/revoke-all", post(account_h::logout_everywhere))
+        .route_layer(from_fn(middleware::require_session));
+
+    let protected_admin_routes = Router::new()
+        .route("/", get(dash_h::show_dashboard))
+        .merge(account_routes)
+        .merge(product_view_routes)
+        .merge(product_edit_routes)
+        .merge(order_view_routes)
+        .merge(order_update_routes)
+        .merge(quote_view_routes)
+        .merge(quote_update_routes)
+        .merge(user_admin_routes)
+        .merge(audit_routes)
+        .route_layer(from_fn(session_store::track_session))
+        .route_layer(from_fn(csrf::csrf_protect))
+        .route_layer(from_fn(middleware::require_staff))
+        .route_layer(from_fn(api_tokens::bearer_auth))
         .layer(Extension(products_coll))
+        .layer(Extension(categories_coll))
         .layer(Extension(users_coll))
         .layer(Extension(orders_coll))
         .layer(Extension(badge_quotes_coll))
+        .layer(Extension(two_factor_cipher.clone()))
+        .layer(Extension(api_tokens))
+        .layer(Extension(audit_log.clone()))
+        .layer(Extension(stock_ledger))
+        .layer(Extension(session_store.clone()))
+        .layer(Extension(image_stores.clone()))
         .layer(Extension(db.clone()));
 
     // Public routes (login and info/health)
     let public_routes = Router::new()
         .route("/login", get(auth_h::show_login_form).post(auth_h::handle_login))
+        .route("/login/2fa", get(auth_h::show_two_factor_form).post(auth_h::handle_two_factor))
+        .route("/forgot-password", get(auth_h::show_forgot_password_form).post(auth_h::handle_forgot_password))
+        .route("/reset-password", get(auth_h::show_reset_password_form).post(auth_h::handle_reset_password))
         .route("/logout", get(auth_h::handle_logout))
         .route("/info", get(ver_h::info))
-        .route("/health", get(ver_h::health));
+        .route("/health", get(ver_h::health))
+        .layer(Extension(LoginThrottle::default()))
+        .layer(Extension(ResetThrottle::default()))
+        .layer(Extension(two_factor_cipher))
+        .layer(Extension(mailer))
+        .layer(Extension(password_resets))
+        .layer(Extension(audit_log))
+        .layer(Extension(session_store));
 
     // Static files and product images
     let static_routes = Router::new()
         .nest_service("/static", ServeDir::new("static"))
-        .nest_service("/product-images", ServeDir::new("product-images"));
+        .route("/product-images/{filename}", get(pm_h::serve_product_image))
+        .layer(Extension(image_stores));
 
     // Main app
     let app = Router::new()
-        .merge(dashboard_routes)
         .merge(protected_admin_routes)
         .merge(public_routes) 
         .merge(static_routes)
@@ -218,21 +425,3 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
-
-/// Admin dashboard homepage
-async fn dashboard(auth: auth_h::AppAuthSession) -> impl IntoResponse {
-    let user_state = user_state::extract_user_state(&auth);
-    
-    // Redirect unauthenticated users to login
-    if !user_state.is_authenticated {
-        return Redirect::to("/login").into_response();
-    }
-    
-    // Ensure user is admin
-    if !user_state.is_admin {
-        return (StatusCode::FORBIDDEN, "Access denied - Admin privileges required").into_response();
-    }
-    
-    // Redirect to products page for now (could be a proper dashboard later)
-    Redirect::to("/products").into_response()
-}
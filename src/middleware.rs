@@ -0,0 +1,189 @@
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use axum_login::AuthzBackend;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{api_tokens::ApiPrincipal, handlers::auth::AppAuthSession};
+
+/// JSON body returned to fetch() callers when a guard rejects the request
+#[derive(Serialize, Debug, Clone)]
+pub struct GuardResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+/// Browser navigations and form posts ask for HTML; the admin pages' fetch
+/// calls don't, so they get JSON errors they can show inline instead.
+pub fn wants_html(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|accept| accept.contains("text/html"))
+        .unwrap_or(false)
+}
+
+/// Build the login URL, remembering where the user was heading
+fn login_redirect(request: &Request) -> Redirect {
+    let next = request
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    Redirect::to(&format!("/login?next={}", urlencoding::encode(next)))
+}
+
+/// Rejection for a request that has no logged-in user
+pub fn unauthenticated(request: &Request) -> Response {
+    if wants_html(request) {
+        login_redirect(request).into_response()
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(GuardResponse {
+                success: false,
+                message: "Authentication required".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Rejection for a logged-in user lacking the required privileges
+pub fn forbidden(request: &Request) -> Response {
+    if wants_html(request) {
+        (StatusCode::FORBIDDEN, "Access denied - your account does not have permission for this").into_response()
+    } else {
+        (
+            StatusCode::FORBIDDEN,
+            Json(GuardResponse {
+                success: false,
+                message: "Access denied".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Guard for every admin route: requires a logged-in user with staff access
+/// (or a valid API token, which `api_tokens::bearer_auth` has already checked)
+pub async fn require_staff(auth: AppAuthSession, request: Request, next: Next) -> Response {
+    if request.extensions().get::<ApiPrincipal>().is_some() {
+        return next.run(request).await;
+    }
+    match auth.user {
+        None => unauthenticated(&request),
+        Some(ref user) if !user.has_staff_access() => forbidden(&request),
+        Some(_) => next.run(request).await,
+    }
+}
+
+/// Per-route permission guard, used as
+/// `route_layer(from_fn_with_state(permissions::ORDERS_UPDATE, require_permission))`
+pub async fn require_permission(
+    State(permission): State<&'static str>,
+    auth: AppAuthSession,
+    request: Request,
+    next: Next,
+) -> Response {
+    // API tokens are limited to their scopes rather than the owner's full access
+    if let Some(principal) = request.extensions().get::<ApiPrincipal>() {
+        return if principal.permissions.contains(permission) {
+            next.run(request).await
+        } else {
+            forbidden(&request)
+        };
+    }
+
+    let Some(ref user) = auth.user else {
+        return unauthenticated(&request);
+    };
+
+    match auth.backend.has_perm(user, permission.to_string()).await {
+        Ok(true) => next.run(request).await,
+        Ok(false) => forbidden(&request),
+        Err(e) => {
+            error!("Permission check for {} failed: {}", permission, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Permission check failed").into_response()
+        }
+    }
+}
+
+/// Guard for account pages (passwords, 2FA, tokens), which API tokens can't use
+pub async fn require_session(request: Request, next: Next) -> Response {
+    if request.extensions().get::<ApiPrincipal>().is_some() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(GuardResponse {
+                success: false,
+                message: "Account settings can't be changed with an API token".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request_with_accept(accept: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri("/orders?page=2");
+        if let Some(accept) = accept {
+            builder = builder.header(header::ACCEPT, accept);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_wants_html_for_browser_navigation() {
+        let request = request_with_accept(Some("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(wants_html(&request));
+    }
+
+    #[test]
+    fn test_wants_json_for_fetch_calls() {
+        assert!(!wants_html(&request_with_accept(Some("*/*"))));
+        assert!(!wants_html(&request_with_accept(Some("application/json"))));
+        assert!(!wants_html(&request_with_accept(None)));
+    }
+
+    #[test]
+    fn test_unauthenticated_html_redirects_to_login_with_next() {
+        let request = request_with_accept(Some("text/html"));
+        let response = unauthenticated(&request);
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            "/login?next=%2Forders%3Fpage%3D2"
+        );
+    }
+
+    #[test]
+    fn test_unauthenticated_json_returns_401() {
+        let request = request_with_accept(Some("*/*"));
+        let response = unauthenticated(&request);
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_forbidden_returns_403_for_both_kinds() {
+        assert_eq!(
+            forbidden(&request_with_accept(Some("text/html"))).status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            forbidden(&request_with_accept(None)).status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -1,6 +1,12 @@
 use askama::Template;
 use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::{
+    audit::{AuditFilter, FieldChange},
+    money::Money,
+};
 
 /// —————————————————————————————
 /// User model (Mongo "users" collection)
@@ -12,6 +18,92 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
     pub is_admin: bool,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Consecutive failed logins, reset on success or admin unlock
+    #[serde(default)]
+    pub failed_login_attempts: i32,
+    /// Set once failed logins pass the lockout threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<mongodb::bson::DateTime>,
+    /// AES-GCM encrypted TOTP secret (see two_factor.rs); None when 2FA is off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// Last TOTP time step accepted, so a code can't be used twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_last_step: Option<i64>,
+    /// SHA-256 hashes of the unused recovery codes
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Disabled accounts can't log in and their sessions stop working
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl User {
+    /// A new account: not locked, without 2FA
+    pub fn new(username: String, password_hash: String, is_admin: bool, roles: Vec<Role>) -> Self {
+        Self {
+            id: ObjectId::new(),
+            username,
+            password_hash,
+            is_admin,
+            roles,
+            failed_login_attempts: 0,
+            locked_until: None,
+            totp_secret: None,
+            totp_last_step: None,
+            recovery_codes: vec![],
+            disabled: false,
+        }
+    }
+
+    /// Whether the user may use the admin app at all (legacy admins or any staff role)
+    pub fn has_staff_access(&self) -> bool {
+        self.is_admin || !self.roles.is_empty()
+    }
+
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+}
+
+/// Staff roles; each grants a fixed group of permissions (see `auth::permissions`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Fulfilment,
+    Quotes,
+    Viewer,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Owner, Role::Fulfilment, Role::Quotes, Role::Viewer];
+
+    /// Stored form, as used in forms and the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Fulfilment => "fulfilment",
+            Role::Quotes => "quotes",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.as_str() == value)
+    }
+
+    /// Name shown on the admin pages
+    pub fn label(&self) -> &'static str {
+        match self {
+            Role::Owner => "Owner",
+            Role::Fulfilment => "Fulfilment",
+            Role::Quotes => "Quotes",
+            Role::Viewer => "Viewer",
+        }
+    }
 }
 
 /// Login form (extract from /login)
@@ -26,31 +118,207 @@ pub struct Credentials {
 #[derive(Debug, Clone, Default)]
 pub struct UserState {
     pub is_authenticated: bool,
+    pub username: String,
     pub is_admin: bool,
+    pub permissions: HashSet<String>,
+    /// Per-session token for forms and fetch calls (empty when logged out)
+    pub csrf_token: String,
 }
 
 impl UserState {
-    pub fn new(is_authenticated: bool, _username: String, is_admin: bool) -> Self {
+    pub fn new(is_authenticated: bool, username: String, is_admin: bool) -> Self {
         Self {
             is_authenticated,
+            username,
             is_admin,
+            permissions: HashSet::new(),
+            csrf_token: String::new(),
         }
     }
+
+    /// Attach the session's CSRF token
+    pub fn with_csrf_token(mut self, csrf_token: String) -> Self {
+        self.csrf_token = csrf_token;
+        self
+    }
+
+    /// Attach the user's effective permissions
+    pub fn with_permissions(mut self, permissions: HashSet<String>) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Permission check for templates, e.g. `user_state.can("products:edit")`
+    pub fn can(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
+    }
 }
 
 /// —————————————————————————————
 /// Product model (Mongo "products" collection)
 /// —————————————————————————————
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Product {
     #[serde(rename = "_id")]
     pub id: ObjectId,
     pub name: String,
+    /// Primary image, used by the storefront; always the first of `images`
     pub image_url: String,
-    pub price: String,
+    pub price: Money,
     pub quantity: i32,
     pub description: String,
     pub adoptable: bool,
+    /// Ordered gallery. Empty on products created before galleries existed.
+    #[serde(default)]
+    pub images: Vec<ProductImage>,
+    /// One of the managed categories, if assigned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_id: Option<ObjectId>,
+    /// Free-form labels, stored lowercase
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Stock-keeping code, used to match rows on import. Variants have their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
+    /// Stock at or below which the product needs reordering; None to not track it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reorder_threshold: Option<i32>,
+    /// Option axes such as colour or size; empty for a product sold one way
+    #[serde(default)]
+    pub options: Vec<ProductOption>,
+    /// One row per combination of option values that's for sale. When there
+    /// are variants, `quantity` is the total of their stock.
+    #[serde(default)]
+    pub variants: Vec<ProductVariant>,
+}
+
+impl Product {
+    /// The gallery, falling back to the lone `image_url` on older products
+    pub fn gallery(&self) -> Vec<ProductImage> {
+        if self.images.is_empty() && !self.image_url.is_empty() {
+            vec![ProductImage::from(self.image_url.clone())]
+        } else {
+            self.images.clone()
+        }
+    }
+
+    /// At or below its reorder threshold
+    pub fn is_low_stock(&self) -> bool {
+        self.reorder_threshold.is_some_and(|threshold| self.quantity <= threshold)
+    }
+}
+
+/// An option axis, e.g. "Colour" with values "Red", "Blue"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProductOption {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+/// One purchasable combination of option values, with its own stock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProductVariant {
+    pub sku: String,
+    /// One value per option axis, in the same order as `Product::options`
+    pub option_values: Vec<String>,
+    /// Added to the product's price; negative for a cheaper variant
+    pub price_delta: Money,
+    pub quantity: i32,
+}
+
+impl ProductVariant {
+    /// The option values as shown to staff, e.g. "Red / Large"
+    pub fn label(&self) -> String {
+        self.option_values.join(" / ")
+    }
+}
+
+/// A gallery image and its resized variants
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredProductImage")]
+pub struct ProductImage {
+    pub url: String,
+    /// Missing on images uploaded before uploads were processed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+impl From<String> for ProductImage {
+    fn from(url: String) -> Self {
+        Self {
+            url,
+            thumbnail_url: None,
+            width: None,
+            height: None,
+        }
+    }
+}
+
+/// Gallery entries were plain URLs before thumbnails were added
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredProductImage {
+    Url(String),
+    Image {
+        url: String,
+        #[serde(default)]
+        thumbnail_url: Option<String>,
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+    },
+}
+
+impl From<StoredProductImage> for ProductImage {
+    fn from(stored: StoredProductImage) -> Self {
+        match stored {
+            StoredProductImage::Url(url) => url.into(),
+            StoredProductImage::Image {
+                url,
+                thumbnail_url,
+                width,
+                height,
+            } => Self {
+                url,
+                thumbnail_url,
+                width,
+                height,
+            },
+        }
+    }
+}
+
+/// A gallery image for templates; `thumbnail_url` falls back to the full image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductImageDisplay {
+    pub url: String,
+    pub thumbnail_url: String,
+}
+
+/// An option axis for templates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductOptionDisplay {
+    pub name: String,
+    /// Comma-separated, as typed into the edit form
+    pub values: String,
+}
+
+/// A variant row for templates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductVariantDisplay {
+    pub sku: String,
+    /// Option values joined with " / "
+    pub label: String,
+    /// As entered in the form, e.g. "-0.50"
+    pub price_delta: String,
+    /// The product's price plus the delta, e.g. "£4.00"
+    pub formatted_price: String,
+    pub quantity: i32,
 }
 
 /// A display‐safe version for Askama
@@ -59,10 +327,108 @@ pub struct ProductDisplay {
     pub id: String,
     pub name: String,
     pub image_url: String,
+    /// Thumbnail of the primary image, for listings
+    pub thumbnail_url: String,
+    pub images: Vec<ProductImageDisplay>,
+    /// As entered in the form, e.g. "4.50"
     pub price: String,
+    /// With the currency symbol, e.g. "£4.50"
+    pub formatted_price: String,
+    pub currency_symbol: String,
     pub quantity: i32,
     pub description: String,
     pub adoptable: bool,
+    /// Empty when uncategorised
+    pub category_name: String,
+    pub tags: Vec<String>,
+    /// Empty when not set
+    pub sku: String,
+    /// Empty when not tracked
+    pub reorder_threshold: String,
+    pub low_stock: bool,
+    pub options: Vec<ProductOptionDisplay>,
+    pub variants: Vec<ProductVariantDisplay>,
+}
+
+impl ProductDisplay {
+    /// Tags as typed into the product form
+    pub fn tags_text(&self) -> String {
+        self.tags.join(", ")
+    }
+}
+
+/// —————————————————————————————
+/// Category model (Mongo "categories" collection)
+/// —————————————————————————————
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Category {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+    /// URL-friendly name, used in the product list's filter links
+    pub slug: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A category row on the categories page
+#[derive(Debug, Clone, Default)]
+pub struct CategoryDisplay {
+    pub id: String,
+    pub name: String,
+    pub slug: String,
+    pub description: String,
+    pub product_count: u64,
+}
+
+/// Query parameters for the category list
+#[derive(Debug, Deserialize)]
+pub struct CategoryQueryParams {
+    pub success: Option<String>,
+}
+
+/// Create/edit category form
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CategoryForm {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// An entry in a category or tag <select>
+#[derive(Debug, Clone)]
+pub struct SelectOption {
+    pub value: String,
+    pub label: String,
+    pub selected: bool,
+}
+
+/// Template for the category list
+#[derive(Template)]
+#[template(path = "categories.html")]
+pub struct CategoriesTemplate {
+    pub categories: Vec<CategoryDisplay>,
+    pub user_state: UserState,
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// Template for creating or editing a category
+#[derive(Template)]
+#[template(path = "category_form.html")]
+pub struct CategoryFormTemplate {
+    pub user_state: UserState,
+    /// Empty id for a new category
+    pub category: CategoryDisplay,
+    pub error_message: String,
+}
+
+/// Response for category operations (JSON)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CategoryOperationResponse {
+    pub success: bool,
+    pub message: String,
+    pub category_id: Option<String>,
 }
 
 /// Create product form
@@ -93,8 +459,11 @@ pub struct OrderItem {
     #[serde(rename = "name")]
     pub product_name: String, // MongoDB field is "name", we want "product_name" in Rust
     pub quantity: i32,
-    pub price: f64,
-    pub line_total: f64,
+    pub price: Money,
+    pub line_total: Money,
+    /// The variant ordered, for products sold in variants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -106,13 +475,17 @@ pub struct Order {
     pub customer_email: String,
     pub shipping_address: ShippingAddress,
     pub items: Vec<OrderItem>,
-    pub subtotal: f64,
-    pub shipping_cost: f64,
-    pub total: f64,
+    pub subtotal: Money,
+    pub shipping_cost: Money,
+    pub total: Money,
+    /// ISO 4217 code; each amount also records its own currency
     pub currency: String,
     pub status: String,
     pub created_at: String,
     pub updated_at: String,
+    /// Whether the order's items have been taken out of stock
+    #[serde(default)]
+    pub stock_deducted: bool,
 }
 
 /// —————————————————————————————
@@ -128,7 +501,7 @@ pub struct CustomBadgeQuote {
     pub thickness: String,
     pub email: String,
     pub image_path: Option<String>,
-    pub estimated_price: f64,
+    pub estimated_price: Money,
     pub created_at: String,
     pub updated_at: String,
     pub status: String,
@@ -137,15 +510,65 @@ pub struct CustomBadgeQuote {
 /// —————————————————————————————
 /// Product Management Models
 /// —————————————————————————————
-
 /// Form for editing existing products
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct EditProductForm {
     pub name: String,
     pub price: String,
     pub quantity: String,
     pub description: String,
     pub adoptable: Option<String>, // "on" if checked, None if unchecked
+    /// Category id, or empty for none
+    #[serde(default)]
+    pub category: String,
+    /// Comma-separated
+    #[serde(default)]
+    pub tags: String,
+    /// Empty for none
+    #[serde(default)]
+    pub sku: String,
+    /// Empty to not track
+    #[serde(default)]
+    pub reorder_threshold: String,
+}
+
+/// Query parameters for the product list
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProductQueryParams {
+    pub success: Option<String>,
+    /// Text to find in the name or description
+    pub q: Option<String>,
+    /// Category slug, or "none" for uncategorised products
+    pub category: Option<String>,
+    pub tag: Option<String>,
+    /// "in_stock" or "out_of_stock"
+    pub stock: Option<String>,
+    /// "adoptable" or "product"
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// "name", "price" or "quantity", with "_desc" for descending
+    pub sort: Option<String>,
+    pub page: Option<u32>,
+}
+
+impl ProductQueryParams {
+    /// The search, filters and sort as a query string, for pagination links
+    pub fn query_string(&self) -> String {
+        [
+            ("q", &self.q),
+            ("category", &self.category),
+            ("tag", &self.tag),
+            ("stock", &self.stock),
+            ("type", &self.kind),
+            ("sort", &self.sort),
+        ]
+        .iter()
+        .filter_map(|(key, value)| Some((key, value.as_deref()?.trim())))
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+    }
 }
 
 /// Template for product management list page
@@ -153,9 +576,21 @@ pub struct EditProductForm {
 #[template(path = "product_management.html")]
 pub struct ProductManagementTemplate {
     pub products: Vec<ProductDisplay>,
+    pub pagination: PaginationInfo,
     pub user_state: UserState,
     pub success_message: String,
     pub error_message: String,
+    pub search: String,
+    pub category_filter: Vec<SelectOption>,
+    pub tag_filter: Vec<SelectOption>,
+    pub stock_filter: Vec<SelectOption>,
+    pub type_filter: Vec<SelectOption>,
+    pub sort_options: Vec<SelectOption>,
+    /// Categories for the bulk "Set category" action, by id
+    pub bulk_categories: Vec<SelectOption>,
+    pub is_filtered: bool,
+    /// Search, filters and sort, for pagination links
+    pub query_string: String,
 }
 
 /// Template for editing a product
@@ -163,6 +598,7 @@ pub struct ProductManagementTemplate {
 #[template(path = "edit_product.html")]
 pub struct EditProductTemplate {
     pub product: ProductDisplay,
+    pub categories: Vec<SelectOption>,
     pub user_state: UserState,
     pub error_message: String,
 }
@@ -172,9 +608,161 @@ pub struct EditProductTemplate {
 #[template(path = "create_products.html")]
 pub struct CreateProductTemplate {
     pub user_state: UserState,
+    pub categories: Vec<SelectOption>,
     pub error: String,
 }
 
+/// Query parameters for the product export, alongside the list's filters
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProductExportParams {
+    /// "csv" (the default) or "json"
+    pub format: Option<String>,
+}
+
+/// The preview form's hidden copy of the uploaded rows, as JSON
+#[derive(Debug, Deserialize)]
+pub struct ProductImportApplyForm {
+    pub rows: String,
+}
+
+/// One row of an import preview
+#[derive(Debug, Clone, Default)]
+pub struct ImportRowDisplay {
+    /// 1-based, not counting the CSV header
+    pub row: usize,
+    /// The id or SKU the row was matched on, or its name for new products
+    pub key: String,
+    pub name: String,
+    /// What will change, or why the row can't be imported
+    pub details: String,
+    pub warning: String,
+}
+
+/// Template for uploading a product import and previewing it
+#[derive(Template)]
+#[template(path = "product_import.html")]
+pub struct ProductImportTemplate {
+    pub user_state: UserState,
+    pub error_message: String,
+    pub has_preview: bool,
+    pub creates: Vec<ImportRowDisplay>,
+    pub updates: Vec<ImportRowDisplay>,
+    pub errors: Vec<ImportRowDisplay>,
+    pub unchanged: usize,
+    /// The parsed rows, posted back to apply the import
+    pub rows_json: String,
+}
+
+/// A bulk action from the product list, applied to each selected product
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkProductRequest {
+    pub product_ids: Vec<String>,
+    /// "set_quantity", "adjust_price", "set_adoptable", "set_category" or "delete"
+    pub action: String,
+    /// A quantity, a price change such as "1.50", "-2" or "10%", "true" or
+    /// "false", or a category id (empty for none)
+    #[serde(default)]
+    pub value: String,
+}
+
+/// What a bulk action did to one product
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulkItemResult {
+    pub product_id: String,
+    pub name: String,
+    /// "updated", "unchanged", "deleted", "skipped" or "failed"
+    pub outcome: String,
+    pub message: String,
+}
+
+/// Response for bulk product actions (JSON)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkProductResponse {
+    pub success: bool,
+    pub message: String,
+    pub results: Vec<BulkItemResult>,
+}
+
+/// A product at or below its reorder threshold, for the dashboard
+#[derive(Debug, Clone)]
+pub struct LowStockDisplay {
+    pub id: String,
+    pub name: String,
+    pub quantity: i32,
+    pub reorder_threshold: i32,
+}
+
+/// Template for the dashboard
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+pub struct DashboardTemplate {
+    pub user_state: UserState,
+    /// The emptiest few low-stock products
+    pub low_stock: Vec<LowStockDisplay>,
+    pub low_stock_total: usize,
+    pub error_message: String,
+}
+
+/// Page number and outcome for a product's stock history
+#[derive(Debug, Deserialize)]
+pub struct StockPageParams {
+    pub page: Option<u32>,
+    pub success: Option<String>,
+}
+
+/// Form data for changing a product's stock by hand
+#[derive(Debug, Deserialize)]
+pub struct StockAdjustmentForm {
+    /// "adjustment", "return" or "stocktake"
+    pub kind: String,
+    /// The variant to change; empty for the product's own stock
+    #[serde(default)]
+    pub sku: String,
+    /// The change for an adjustment or return, or the count for a stocktake
+    pub amount: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// One stock level on the stock page
+#[derive(Debug, Clone)]
+pub struct StockLineDisplay {
+    /// Empty for the product's own stock
+    pub sku: String,
+    pub label: String,
+    pub quantity: i32,
+}
+
+/// Stock movement for the stock page
+#[derive(Debug, Clone)]
+pub struct StockMovementDisplay {
+    pub formatted_timestamp: String,
+    pub kind: String,
+    pub sku: String,
+    /// Signed, e.g. "+3" or "-1"
+    pub change: String,
+    pub quantity_after: i32,
+    pub reason: String,
+    pub order_reference: String,
+    pub actor: String,
+}
+
+/// Template for a product's stock levels, adjustments and history
+#[derive(Template)]
+#[template(path = "product_stock.html")]
+pub struct ProductStockTemplate {
+    pub user_state: UserState,
+    pub product_id: String,
+    pub product_name: String,
+    pub total_quantity: i32,
+    pub lines: Vec<StockLineDisplay>,
+    pub has_variants: bool,
+    pub movements: Vec<StockMovementDisplay>,
+    pub pagination: PaginationInfo,
+    pub success_message: String,
+    pub error_message: String,
+}
+
 /// Response for product operations (JSON)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProductOperationResponse {
@@ -186,7 +774,6 @@ pub struct ProductOperationResponse {
 /// —————————————————————————————
 /// Order Processing Models
 /// —————————————————————————————
-
 /// Display version of ShippingAddress for templates (all strings, no Options)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShippingAddressDisplay {
@@ -206,9 +793,9 @@ pub struct OrderDisplay {
     pub customer_email: String,
     pub shipping_address: ShippingAddressDisplay,
     pub items: Vec<OrderItem>,
-    pub subtotal: f64,
-    pub shipping_cost: f64,
-    pub total: f64,
+    pub subtotal: Money,
+    pub shipping_cost: Money,
+    pub total: Money,
     pub currency: String,
     pub status: String,
     pub created_at: String,
@@ -216,6 +803,8 @@ pub struct OrderDisplay {
     pub formatted_total: String,
     pub formatted_created_at: String,
     pub status_class: String, // CSS class for status badge
+    /// Items there isn't enough stock for, shown until the order is paid
+    pub stock_warnings: Vec<String>,
 }
 
 /// Query parameters for order processing page
@@ -268,7 +857,6 @@ pub struct OrderOperationResponse {
 /// —————————————————————————————
 /// Quote Processing Models
 /// —————————————————————————————
-
 /// Display version of CustomBadgeQuote for templates
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QuoteDisplay {
@@ -279,7 +867,7 @@ pub struct QuoteDisplay {
     pub thickness: String,
     pub email: String,
     pub image_path: Option<String>,
-    pub estimated_price: f64,
+    pub estimated_price: Money,
     pub created_at: String,
     pub status: String,
     pub formatted_price: String,
@@ -323,6 +911,261 @@ pub struct QuoteOperationResponse {
     pub quote_id: Option<String>,
 }
 
+/// —————————————————————————————
+/// User Management Models
+/// —————————————————————————————
+/// Display version of User for templates (never includes the password hash)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDisplay {
+    pub id: String,
+    pub username: String,
+    pub is_admin: bool,
+    pub roles: String, // Comma separated, empty if none
+    pub failed_login_attempts: i32,
+    pub is_locked: bool,
+    pub formatted_locked_until: String, // Empty if not locked
+    pub two_factor_enabled: bool,
+    pub disabled: bool,
+}
+
+/// Checkbox state for one role on the user forms
+#[derive(Debug, Clone)]
+pub struct RoleOption {
+    pub value: &'static str,
+    pub label: &'static str,
+    pub checked: bool,
+}
+
+impl RoleOption {
+    /// One option per role, ticked where `roles` has it
+    pub fn all(roles: &[Role]) -> Vec<RoleOption> {
+        Role::ALL
+            .iter()
+            .map(|role| RoleOption {
+                value: role.as_str(),
+                label: role.label(),
+                checked: roles.contains(role),
+            })
+            .collect()
+    }
+}
+
+/// Create/edit user form, parsed from the raw pairs since each ticked
+/// role checkbox sends its own `roles` field
+#[derive(Debug, Clone, Default)]
+pub struct UserForm {
+    pub username: String,
+    pub password: String,
+    pub confirm_password: String,
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub roles: Vec<Role>,
+}
+
+impl UserForm {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Self {
+        let mut form = UserForm::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "username" => form.username = value.trim().to_string(),
+                "password" => form.password = value,
+                "confirm_password" => form.confirm_password = value,
+                "is_admin" => form.is_admin = true,
+                "disabled" => form.disabled = true,
+                "roles" => {
+                    if let Some(role) = Role::parse(&value)
+                        && !form.roles.contains(&role)
+                    {
+                        form.roles.push(role);
+                    }
+                }
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+/// Form for setting a new password on someone else's account
+#[derive(Deserialize, Debug, Clone)]
+pub struct ResetPasswordForm {
+    pub password: String,
+    pub confirm_password: String,
+}
+
+/// Query parameters for the user list
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserQueryParams {
+    pub success: Option<String>,
+}
+
+/// Template for creating a user
+#[derive(Template)]
+#[template(path = "create_user.html")]
+pub struct CreateUserTemplate {
+    pub user_state: UserState,
+    pub username: String,
+    pub is_admin: bool,
+    pub roles: Vec<RoleOption>,
+    pub error_message: String,
+}
+
+/// Template for editing a user's access and password
+#[derive(Template)]
+#[template(path = "edit_user.html")]
+pub struct EditUserTemplate {
+    pub user_state: UserState,
+    pub user: UserDisplay,
+    pub roles: Vec<RoleOption>,
+    pub is_self: bool,
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// Template for the user management page
+#[derive(Template)]
+#[template(path = "users.html")]
+pub struct UsersTemplate {
+    pub users: Vec<UserDisplay>,
+    pub user_state: UserState,
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// A pending enrolment: shown once, until the first code is confirmed
+#[derive(Debug, Clone)]
+pub struct TwoFactorSetup {
+    pub qr_svg: String,
+    pub otpauth_uri: String,
+    pub secret: String, // Base32, for typing in by hand
+}
+
+/// Template for the current user's two-factor settings
+#[derive(Template)]
+#[template(path = "two_factor_settings.html")]
+pub struct TwoFactorSettingsTemplate {
+    pub user_state: UserState,
+    pub enabled: bool,
+    pub available: bool, // False until TOTP_ENCRYPTION_KEY is set
+    pub recovery_codes_left: usize,
+    pub setup: Option<TwoFactorSetup>,
+    pub new_recovery_codes: Vec<String>, // Only filled straight after they're generated
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// One of the user's API tokens, for the tokens page
+#[derive(Debug, Clone)]
+pub struct ApiTokenDisplay {
+    pub id: String,
+    pub name: String,
+    pub display_prefix: String,
+    pub scopes: String,
+    pub formatted_created_at: String,
+    pub formatted_expires_at: String,   // Empty if it never expires
+    pub formatted_last_used_at: String, // Empty if never used
+    pub expired: bool,
+}
+
+/// New API token form, parsed from the raw pairs since each ticked
+/// scope checkbox sends its own `scopes` field
+#[derive(Debug, Clone, Default)]
+pub struct ApiTokenForm {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>, // None means it never expires
+}
+
+impl ApiTokenForm {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Self {
+        let mut form = ApiTokenForm::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "name" => form.name = value.trim().to_string(),
+                "scopes" => form.scopes.push(value),
+                "expires_in_days" => form.expires_in_days = value.parse().ok(),
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+/// Template for managing the logged-in user's API tokens
+#[derive(Template)]
+#[template(path = "api_tokens.html")]
+pub struct ApiTokensTemplate {
+    pub user_state: UserState,
+    pub tokens: Vec<ApiTokenDisplay>,
+    pub available_scopes: Vec<&'static str>,
+    pub expiry_days: &'static [i64],
+    pub new_token: Option<String>, // Only filled straight after creation
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// One of the user's login sessions, for the sessions page
+#[derive(Debug, Clone)]
+pub struct SessionDisplay {
+    pub id: String,
+    pub formatted_created_at: String,
+    pub formatted_last_seen_at: String,
+    pub ip: String,
+    pub user_agent: String,
+    pub is_current: bool,
+}
+
+/// Template for listing and revoking the logged-in user's sessions
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsTemplate {
+    pub user_state: UserState,
+    pub sessions: Vec<SessionDisplay>,
+    pub success_message: String,
+    pub error_message: String,
+}
+
+/// Page number for the audit log (the filters are `audit::AuditFilter`)
+#[derive(Debug, Deserialize)]
+pub struct AuditPageParams {
+    pub page: Option<u32>,
+}
+
+/// Audit entry for the page and the JSON export
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntryDisplay {
+    pub timestamp: String, // RFC 3339
+    #[serde(skip)]
+    pub formatted_timestamp: String,
+    pub actor: String,
+    pub action: String,
+    pub target_id: String,
+    pub changes: Vec<FieldChange>,
+    pub ip: String,
+    pub via_api_token: bool,
+}
+
+/// Template for the audit log page
+#[derive(Template)]
+#[template(path = "audit.html")]
+pub struct AuditTemplate {
+    pub user_state: UserState,
+    pub entries: Vec<AuditEntryDisplay>,
+    pub pagination: PaginationInfo,
+    pub filter: AuditFilter,
+    pub actions: &'static [&'static str],
+    pub query_string: String, // Current filters, for pagination and export links
+    pub error_message: String,
+}
+
+/// Response for user operations (JSON)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserOperationResponse {
+    pub success: bool,
+    pub message: String,
+    pub user_id: Option<String>,
+}
+
 /// —————————————————————————————
 /// Calculator Template
 /// —————————————————————————————
@@ -339,6 +1182,62 @@ pub struct CalculatorTemplate {
 #[template(path = "login.html")]
 pub struct LoginTemplate {
     pub next_url: String,
+    pub error: String,
+    pub notice: String,
+    pub user_state: UserState,
+}
+
+/// "Forgot password" page; `message` is shown once a request is made
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+pub struct ForgotPasswordTemplate {
+    pub message: String,
+    pub error: String,
+    pub user_state: UserState,
+}
+
+/// Choose a new password from an emailed reset link
+#[derive(Template)]
+#[template(path = "new_password.html")]
+pub struct NewPasswordTemplate {
+    pub token: String,
+    pub token_valid: bool,
+    pub error: String,
+    pub user_state: UserState,
+}
+
+/// Change password page for the logged-in user
+#[derive(Template)]
+#[template(path = "change_password.html")]
+pub struct ChangePasswordTemplate {
+    pub user_state: UserState,
+    pub success_message: String,
+    pub error_message: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ForgotPasswordForm {
+    pub username: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewPasswordForm {
+    pub token: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+/// Second login step for accounts with two-factor authentication
+#[derive(Template)]
+#[template(path = "two_factor_login.html")]
+pub struct TwoFactorLoginTemplate {
     pub error: String,
     pub user_state: UserState,
 }
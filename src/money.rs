@@ -0,0 +1,233 @@
+use mongodb::bson::{Bson, doc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MoneyError {
+    #[error("'{0}' is not a valid amount, e.g. 4.50")]
+    Invalid(String),
+    #[error("Amount is too large")]
+    Overflow,
+}
+
+/// Currencies the shop can take payment in. All have two decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Currency {
+    #[default]
+    #[serde(rename = "GBP")]
+    Gbp,
+    #[serde(rename = "EUR")]
+    Eur,
+    #[serde(rename = "USD")]
+    Usd,
+}
+
+impl Currency {
+    /// ISO 4217 code, as stored on orders
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Gbp => "GBP",
+            Currency::Eur => "EUR",
+            Currency::Usd => "USD",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Currency::Gbp => "£",
+            Currency::Eur => "€",
+            Currency::Usd => "$",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Currency> {
+        match code.trim().to_ascii_uppercase().as_str() {
+            "GBP" => Some(Currency::Gbp),
+            "EUR" => Some(Currency::Eur),
+            "USD" => Some(Currency::Usd),
+            _ => None,
+        }
+    }
+}
+
+/// An amount of money, held in minor units (pence) so sums are exact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "StoredMoney")]
+pub struct Money {
+    pub minor_units: i64,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(minor_units: i64, currency: Currency) -> Self {
+        Self { minor_units, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Parse an amount typed in major units, e.g. "4.5", "-0.50" or "£12".
+    /// More than two decimal places is an error rather than being rounded.
+    pub fn parse(input: &str, currency: Currency) -> Result<Money, MoneyError> {
+        let invalid = || MoneyError::Invalid(input.to_string());
+        let trimmed = input.trim();
+        let (negative, unsigned) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+        let unsigned = unsigned.strip_prefix(currency.symbol()).unwrap_or(unsigned);
+
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !all_digits(whole) || !all_digits(fraction) || fraction.len() > 2 {
+            return Err(invalid());
+        }
+
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| MoneyError::Overflow)?
+        };
+        let fraction: i64 = format!("{:0<2}", fraction).parse().map_err(|_| invalid())?;
+        let minor_units = whole
+            .checked_mul(100)
+            .and_then(|units| units.checked_add(fraction))
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(if negative { -minor_units } else { minor_units }, currency))
+    }
+
+    /// Convert a legacy floating point amount in major units, rounding to the
+    /// nearest minor unit
+    pub fn from_major_f64(amount: f64, currency: Currency) -> Result<Money, MoneyError> {
+        let minor_units = (amount * 100.0).round();
+        if !minor_units.is_finite() || minor_units.abs() >= i64::MAX as f64 {
+            return Err(MoneyError::Overflow);
+        }
+        Ok(Money::new(minor_units as i64, currency))
+    }
+
+    /// The same amount in another currency, for amounts stored before
+    /// currencies were recorded
+    pub fn with_currency(self, currency: Currency) -> Money {
+        Money::new(self.minor_units, currency)
+    }
+
+    /// Sum of two amounts, or None if the currencies differ or it overflows
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(self.minor_units.checked_add(other.minor_units)?, self.currency))
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.minor_units < 0
+    }
+
+    /// The amount without a currency symbol, as entered in forms, e.g. "4.50"
+    pub fn to_decimal_string(self) -> String {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let units = self.minor_units.unsigned_abs();
+        format!("{}{}.{:02}", sign, units / 100, units % 100)
+    }
+}
+
+/// With the currency symbol, e.g. "£4.50" or "-£0.50"
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let units = self.minor_units.unsigned_abs();
+        write!(f, "{}{}{}.{:02}", sign, self.currency.symbol(), units / 100, units % 100)
+    }
+}
+
+/// Same shape as the serde form, for use in `doc!` updates
+impl From<Money> for Bson {
+    fn from(money: Money) -> Bson {
+        Bson::Document(doc! { "minor_units": money.minor_units, "currency": money.currency.code() })
+    }
+}
+
+/// Amounts written before the money type (by this app or the storefront) are
+/// plain numbers or strings in pounds; `migrate-money` rewrites them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredMoney {
+    Money { minor_units: i64, currency: Currency },
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<StoredMoney> for Money {
+    type Error = MoneyError;
+
+    fn try_from(stored: StoredMoney) -> Result<Self, Self::Error> {
+        match stored {
+            StoredMoney::Money { minor_units, currency } => Ok(Money::new(minor_units, currency)),
+            StoredMoney::Number(amount) => Money::from_major_f64(amount, Currency::default()),
+            StoredMoney::Text(text) => Money::parse(&text, Currency::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson;
+
+    fn gbp(minor_units: i64) -> Money {
+        Money::new(minor_units, Currency::Gbp)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Money::parse("4.50", Currency::Gbp), Ok(gbp(450)));
+        assert_eq!(Money::parse(" 4.5 ", Currency::Gbp), Ok(gbp(450)));
+        assert_eq!(Money::parse("12", Currency::Gbp), Ok(gbp(1200)));
+        assert_eq!(Money::parse(".99", Currency::Gbp), Ok(gbp(99)));
+        assert_eq!(Money::parse("£3.05", Currency::Gbp), Ok(gbp(305)));
+        assert_eq!(Money::parse("-0.50", Currency::Gbp), Ok(gbp(-50)));
+
+        for bad in ["", ".", "abc", "4.505", "1e3", "4,50", "--1", "NaN"] {
+            assert!(Money::parse(bad, Currency::Gbp).is_err(), "{}", bad);
+        }
+        assert_eq!(Money::parse("99999999999999999999", Currency::Gbp), Err(MoneyError::Overflow));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(gbp(4498).to_string(), "£44.98");
+        assert_eq!(gbp(-50).to_string(), "-£0.50");
+        assert_eq!(Money::new(1000, Currency::Eur).to_string(), "€10.00");
+        assert_eq!(gbp(5).to_decimal_string(), "0.05");
+        assert_eq!(gbp(-1250).to_decimal_string(), "-12.50");
+    }
+
+    #[test]
+    fn test_from_major_f64_rounds_to_pence() {
+        assert_eq!(Money::from_major_f64(19.99, Currency::Gbp), Ok(gbp(1999)));
+        assert_eq!(Money::from_major_f64(0.1 + 0.2, Currency::Gbp), Ok(gbp(30)));
+        assert!(Money::from_major_f64(f64::NAN, Currency::Gbp).is_err());
+    }
+
+    #[test]
+    fn test_checked_add_needs_matching_currency() {
+        assert_eq!(gbp(450).checked_add(gbp(-50)), Some(gbp(400)));
+        assert_eq!(gbp(450).checked_add(Money::new(50, Currency::Usd)), None);
+    }
+
+    #[test]
+    fn test_bson_round_trip_and_legacy_values() {
+        let stored = bson::to_bson(&gbp(450)).unwrap();
+        assert_eq!(stored, Bson::Document(doc! { "minor_units": 450_i64, "currency": "GBP" }));
+        assert_eq!(Bson::from(gbp(450)), stored);
+        assert_eq!(bson::from_bson::<Money>(stored).unwrap(), gbp(450));
+
+        assert_eq!(bson::from_bson::<Money>(Bson::String("4.50".to_string())).unwrap(), gbp(450));
+        assert_eq!(bson::from_bson::<Money>(Bson::Double(19.99)).unwrap(), gbp(1999));
+        assert_eq!(bson::from_bson::<Money>(Bson::Int32(5)).unwrap(), gbp(500));
+        assert!(bson::from_bson::<Money>(Bson::String("n/a".to_string())).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use crate::{auth::generate_token, login_throttle::LoginThrottle};
+
+/// Name of the Mongo collection holding outstanding reset tokens
+pub const PASSWORD_RESETS_COLLECTION: &str = "password_resets";
+
+/// How long an emailed reset link stays valid
+pub const RESET_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest gap between reset emails to one account
+pub const RESET_REQUEST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A reset token as stored in the "password_resets" collection. Only the
+/// token's hash is kept, so a database dump can't be used to reset passwords.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetDocument {
+    #[serde(rename = "_id")]
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub created_at: bson::DateTime,
+    /// TTL-indexed: MongoDB removes the document once this passes
+    pub expires_at: bson::DateTime,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Per-IP backoff on reset requests. Separate from the login throttle, so
+/// asking for resets never counts against anyone's logins.
+#[derive(Debug, Clone, Default)]
+pub struct ResetThrottle(pub LoginThrottle);
+
+/// Single-use, expiring password reset tokens
+#[derive(Debug, Clone)]
+pub struct PasswordResets {
+    collection: Collection<PasswordResetDocument>,
+}
+
+impl PasswordResets {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(PASSWORD_RESETS_COLLECTION),
+        }
+    }
+
+    /// Create the TTL index that clears out expired tokens
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("password_resets_ttl".to_string())
+                    .expire_after(Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        self.collection.create_index(ttl_index).await?;
+        Ok(())
+    }
+
+    /// Issue a new token for the user, replacing any earlier one
+    pub async fn create(&self, user_id: ObjectId) -> mongodb::error::Result<String> {
+        self.collection
+            .delete_many(doc! { "user_id": user_id })
+            .await?;
+
+        let token = generate_token();
+        let now = bson::DateTime::now();
+        let document = PasswordResetDocument {
+            token_hash: hash_token(&token),
+            user_id,
+            created_at: now,
+            expires_at: bson::DateTime::from_millis(
+                now.timestamp_millis() + RESET_TOKEN_LIFETIME.as_millis() as i64,
+            ),
+        };
+        self.collection.insert_one(&document).await?;
+        Ok(token)
+    }
+
+    /// Whether the user was sent a reset link within `RESET_REQUEST_INTERVAL`
+    pub async fn recently_requested(&self, user_id: ObjectId) -> mongodb::error::Result<bool> {
+        let since = bson::DateTime::from_millis(
+            bson::DateTime::now().timestamp_millis() - RESET_REQUEST_INTERVAL.as_millis() as i64,
+        );
+        let recent = self
+            .collection
+            .find_one(doc! { "user_id": user_id, "created_at": { "$gt": since } })
+            .await?;
+        Ok(recent.is_some())
+    }
+
+    /// The user a still-valid token belongs to, without using it up
+    pub async fn check(&self, token: &str) -> mongodb::error::Result<Option<ObjectId>> {
+        let document = self
+            .collection
+            .find_one(doc! {
+                "_id": hash_token(token),
+                "expires_at": { "$gt": bson::DateTime::now() },
+            })
+            .await?;
+        Ok(document.map(|d| d.user_id))
+    }
+
+    /// Use up a token, returning its user if it was valid
+    pub async fn redeem(&self, token: &str) -> mongodb::error::Result<Option<ObjectId>> {
+        let document = self
+            .collection
+            .find_one_and_delete(doc! {
+                "_id": hash_token(token),
+                "expires_at": { "$gt": bson::DateTime::now() },
+            })
+            .await?;
+        Ok(document.map(|d| d.user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_token_is_stable_and_hides_token() {
+        let token = generate_token();
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+        assert_eq!(hash_token(&token).len(), 64);
+        assert_ne!(hash_token(&token), hash_token(&generate_token()));
+    }
+}
@@ -0,0 +1,388 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use axum_login::tower_sessions::{
+    Session, SessionStore,
+    cookie::time::OffsetDateTime,
+    session::{Id, Record},
+    session_store,
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration};
+use tracing::error;
+
+use crate::{api_tokens::ApiPrincipal, handlers::auth::AppAuthSession};
+
+/// Name of the Mongo collection holding admin sessions
+pub const SESSIONS_COLLECTION: &str = "sessions";
+
+/// Session key holding where and when the session was used (kept up to date by `track_session`)
+pub const SESSION_INFO_KEY: &str = "session_info";
+
+/// Session key axum-login keeps the logged-in user's id under
+const AUTH_DATA_KEY: &str = "axum-login.data";
+
+/// Only rewrite the session for a new last-seen time this often
+const LAST_SEEN_RESOLUTION_MS: i64 = 60 * 1000;
+
+/// MongoDB duplicate key error code, hit when a generated session id collides
+pub const DUPLICATE_KEY: i32 = 11000;
+
+/// A session as stored in the "sessions" collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDocument {
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Session data as JSON, kept opaque so keys like "axum-login.data" survive untouched
+    pub data: String,
+    /// TTL-indexed: MongoDB removes the document once this passes
+    pub expires_at: bson::DateTime,
+    /// Copied out of `data` so a user's sessions can be listed and revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<SessionInfo>,
+}
+
+/// Where and when a session has been used, shown on the sessions page
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Unix milliseconds
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub ip: String,
+    pub user_agent: String,
+}
+
+/// Session store backed by the "sessions" collection, so logins survive
+/// restarts and are shared between replicas
+#[derive(Debug, Clone)]
+pub struct MongoSessionStore {
+    collection: Collection<SessionDocument>,
+}
+
+impl MongoSessionStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(SESSIONS_COLLECTION),
+        }
+    }
+
+    /// Create the TTL index that expires sessions server-side
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("sessions_ttl".to_string())
+                    .expire_after(Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        let user_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(IndexOptions::builder().name("sessions_user".to_string()).build())
+            .build();
+        self.collection.create_indexes([ttl_index, user_index]).await?;
+        Ok(())
+    }
+
+    /// The user's live sessions, most recently used first
+    pub async fn list_for_user(&self, user_id: ObjectId) -> mongodb::error::Result<Vec<SessionDocument>> {
+        self.collection
+            .find(doc! {
+                "user_id": user_id,
+                "expires_at": { "$gt": bson::DateTime::now() },
+            })
+            .sort(doc! { "info.last_seen_at": -1 })
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// End one of the user's sessions; false if it wasn't theirs or had already gone
+    pub async fn revoke(&self, user_id: ObjectId, session_id: &str) -> mongodb::error::Result<bool> {
+        let result = self
+            .collection
+            .delete_one(doc! { "_id": session_id, "user_id": user_id })
+            .await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// End all of the user's sessions, apart from `except` if given
+    pub async fn revoke_all_for_user(&self, user_id: ObjectId, except: Option<&str>) -> mongodb::error::Result<u64> {
+        let mut filter = doc! { "user_id": user_id };
+        if let Some(keep) = except {
+            filter.insert("_id", doc! { "$ne": keep });
+        }
+        Ok(self.collection.delete_many(filter).await?.deleted_count)
+    }
+}
+
+fn to_bson_datetime(time: OffsetDateTime) -> bson::DateTime {
+    bson::DateTime::from_millis((time.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+fn from_bson_datetime(time: bson::DateTime) -> session_store::Result<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp_nanos(time.timestamp_millis() as i128 * 1_000_000)
+        .map_err(|e| session_store::Error::Decode(e.to_string()))
+}
+
+/// The logged-in user's id from axum-login's session data (an ObjectId serializes as {"$oid": ...})
+fn session_user_id(record: &Record) -> Option<ObjectId> {
+    let oid = record.data.get(AUTH_DATA_KEY)?.get("user_id")?.get("$oid")?.as_str()?;
+    ObjectId::parse_str(oid).ok()
+}
+
+/// Convert a tower-sessions record into its stored form
+pub fn to_document(record: &Record) -> session_store::Result<SessionDocument> {
+    Ok(SessionDocument {
+        id: record.id.to_string(),
+        data: serde_json::to_string(&record.data)
+            .map_err(|e| session_store::Error::Encode(e.to_string()))?,
+        expires_at: to_bson_datetime(record.expiry_date),
+        user_id: session_user_id(record),
+        info: record
+            .data
+            .get(SESSION_INFO_KEY)
+            .and_then(|info| serde_json::from_value(info.clone()).ok()),
+    })
+}
+
+/// Convert a stored session back into a tower-sessions record
+pub fn from_document(document: SessionDocument) -> session_store::Result<Record> {
+    Ok(Record {
+        id: document
+            .id
+            .parse::<Id>()
+            .map_err(|e| session_store::Error::Decode(e.to_string()))?,
+        data: serde_json::from_str(&document.data)
+            .map_err(|e| session_store::Error::Decode(e.to_string()))?,
+        expiry_date: from_bson_datetime(document.expires_at)?,
+    })
+}
+
+fn backend_error(e: mongodb::error::Error) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+/// Did a write fail on a unique index? Inserts report it as a write error,
+/// find-and-modify as a command error.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    match *e.kind {
+        ErrorKind::Write(WriteFailure::WriteError(ref we)) => we.code == DUPLICATE_KEY,
+        ErrorKind::Command(ref ce) => ce.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+/// The session's info after a request from `ip` with `user_agent` at `now`,
+/// or None if what's stored is still current enough
+fn updated_info(existing: Option<SessionInfo>, ip: String, user_agent: String, now: i64) -> Option<SessionInfo> {
+    match existing {
+        Some(info)
+            if info.ip == ip
+                && info.user_agent == user_agent
+                && now - info.last_seen_at < LAST_SEEN_RESOLUTION_MS =>
+        {
+            None
+        }
+        Some(info) => Some(SessionInfo {
+            last_seen_at: now,
+            ip,
+            user_agent,
+            ..info
+        }),
+        None => Some(SessionInfo {
+            created_at: now,
+            last_seen_at: now,
+            ip,
+            user_agent,
+        }),
+    }
+}
+
+/// Admin-route layer recording the IP, user agent and last-seen time of
+/// logged-in browser sessions
+pub async fn track_session(session: Session, auth: AppAuthSession, request: Request, next: Next) -> Response {
+    if auth.user.is_some() && request.extensions().get::<ApiPrincipal>().is_none() {
+        let ip = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_default();
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let existing = session.get::<SessionInfo>(SESSION_INFO_KEY).await.ok().flatten();
+        let now = bson::DateTime::now().timestamp_millis();
+
+        if let Some(info) = updated_info(existing, ip, user_agent, now)
+            && let Err(e) = session.insert(SESSION_INFO_KEY, &info).await
+        {
+            error!("Failed to record session info: {}", e);
+        }
+    }
+    next.run(request).await
+}
+
+#[async_trait]
+impl SessionStore for MongoSessionStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        loop {
+            match self.collection.insert_one(to_document(record)?).await {
+                Ok(_) => return Ok(()),
+                // Id collision: pick a new one and try again
+                Err(e) if is_duplicate_key(&e) => record.id = Id::default(),
+                Err(e) => return Err(backend_error(e)),
+            }
+        }
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let document = to_document(record)?;
+        self.collection
+            .replace_one(doc! { "_id": &document.id }, &document)
+            .upsert(true)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        // The TTL monitor only runs once a minute, so filter out expired sessions too
+        let document = self
+            .collection
+            .find_one(doc! {
+                "_id": session_id.to_string(),
+                "expires_at": { "$gt": bson::DateTime::now() },
+            })
+            .await
+            .map_err(backend_error)?;
+
+        document.map(from_document).transpose()
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        self.collection
+            .delete_one(doc! { "_id": session_id.to_string() })
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_login::tower_sessions::cookie::time::Duration as TimeDuration;
+    use std::collections::HashMap;
+
+    fn sample_record() -> Record {
+        let mut data = HashMap::new();
+        data.insert(
+            "axum-login.data".to_string(),
+            serde_json::json!({ "user_id": { "$oid": "65a1b2c3d4e5f60718293a4b" }, "auth_hash": [1, 2, 3] }),
+        );
+        Record {
+            id: Id::default(),
+            data,
+            expiry_date: OffsetDateTime::now_utc() + TimeDuration::hours(1),
+        }
+    }
+
+    #[test]
+    fn test_record_round_trips_through_document() {
+        let record = sample_record();
+        let document = to_document(&record).unwrap();
+        let restored = from_document(document).unwrap();
+
+        assert_eq!(restored.id, record.id);
+        assert_eq!(restored.data, record.data);
+        // Stored with millisecond precision
+        assert_eq!(
+            restored.expiry_date.unix_timestamp_nanos() / 1_000_000,
+            record.expiry_date.unix_timestamp_nanos() / 1_000_000
+        );
+    }
+
+    #[test]
+    fn test_document_uses_session_id_as_key() {
+        let record = sample_record();
+        let document = to_document(&record).unwrap();
+
+        assert_eq!(document.id, record.id.to_string());
+        assert_eq!(
+            document.expires_at.timestamp_millis(),
+            (record.expiry_date.unix_timestamp_nanos() / 1_000_000) as i64
+        );
+    }
+
+    #[test]
+    fn test_document_copies_out_user_and_info() {
+        let mut record = sample_record();
+        let info = SessionInfo {
+            created_at: 1,
+            last_seen_at: 2,
+            ip: "10.0.0.5".to_string(),
+            user_agent: "Firefox".to_string(),
+        };
+        record
+            .data
+            .insert(SESSION_INFO_KEY.to_string(), serde_json::to_value(&info).unwrap());
+
+        let document = to_document(&record).unwrap();
+        assert_eq!(document.user_id.unwrap().to_hex(), "65a1b2c3d4e5f60718293a4b");
+        assert_eq!(document.info, Some(info));
+
+        // Anonymous sessions (e.g. the login page's CSRF token) have neither
+        let anonymous = Record {
+            data: HashMap::new(),
+            ..sample_record()
+        };
+        let document = to_document(&anonymous).unwrap();
+        assert!(document.user_id.is_none());
+        assert!(document.info.is_none());
+    }
+
+    #[test]
+    fn test_updated_info_only_when_stale_or_changed() {
+        let first = updated_info(None, "10.0.0.5".into(), "Firefox".into(), 1_000).unwrap();
+        assert_eq!(first.created_at, 1_000);
+        assert_eq!(first.last_seen_at, 1_000);
+
+        // Same place, shortly after: nothing to write
+        assert!(updated_info(Some(first.clone()), "10.0.0.5".into(), "Firefox".into(), 30_000).is_none());
+
+        // A minute later, or from somewhere new, the last-seen details move on
+        let later = updated_info(Some(first.clone()), "10.0.0.5".into(), "Firefox".into(), 61_000).unwrap();
+        assert_eq!(later.created_at, 1_000);
+        assert_eq!(later.last_seen_at, 61_000);
+
+        let moved = updated_info(Some(first), "192.168.1.9".into(), "Firefox".into(), 2_000).unwrap();
+        assert_eq!(moved.ip, "192.168.1.9");
+        assert_eq!(moved.created_at, 1_000);
+    }
+
+    #[test]
+    fn test_from_document_rejects_bad_id() {
+        let mut document = to_document(&sample_record()).unwrap();
+        document.id = "not-a-session-id!".to_string();
+
+        assert!(from_document(document).is_err());
+    }
+}
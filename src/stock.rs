@@ -0,0 +1,394 @@
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::{audit::Actor, models::Product};
+
+/// Name of the Mongo collection holding the stock ledger
+pub const STOCK_MOVEMENTS_COLLECTION: &str = "stock_movements";
+
+/// Highest stock level a product or variant can have
+pub const MAX_STOCK: i32 = 999_999;
+
+/// Attempts at a stock change before giving up because others keep changing it
+const MAX_ATTEMPTS: usize = 3;
+
+/// Why stock changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementKind {
+    /// Stock a product or variant started with
+    Initial,
+    /// A manual correction, always with a reason
+    Adjustment,
+    /// Sold through an order
+    Sale,
+    /// Came back from an order
+    Return,
+    /// Counted and set to what's on the shelf
+    Stocktake,
+}
+
+impl MovementKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MovementKind::Initial => "Opening stock",
+            MovementKind::Adjustment => "Adjustment",
+            MovementKind::Sale => "Sale",
+            MovementKind::Return => "Return",
+            MovementKind::Stocktake => "Stocktake",
+        }
+    }
+}
+
+/// A record in the "stock_movements" collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StockMovement {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub product_id: ObjectId,
+    /// The variant whose stock changed; None for the product's own stock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
+    pub kind: MovementKind,
+    /// How much stock changed by; negative when it went down
+    pub change: i32,
+    /// Stock of the product or variant afterwards
+    pub quantity_after: i32,
+    #[serde(default)]
+    pub reason: String,
+    /// The order behind a sale or return
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_reference: Option<String>,
+    pub actor: String,
+    pub timestamp: bson::DateTime,
+}
+
+impl StockMovement {
+    /// A movement of `product_id`'s stock by `actor`. The SKU, change and
+    /// resulting level are filled in once it's applied.
+    pub fn new(actor: &Actor, product_id: ObjectId, kind: MovementKind) -> Self {
+        Self {
+            id: ObjectId::new(),
+            product_id,
+            sku: None,
+            kind,
+            change: 0,
+            quantity_after: 0,
+            reason: String::new(),
+            order_reference: None,
+            actor: actor.username.clone(),
+            timestamp: bson::DateTime::now(),
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = reason.trim().to_string();
+        self
+    }
+
+    pub fn with_order_reference(mut self, order_reference: &str) -> Self {
+        self.order_reference = Some(order_reference.to_string());
+        self
+    }
+}
+
+/// A requested change to one stock level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StockChange {
+    /// Up or down by this much
+    By(i32),
+    /// Set to this, e.g. after a stocktake
+    To(i32),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StockError {
+    #[error("Product not found")]
+    ProductNotFound,
+    #[error("Stock for this product is kept per variant; choose one")]
+    VariantRequired,
+    #[error("There's no variant with SKU {0}")]
+    UnknownVariant(String),
+    #[error("Only {available} in stock")]
+    Insufficient { available: i32 },
+    #[error("Stock can't go above 999,999")]
+    TooMuch,
+    #[error("Stock kept changing while saving; try again")]
+    Conflict,
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+impl From<mongodb::error::Error> for StockError {
+    fn from(e: mongodb::error::Error) -> Self {
+        StockError::Database(e.to_string())
+    }
+}
+
+/// The stock levels a product keeps: one per variant, or its own when it
+/// has none
+pub fn stock_lines(product: &Product) -> Vec<(Option<String>, i32)> {
+    if product.variants.is_empty() {
+        vec![(None, product.quantity)]
+    } else {
+        product
+            .variants
+            .iter()
+            .map(|variant| (Some(variant.sku.clone()), variant.quantity))
+            .collect()
+    }
+}
+
+/// How each stock level differs between two versions of a product, as
+/// (SKU, change, new level). Lines that only exist on one side count as
+/// going from or to zero.
+pub fn line_changes(before: &[(Option<String>, i32)], after: &[(Option<String>, i32)]) -> Vec<(Option<String>, i32, i32)> {
+    let level = |lines: &[(Option<String>, i32)], sku: &Option<String>| {
+        lines
+            .iter()
+            .find(|(line, _)| line == sku)
+            .map(|(_, quantity)| *quantity)
+            .unwrap_or(0)
+    };
+    let mut changes = Vec::new();
+    for (sku, quantity) in after {
+        let change = quantity - level(before, sku);
+        if change != 0 {
+            changes.push((sku.clone(), change, *quantity));
+        }
+    }
+    for (sku, quantity) in before {
+        if *quantity != 0 && !after.iter().any(|(line, _)| line == sku) {
+            changes.push((sku.clone(), -quantity, 0));
+        }
+    }
+    changes
+}
+
+/// The stock level a change applies to, with its current and new values.
+/// `sku` is matched ignoring case and returned as stored.
+pub fn plan_change(product: &Product, sku: Option<&str>, change: StockChange) -> Result<(Option<String>, i32, i32), StockError> {
+    let (line, current) = match sku {
+        None if product.variants.is_empty() => (None, product.quantity),
+        None => return Err(StockError::VariantRequired),
+        Some(sku) => {
+            let variant = product
+                .variants
+                .iter()
+                .find(|variant| variant.sku.eq_ignore_ascii_case(sku))
+                .ok_or_else(|| StockError::UnknownVariant(sku.to_string()))?;
+            (Some(variant.sku.clone()), variant.quantity)
+        }
+    };
+    let new = match change {
+        StockChange::By(amount) => current.checked_add(amount).ok_or(StockError::TooMuch)?,
+        StockChange::To(quantity) => quantity,
+    };
+    if new < 0 {
+        return Err(StockError::Insufficient { available: current.max(0) });
+    }
+    if new > MAX_STOCK {
+        return Err(StockError::TooMuch);
+    }
+    Ok((line, current, new))
+}
+
+/// Every change to product stock, with why it happened. Stock levels are
+/// changed through here so the two can't drift apart.
+#[derive(Debug, Clone)]
+pub struct StockLedger {
+    movements: Collection<StockMovement>,
+    products: Collection<Product>,
+}
+
+impl StockLedger {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            movements: db.collection(STOCK_MOVEMENTS_COLLECTION),
+            products: db.collection("products"),
+        }
+    }
+
+    /// Index for a product's history, newest first
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "product_id": 1, "timestamp": -1 })
+            .options(IndexOptions::builder().name("stock_movements_product".to_string()).build())
+            .build();
+        self.movements.create_index(index).await?;
+        Ok(())
+    }
+
+    /// Change one stock level and record the movement. `movement` says why;
+    /// its SKU, change and resulting level are filled in here. The update
+    /// only applies if the level hasn't moved since it was read, so
+    /// concurrent sales and adjustments can't overwrite each other.
+    pub async fn apply(
+        &self,
+        sku: Option<&str>,
+        change: StockChange,
+        movement: StockMovement,
+    ) -> Result<StockMovement, StockError> {
+        let product_id = movement.product_id;
+        for _ in 0..MAX_ATTEMPTS {
+            let product = self
+                .products
+                .find_one(doc! { "_id": product_id })
+                .await?
+                .ok_or(StockError::ProductNotFound)?;
+            let (line, current, new) = plan_change(&product, sku, change)?;
+            if new == current {
+                return Ok(StockMovement {
+                    sku: line,
+                    change: 0,
+                    quantity_after: current,
+                    ..movement
+                });
+            }
+
+            let (filter, update) = match &line {
+                None => (
+                    doc! { "_id": product_id, "quantity": current },
+                    doc! { "$set": { "quantity": new } },
+                ),
+                Some(line) => (
+                    doc! { "_id": product_id, "variants": { "$elemMatch": { "sku": line, "quantity": current } } },
+                    doc! { "$set": { "variants.$.quantity": new }, "$inc": { "quantity": new - current } },
+                ),
+            };
+            if self.products.update_one(filter, update).await?.matched_count == 0 {
+                continue; // Changed since it was read
+            }
+
+            let movement = StockMovement {
+                sku: line,
+                change: new - current,
+                quantity_after: new,
+                ..movement
+            };
+            self.record(&movement).await;
+            return Ok(movement);
+        }
+        Err(StockError::Conflict)
+    }
+
+    /// Record a change that's already been saved, e.g. a new product's
+    /// opening stock. Failures are logged rather than undoing the change.
+    pub async fn record(&self, movement: &StockMovement) {
+        if let Err(e) = self.movements.insert_one(movement).await {
+            error!(
+                "Failed to record stock movement for product {}: {}",
+                movement.product_id, e
+            );
+        }
+    }
+
+    /// Record how a product's stock differs between two saved versions of
+    /// it; `before` is None for a new product
+    pub async fn record_changes(
+        &self,
+        actor: &Actor,
+        before: Option<&Product>,
+        after: &Product,
+        kind: MovementKind,
+        reason: &str,
+    ) {
+        let before_lines = before.map(stock_lines).unwrap_or_default();
+        for (sku, change, quantity_after) in line_changes(&before_lines, &stock_lines(after)) {
+            let movement = StockMovement {
+                sku,
+                change,
+                quantity_after,
+                ..StockMovement::new(actor, after.id, kind).with_reason(reason)
+            };
+            self.record(&movement).await;
+        }
+    }
+
+    /// A product's movements, newest first, along with the total count
+    pub async fn history(&self, product_id: ObjectId, skip: u64, limit: i64) -> mongodb::error::Result<(Vec<StockMovement>, u64)> {
+        let query = doc! { "product_id": product_id };
+        let total = self.movements.count_documents(query.clone()).await?;
+        let movements = self
+            .movements
+            .find(query)
+            .sort(doc! { "timestamp": -1, "_id": -1 })
+            .skip(skip)
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?;
+        Ok((movements, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::ProductVariant,
+        money::{Currency, Money},
+    };
+
+    fn product(quantity: i32, variants: &[(&str, i32)]) -> Product {
+        Product {
+            name: "Fox Tee".to_string(),
+            price: Money::new(1500, Currency::Gbp),
+            quantity,
+            variants: variants
+                .iter()
+                .map(|(sku, quantity)| ProductVariant {
+                    sku: sku.to_string(),
+                    option_values: vec![sku.to_string()],
+                    price_delta: Money::zero(Currency::Gbp),
+                    quantity: *quantity,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_change() {
+        let pin = product(5, &[]);
+        assert_eq!(plan_change(&pin, None, StockChange::By(-2)), Ok((None, 5, 3)));
+        assert_eq!(plan_change(&pin, None, StockChange::To(12)), Ok((None, 5, 12)));
+        assert_eq!(
+            plan_change(&pin, None, StockChange::By(-6)),
+            Err(StockError::Insufficient { available: 5 })
+        );
+        assert_eq!(plan_change(&pin, None, StockChange::To(1_000_000)), Err(StockError::TooMuch));
+        assert!(matches!(plan_change(&pin, Some("TEE-S"), StockChange::By(1)), Err(StockError::UnknownVariant(_))));
+
+        let tee = product(3, &[("TEE-S", 1), ("TEE-M", 2)]);
+        assert_eq!(plan_change(&tee, None, StockChange::By(1)), Err(StockError::VariantRequired));
+        assert_eq!(
+            plan_change(&tee, Some("tee-m"), StockChange::By(4)),
+            Ok((Some("TEE-M".to_string()), 2, 6))
+        );
+    }
+
+    #[test]
+    fn test_line_changes() {
+        let before = stock_lines(&product(3, &[("TEE-S", 1), ("TEE-M", 2)]));
+        let after = stock_lines(&product(6, &[("TEE-M", 2), ("TEE-L", 4)]));
+        assert_eq!(
+            line_changes(&before, &after),
+            vec![(Some("TEE-L".to_string()), 4, 4), (Some("TEE-S".to_string()), -1, 0)]
+        );
+
+        // Switching from the product's own stock to variants
+        let before = stock_lines(&product(5, &[]));
+        let after = stock_lines(&product(2, &[("TEE-S", 2)]));
+        assert_eq!(line_changes(&before, &after), vec![(Some("TEE-S".to_string()), 2, 2), (None, -5, 0)]);
+
+        assert!(line_changes(&before, &before).is_empty());
+    }
+}
@@ -0,0 +1,361 @@
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    Client,
+    config::{BehaviorVersion, Region},
+    primitives::ByteStream,
+};
+use std::{env, path::PathBuf, sync::Arc, time::SystemTime};
+use thiserror::Error;
+use tracing::info;
+
+/// Bucket prefixes used by the S3 backend, mirroring the local directory names
+const PRODUCT_IMAGES_PREFIX: &str = "product-images/";
+const BADGE_UPLOADS_PREFIX: &str = "private_uploads/";
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("invalid file name: {0}")]
+    InvalidKey(String),
+    #[error("file system error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("S3 error: {0}")]
+    S3(String),
+}
+
+/// A file as listed by a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// A flat store of files addressed by name. Names are single path segments
+/// (no slashes), so a request can never reach outside its store.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError>;
+    /// The file's contents, or None if there's no such file
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Deleting a file that isn't there is not an error
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    async fn list(&self) -> Result<Vec<StoredFile>, StorageError>;
+    /// Where files go, for the startup log
+    fn describe(&self) -> String;
+}
+
+fn check_key(key: &str) -> Result<(), StorageError> {
+    if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+        return Err(StorageError::InvalidKey(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Content type to serve a stored image with, from its extension
+pub fn content_type_for(key: &str) -> &'static str {
+    match key.rsplit('.').next().unwrap_or("").to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Files in a local directory (which may be a network mount)
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// Use `root`, creating it if needed
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        check_key(key)?;
+        tokio::fs::write(self.root.join(key), bytes).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        check_key(key)?;
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<StoredFile>, StorageError> {
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            files.push(StoredFile {
+                key: entry.file_name().to_string_lossy().to_string(),
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+        Ok(files)
+    }
+
+    fn describe(&self) -> String {
+        format!("directory {}", self.root.display())
+    }
+}
+
+/// Files under a prefix in an S3-compatible bucket (AWS, MinIO, ...)
+#[derive(Debug, Clone)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(client: Client, bucket: &str, prefix: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn object_key(&self, key: &str) -> Result<String, StorageError> {
+        check_key(key)?;
+        Ok(format!("{}{}", self.prefix, key))
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key)?)
+            .content_type(content_type_for(key))
+            .body(ByteStream::from(bytes))
+            .send()
+            .await
+            .map_err(|e| StorageError::S3(e.into_service_error().to_string()))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key)?)
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_no_such_key() {
+                    return Ok(None);
+                }
+                return Err(StorageError::S3(e.to_string()));
+            }
+        };
+        let bytes = object
+            .body
+            .collect()
+            .await
+            .map_err(|e| StorageError::S3(e.to_string()))?;
+        Ok(Some(bytes.to_vec()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key)?)
+            .send()
+            .await
+            .map_err(|e| StorageError::S3(e.into_service_error().to_string()))?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StoredFile>, StorageError> {
+        let mut files = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| StorageError::S3(e.into_service_error().to_string()))?;
+            for object in page.contents() {
+                // Anything in a "subdirectory" isn't one of ours
+                let Some(key) = object.key().and_then(|key| key.strip_prefix(&self.prefix)) else {
+                    continue;
+                };
+                if check_key(key).is_err() {
+                    continue;
+                }
+                files.push(StoredFile {
+                    key: key.to_string(),
+                    size: object.size().unwrap_or(0).max(0) as u64,
+                    modified: object.last_modified().and_then(|t| SystemTime::try_from(*t).ok()),
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+/// Where product images and customers' badge uploads are kept
+#[derive(Clone)]
+pub struct ImageStores {
+    pub product_images: Arc<dyn Storage>,
+    pub badge_uploads: Arc<dyn Storage>,
+}
+
+impl ImageStores {
+    /// Pick the backend from STORAGE_BACKEND ("local", the default, or "s3").
+    ///
+    /// Local uses PRODUCT_IMAGES_DIR and BADGE_UPLOADS_DIR. S3 needs S3_BUCKET,
+    /// takes S3_ENDPOINT and S3_REGION for MinIO and the like, and reads
+    /// credentials the usual AWS way (e.g. AWS_ACCESS_KEY_ID).
+    pub async fn from_env() -> Result<Self, String> {
+        let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+        let stores = match backend.to_ascii_lowercase().as_str() {
+            "local" => {
+                let products = env::var("PRODUCT_IMAGES_DIR").unwrap_or_else(|_| "product-images".to_string());
+                let badges = env::var("BADGE_UPLOADS_DIR").unwrap_or_else(|_| "private_uploads".to_string());
+                Self {
+                    product_images: Arc::new(LocalStorage::open(&products).await.map_err(|e| {
+                        format!("could not use {} for product images: {}", products, e)
+                    })?),
+                    badge_uploads: Arc::new(LocalStorage::open(&badges).await.map_err(|e| {
+                        format!("could not use {} for badge uploads: {}", badges, e)
+                    })?),
+                }
+            }
+            "s3" => {
+                let bucket = env::var("S3_BUCKET").map_err(|_| "S3_BUCKET must be set when STORAGE_BACKEND=s3")?;
+                let client = s3_client_from_env().await;
+                Self {
+                    product_images: Arc::new(S3Storage::new(client.clone(), &bucket, PRODUCT_IMAGES_PREFIX)),
+                    badge_uploads: Arc::new(S3Storage::new(client, &bucket, BADGE_UPLOADS_PREFIX)),
+                }
+            }
+            other => return Err(format!("unknown STORAGE_BACKEND '{}' (expected local or s3)", other)),
+        };
+
+        info!(
+            "🗄️ Product images in {}, badge uploads in {}",
+            stores.product_images.describe(),
+            stores.badge_uploads.describe()
+        );
+        Ok(stores)
+    }
+}
+
+async fn s3_client_from_env() -> Client {
+    let endpoint = env::var("S3_ENDPOINT").ok().filter(|v| !v.is_empty());
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Ok(region) = env::var("S3_REGION") {
+        loader = loader.region(Region::new(region));
+    }
+    if let Some(endpoint) = &endpoint {
+        loader = loader.endpoint_url(endpoint);
+    }
+    let shared = loader.load().await;
+
+    // Self-hosted servers such as MinIO usually want path-style URLs
+    let config = aws_sdk_s3::config::Builder::from(&shared)
+        .force_path_style(endpoint.is_some())
+        .build();
+    Client::from_conf(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_must_be_plain_file_names() {
+        assert!(check_key("4f1c.jpg").is_ok());
+        assert!(check_key("badge_123.png").is_ok());
+        for key in ["", "../secrets", "a/b.jpg", "a\\b.jpg", ".env"] {
+            assert!(check_key(key).is_err(), "{:?} should be rejected", key);
+        }
+    }
+
+    #[test]
+    fn test_content_type_for() {
+        assert_eq!(content_type_for("a.JPG"), "image/jpeg");
+        assert_eq!(content_type_for("a-thumb.webp"), "image/webp");
+        assert_eq!(content_type_for("noextension"), "application/octet-stream");
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_round_trip() {
+        let root = env::temp_dir().join(format!("ffa-storage-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::open(&root).await.unwrap();
+
+        storage.put("a.jpg", b"jpeg bytes".to_vec()).await.unwrap();
+        assert_eq!(storage.get("a.jpg").await.unwrap().as_deref(), Some(&b"jpeg bytes"[..]));
+        assert_eq!(storage.get("missing.jpg").await.unwrap(), None);
+        assert!(storage.get("../a.jpg").await.is_err());
+
+        let listed = storage.list().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].key.as_str(), listed[0].size), ("a.jpg", 10));
+
+        storage.delete("a.jpg").await.unwrap();
+        storage.delete("a.jpg").await.unwrap();
+        assert!(storage.list().await.unwrap().is_empty());
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    /// Run with a local MinIO, e.g.
+    /// `S3_ENDPOINT=http://localhost:9000 S3_BUCKET=test AWS_ACCESS_KEY_ID=minioadmin
+    /// AWS_SECRET_ACCESS_KEY=minioadmin cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible server"]
+    async fn test_s3_storage_round_trip() {
+        let bucket = env::var("S3_BUCKET").expect("S3_BUCKET");
+        let storage = S3Storage::new(s3_client_from_env().await, &bucket, "storage-test/");
+        let key = format!("{}.png", uuid::Uuid::new_v4());
+
+        storage.put(&key, b"png bytes".to_vec()).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().as_deref(), Some(&b"png bytes"[..]));
+        assert_eq!(storage.get("missing.png").await.unwrap(), None);
+        assert!(storage.list().await.unwrap().iter().any(|file| file.key == key));
+
+        storage.delete(&key).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+    }
+}
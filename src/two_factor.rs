@@ -0,0 +1,394 @@
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use argon2::password_hash::rand_core::RngCore;
+use mongodb::{
+    Collection,
+    bson::{doc, oid::ObjectId},
+};
+use qrcode::{QrCode, render::svg};
+use sha2::{Digest, Sha256};
+use std::env;
+use thiserror::Error;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::info;
+
+use crate::models::User;
+
+/// Issuer shown in authenticator apps (must not contain ':')
+const ISSUER: &str = "Foxy Fabrications Admin";
+
+/// RFC 6238 defaults, which every authenticator app understands
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+/// Accept codes one step either side of now to allow for clock drift
+const SKEW_STEPS: i64 = 1;
+
+/// Recovery codes issued at enrolment (each works once)
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Env var holding the 32-byte AES key (hex) used to encrypt TOTP secrets
+const KEY_ENV: &str = "TOTP_ENCRYPTION_KEY";
+
+#[derive(Debug, Error)]
+pub enum TwoFactorError {
+    #[error("two-factor authentication is not configured (set {KEY_ENV})")]
+    NotConfigured,
+    #[error("stored TOTP secret could not be decrypted")]
+    Decrypt,
+    #[error("invalid TOTP parameters: {0}")]
+    Totp(String),
+    #[error("QR code generation failed: {0}")]
+    Qr(String),
+    #[error("database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+}
+
+/// Encrypts TOTP secrets at rest; a database dump alone can't mint codes
+#[derive(Clone)]
+pub struct TwoFactorCipher {
+    key: Option<Key<Aes256Gcm>>,
+}
+
+impl TwoFactorCipher {
+    /// Load the key from TOTP_ENCRYPTION_KEY (64 hex characters)
+    pub fn from_env() -> Result<Self, String> {
+        match env::var(KEY_ENV) {
+            Ok(value) => Self::from_hex(&value),
+            Err(_) => Ok(Self { key: None }),
+        }
+    }
+
+    pub fn from_hex(value: &str) -> Result<Self, String> {
+        let bytes = hex::decode(value.trim()).map_err(|e| format!("{} is not valid hex: {}", KEY_ENV, e))?;
+        if bytes.len() != 32 {
+            return Err(format!("{} must be 32 bytes (64 hex characters)", KEY_ENV));
+        }
+        Ok(Self {
+            key: Some(*Key::<Aes256Gcm>::from_slice(&bytes)),
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Encrypt a raw secret as hex(nonce || ciphertext)
+    pub fn encrypt(&self, secret: &[u8]) -> Result<String, TwoFactorError> {
+        let key = self.key.as_ref().ok_or(TwoFactorError::NotConfigured)?;
+        let cipher = Aes256Gcm::new(key);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, secret)
+            .map_err(|_| TwoFactorError::Decrypt)?;
+        let mut stored = nonce.to_vec();
+        stored.extend(ciphertext);
+        Ok(hex::encode(stored))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<Vec<u8>, TwoFactorError> {
+        let key = self.key.as_ref().ok_or(TwoFactorError::NotConfigured)?;
+        let bytes = hex::decode(stored).map_err(|_| TwoFactorError::Decrypt)?;
+        if bytes.len() < 12 {
+            return Err(TwoFactorError::Decrypt);
+        }
+        let (nonce, ciphertext) = bytes.split_at(12);
+        Aes256Gcm::new(key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| TwoFactorError::Decrypt)
+    }
+}
+
+/// Fresh 160-bit TOTP secret
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Base32 form of a secret, for typing into an app by hand
+pub fn secret_base32(secret: &[u8]) -> String {
+    Secret::Raw(secret.to_vec()).to_encoded().to_string()
+}
+
+pub fn secret_from_base32(encoded: &str) -> Option<Vec<u8>> {
+    Secret::Encoded(encoded.to_string()).to_bytes().ok()
+}
+
+fn totp(secret: &[u8], account: &str) -> Result<TOTP, TwoFactorError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW_STEPS as u8,
+        STEP_SECONDS,
+        secret.to_vec(),
+        Some(ISSUER.to_string()),
+        account.replace(':', ""),
+    )
+    .map_err(|e| TwoFactorError::Totp(e.to_string()))
+}
+
+/// otpauth:// URI for enrolling an authenticator app
+pub fn otpauth_uri(secret: &[u8], account: &str) -> Result<String, TwoFactorError> {
+    Ok(totp(secret, account)?.get_url())
+}
+
+/// The otpauth URI as an inline SVG QR code
+pub fn qr_svg(uri: &str) -> Result<String, TwoFactorError> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|e| TwoFactorError::Qr(e.to_string()))?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build())
+}
+
+/// Check a 6-digit code at `unix_time`. Returns the matching time step so
+/// the caller can refuse it next time; steps at or before `last_step` are
+/// rejected so an observed code can't be replayed.
+pub fn verify_code(
+    secret: &[u8],
+    code: &str,
+    unix_time: u64,
+    last_step: Option<i64>,
+) -> Result<Option<i64>, TwoFactorError> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+
+    let totp = totp(secret, "")?;
+    let current_step = (unix_time / STEP_SECONDS) as i64;
+    for step in current_step - SKEW_STEPS..=current_step + SKEW_STEPS {
+        if step < 0 || last_step.is_some_and(|last| step <= last) {
+            continue;
+        }
+        let expected = totp.generate(step as u64 * STEP_SECONDS);
+        if constant_time_eq(expected.as_bytes(), code.as_bytes()) {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Record an accepted step, unless another request already used it
+pub async fn claim_step(
+    users: &Collection<User>,
+    user_id: ObjectId,
+    step: i64,
+) -> mongodb::error::Result<bool> {
+    let result = users
+        .update_one(
+            doc! {
+                "_id": user_id,
+                "$or": [
+                    { "totp_last_step": { "$exists": false } },
+                    { "totp_last_step": { "$lt": step } },
+                ],
+            },
+            doc! { "$set": { "totp_last_step": step } },
+        )
+        .await?;
+    Ok(result.modified_count > 0)
+}
+
+/// Recovery codes in `xxxxx-xxxxx` form, avoiding look-alike characters
+pub fn generate_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            OsRng.fill_bytes(&mut bytes);
+            let chars: String = bytes
+                .iter()
+                .map(|b| ALPHABET[*b as usize % ALPHABET.len()] as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are stored hashed; case and dashes don't matter when typed
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Use up a recovery code; true if it was valid and unused
+pub async fn consume_recovery_code(
+    users: &Collection<User>,
+    user_id: ObjectId,
+    code: &str,
+) -> mongodb::error::Result<bool> {
+    let hash = hash_recovery_code(code);
+    let result = users
+        .update_one(
+            doc! { "_id": user_id, "recovery_codes": &hash },
+            doc! { "$pull": { "recovery_codes": &hash } },
+        )
+        .await?;
+    Ok(result.modified_count > 0)
+}
+
+/// Check a TOTP code for this user and mark its time step as used
+pub async fn verify_totp(
+    users: &Collection<User>,
+    cipher: &TwoFactorCipher,
+    user: &User,
+    code: &str,
+) -> Result<bool, TwoFactorError> {
+    let Some(ref stored) = user.totp_secret else {
+        return Ok(false);
+    };
+    let secret = cipher.decrypt(stored)?;
+    match verify_code(&secret, code, now_unix(), user.totp_last_step)? {
+        Some(step) => Ok(claim_step(users, user.id, step).await?),
+        None => Ok(false),
+    }
+}
+
+/// Accept either a current TOTP code or one of the unused recovery codes
+pub async fn verify_second_factor(
+    users: &Collection<User>,
+    cipher: &TwoFactorCipher,
+    user: &User,
+    code: &str,
+) -> Result<bool, TwoFactorError> {
+    if !user.has_two_factor() {
+        return Ok(false);
+    }
+
+    // TOTP codes are six digits; recovery codes are ten characters
+    let trimmed = code.trim().replace(' ', "");
+    let looks_like_totp = trimmed.len() == DIGITS && trimmed.chars().all(|c| c.is_ascii_digit());
+    if !looks_like_totp {
+        let used = consume_recovery_code(users, user.id, code).await?;
+        if used {
+            info!("{} used a recovery code", user.username);
+        }
+        return Ok(used);
+    }
+    verify_totp(users, cipher, user, code).await
+}
+
+/// Remove the secret and recovery codes (user opt-out or admin reset)
+pub async fn clear_two_factor(
+    users: &Collection<User>,
+    user_id: ObjectId,
+) -> mongodb::error::Result<bool> {
+    let result = users
+        .update_one(
+            doc! { "_id": user_id },
+            doc! {
+                "$unset": { "totp_secret": "", "totp_last_step": "" },
+                "$set": { "recovery_codes": [] },
+            },
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Seconds since the Unix epoch, for TOTP checks
+pub fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 appendix B SHA-1 secret
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn test_cipher() -> TwoFactorCipher {
+        TwoFactorCipher::from_hex(&"ab".repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn test_verify_code_matches_rfc_vectors() {
+        // RFC 6238 gives 8-digit codes; the 6-digit code is the last six digits
+        assert_eq!(verify_code(RFC_SECRET, "287082", 59, None).unwrap(), Some(1));
+        assert_eq!(
+            verify_code(RFC_SECRET, "005924", 1234567890, None).unwrap(),
+            Some(1234567890 / 30)
+        );
+    }
+
+    #[test]
+    fn test_verify_code_allows_one_step_of_drift() {
+        assert!(verify_code(RFC_SECRET, "287082", 59 + 30, None).unwrap().is_some());
+        assert!(verify_code(RFC_SECRET, "287082", 59 + 90, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_verify_code_rejects_replay_and_garbage() {
+        assert_eq!(verify_code(RFC_SECRET, "287082", 59, Some(1)).unwrap(), None);
+        assert_eq!(verify_code(RFC_SECRET, "28708", 59, None).unwrap(), None);
+        assert_eq!(verify_code(RFC_SECRET, "abcdef", 59, None).unwrap(), None);
+        assert_eq!(verify_code(RFC_SECRET, "000000", 59, None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_secret_encryption_round_trips() {
+        let cipher = test_cipher();
+        let secret = generate_secret();
+        let stored = cipher.encrypt(&secret).unwrap();
+
+        assert!(!stored.contains(&hex::encode(&secret)));
+        assert_eq!(cipher.decrypt(&stored).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_key_fails() {
+        let stored = test_cipher().encrypt(&generate_secret()).unwrap();
+        let other = TwoFactorCipher::from_hex(&"cd".repeat(32)).unwrap();
+
+        assert!(matches!(other.decrypt(&stored), Err(TwoFactorError::Decrypt)));
+    }
+
+    #[test]
+    fn test_unconfigured_cipher_refuses() {
+        let cipher = TwoFactorCipher { key: None };
+        assert!(!cipher.is_configured());
+        assert!(matches!(cipher.encrypt(b"x"), Err(TwoFactorError::NotConfigured)));
+        assert!(TwoFactorCipher::from_hex("abcd").is_err());
+    }
+
+    #[test]
+    fn test_otpauth_uri_and_base32() {
+        let uri = otpauth_uri(RFC_SECRET, "staff@example.com").unwrap();
+        assert!(uri.starts_with("otpauth://totp/"));
+        assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+
+        let encoded = secret_base32(RFC_SECRET);
+        assert_eq!(secret_from_base32(&encoded).unwrap(), RFC_SECRET);
+    }
+
+    #[test]
+    fn test_recovery_codes_are_unique_and_hash_loosely() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(unique.len(), RECOVERY_CODE_COUNT);
+
+        let code = &codes[0];
+        assert_eq!(code.len(), 11);
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&code.replace('-', "").to_uppercase())
+        );
+        assert_ne!(hash_recovery_code(code), hash_recovery_code(&codes[1]));
+    }
+}
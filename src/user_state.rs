@@ -1,18 +1,51 @@
-use crate::{auth::MongoAuth, models::UserState};
-use axum_login::AuthSession;
+use crate::{
+    api_tokens::ApiPrincipal,
+    auth::{MongoAuth, effective_permissions},
+    csrf,
+    models::UserState,
+};
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+};
+use axum_login::{AuthSession, tower_sessions::Session};
+
+/// Handlers take `user_state: UserState` directly to get the template state
+impl<S> FromRequestParts<S> for UserState
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(principal) = parts.extensions.get::<ApiPrincipal>() {
+            return Ok(api_user_state(principal));
+        }
+        let auth = AuthSession::<MongoAuth>::from_request_parts(parts, state).await?;
+        let session = Session::from_request_parts(parts, state).await?;
+        Ok(extract_user_state(&auth, &session).await)
+    }
+}
 
 /// Extract user state from authentication session
-pub fn extract_user_state(auth: &AuthSession<MongoAuth>) -> UserState {
+pub async fn extract_user_state(auth: &AuthSession<MongoAuth>, session: &Session) -> UserState {
     match auth.user {
         Some(ref user) => UserState::new(
             true, // is_authenticated
             user.username.clone(),
-            user.is_admin,
-        ),
+            user.has_staff_access(),
+        )
+        .with_permissions(effective_permissions(user))
+        .with_csrf_token(csrf::session_token(session).await),
         None => UserState::default(),
     }
 }
 
+/// State for a Bearer-token request: the owner, limited to the token's scopes
+pub fn api_user_state(principal: &ApiPrincipal) -> UserState {
+    UserState::new(true, principal.user.username.clone(), true).with_permissions(principal.permissions.clone())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::models::UserState;
@@ -21,7 +54,7 @@ mod tests {
     fn test_user_state_creation() {
         let user_state = UserState::new(true, "test".to_string(), true);
         
-        assert!(user_state.logged_in);
+        assert!(user_state.is_authenticated);
         assert!(user_state.is_admin);
         assert_eq!(user_state.username, "test");
     }
@@ -30,11 +63,21 @@ mod tests {
     fn test_user_state_default() {
         let user_state = UserState::default();
         
-        assert!(!user_state.logged_in);
+        assert!(!user_state.is_authenticated);
         assert!(!user_state.is_admin);
         assert_eq!(user_state.username, "");
     }
     
+    #[test]
+    fn test_user_state_can_checks_permissions() {
+        let user_state = UserState::new(true, "test".to_string(), true)
+            .with_permissions(["orders:view".to_string()].into_iter().collect());
+
+        assert!(user_state.can("orders:view"));
+        assert!(!user_state.can("orders:update"));
+        assert!(!UserState::default().can("orders:view"));
+    }
+
     // Note: Testing extract_user_state would require complex mocking of AuthSession
     // which is difficult due to the concrete types. The function is tested indirectly
     // through integration tests and real usage.
@@ -1,4 +1,12 @@
 // script.js (place in /static/script.js)
+
+// CSRF token for the current session, rendered into <meta name="csrf-token"> by base.html.
+// Send it as the X-CSRF-Token header on every POST/DELETE fetch call.
+function csrfToken() {
+  const meta = document.querySelector('meta[name="csrf-token"]');
+  return meta ? meta.getAttribute('content') : '';
+}
+
 document.addEventListener('DOMContentLoaded', () => {
   const container = document.getElementById('carousel');
   if (!container) {